@@ -1,14 +1,24 @@
-use std::fmt::{Debug, Display};
+use std::{
+    fmt::{Debug, Display},
+    fs,
+    path::PathBuf,
+};
 
 use serde::{Serialize, ser::Serializer};
+use tauri::{AppHandle, async_runtime};
+use tauri_plugin_dialog::DialogExt;
 
 pub mod assistant;
 pub mod auth;
+pub mod conversation;
 pub mod i18n;
+pub mod memory;
 pub mod settings;
+pub mod storage;
 pub mod updater;
+pub mod usage;
 
-use crate::BoxError;
+use crate::{BoxError, service::run::RunError};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -71,6 +81,42 @@ impl Serialize for Error {
     where
         S: Serializer,
     {
-        serializer.serialize_str(self.to_string().as_ref())
+        match self.source.downcast_ref::<RunError>() {
+            Some(err) => err.serialize(serializer),
+            None => serializer.serialize_str(self.to_string().as_ref()),
+        }
     }
 }
+
+/// Asks the user where to save a file. Returns `None` if the dialog was
+/// cancelled.
+pub(crate) async fn save_file_path(
+    app: &AppHandle,
+    file_name: String,
+    extension: &'static str,
+) -> Result<Option<PathBuf>> {
+    let app = app.clone();
+    let path = async_runtime::spawn_blocking(move || {
+        app.dialog()
+            .file()
+            .set_file_name(file_name)
+            .add_filter(extension, &[extension])
+            .blocking_save_file()
+    })
+    .await?;
+
+    match path {
+        Some(path) => Ok(Some(path.into_path().map_err(|e| e.to_string())?)),
+        None => Ok(None),
+    }
+}
+
+/// Writes a file on a blocking thread and returns its path.
+pub(crate) async fn write_file(path: PathBuf, content: String) -> Result<PathBuf> {
+    let path = async_runtime::spawn_blocking(move || {
+        fs::write(&path, content).map_err(|e| format!("Failed to write {:?}: {}", path, e))?;
+        Ok::<_, String>(path)
+    })
+    .await??;
+    Ok(path)
+}
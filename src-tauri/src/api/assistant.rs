@@ -1,10 +1,14 @@
-use anda_core::{AgentInput, AgentOutput, Json, ToolInput, ToolOutput};
+use anda_core::{AgentInput, Json, ToolInput, ToolOutput};
 use anda_engine::context::EngineCard;
 use ic_agent::Identity;
-use tauri::AppHandle;
+use tauri::{AppHandle, async_runtime, ipc::Channel};
 
 use super::Result;
-use crate::service::{assistant::AndaAssistantExt, icp::ICPClientExt};
+use crate::service::{
+    assistant::{AndaAssistantExt, AssistantStatus},
+    icp::ICPClientExt,
+    run::{RunEvent, RunOutput},
+};
 
 #[tauri::command]
 pub async fn assistant_info(app: AppHandle) -> Result<EngineCard> {
@@ -12,6 +16,20 @@ pub async fn assistant_info(app: AppHandle) -> Result<EngineCard> {
     Ok(engine.information())
 }
 
+#[tauri::command]
+pub fn assistant_status(app: AppHandle) -> AssistantStatus {
+    app.assistant().status()
+}
+
+/// Called by the frontend when the network comes back online.
+#[tauri::command]
+pub fn network_restored(app: AppHandle) {
+    if matches!(app.assistant().status(), AssistantStatus::Failed { .. }) {
+        log::info!("Network restored, reconnecting assistant");
+        app.assistant().network_restored();
+    }
+}
+
 #[tauri::command]
 pub async fn assistant_name(app: AppHandle) -> Option<String> {
     app.assistant().self_name().await
@@ -25,19 +43,96 @@ pub async fn caller_name(app: AppHandle) -> Option<String> {
 }
 
 #[tauri::command]
-pub async fn tool_call(app: AppHandle, input: ToolInput<Json>) -> Result<ToolOutput<Json>> {
+pub async fn tool_call(
+    app: AppHandle,
+    input: ToolInput<Json>,
+    run_id: Option<String>,
+) -> Result<ToolOutput<Json>> {
+    let id = app.icp().identity();
+    let caller = id.sender().unwrap();
+    let engine = app.assistant().engine();
+    let ctx = app.assistant().start_run(run_id, None)?;
+    let res = ctx.clone().run(engine.tool_call(caller, input)).await;
+    app.assistant().end_run(ctx.run_id());
+    Ok(res?)
+}
+
+#[tauri::command]
+pub async fn agent_run(
+    app: AppHandle,
+    input: AgentInput,
+    run_id: Option<String>,
+) -> Result<RunOutput> {
     let id = app.icp().identity();
     let caller = id.sender().unwrap();
     let engine = app.assistant().engine();
-    let res = engine.tool_call(caller, input).await?;
-    Ok(res)
+    let ctx = app.assistant().start_run(run_id, None)?;
+    if let Err(err) = app.assistant().check_budgets(&ctx).await {
+        app.assistant().end_run(ctx.run_id());
+        return Err(err.into());
+    }
+    app.assistant().expect_rounds(&ctx, &input.prompt);
+    let res = match ctx.clone().run(engine.agent_run(caller, input)).await {
+        Ok(res) => res,
+        Err(err) => {
+            app.assistant().end_run(ctx.run_id());
+            app.assistant().record_usage(&ctx, None);
+            return Err(err.into());
+        }
+    };
+
+    // returns once the conversation is created, the frontend follows it
+    let output = ctx.output(res.clone());
+    let app = app.clone();
+    async_runtime::spawn(async move {
+        if let Err(err) = app.assistant().follow_run(&ctx, res).await {
+            log::warn!("Run {} failed: {}", ctx.run_id(), err);
+        }
+    });
+    Ok(output)
 }
 
+/// Runs the agent and pushes its progress to `on_event`: the text of every
+/// completion round as it is streamed, the tool calls, then the final output.
 #[tauri::command]
-pub async fn agent_run(app: AppHandle, input: AgentInput) -> Result<AgentOutput> {
+pub async fn agent_run_stream(
+    app: AppHandle,
+    input: AgentInput,
+    run_id: Option<String>,
+    on_event: Channel<RunEvent>,
+) -> Result<RunOutput> {
     let id = app.icp().identity();
     let caller = id.sender().unwrap();
     let engine = app.assistant().engine();
-    let res = engine.agent_run(caller, input).await?;
-    Ok(res)
+    let ctx = app.assistant().start_run(run_id, Some(on_event))?;
+    if let Err(err) = app.assistant().check_budgets(&ctx).await {
+        app.assistant().end_run(ctx.run_id());
+        ctx.emit(RunEvent::Failed {
+            error: err.to_string(),
+        });
+        return Err(err.into());
+    }
+    app.assistant().expect_rounds(&ctx, &input.prompt);
+    let res = match ctx.clone().run(engine.agent_run(caller, input)).await {
+        Ok(res) => app.assistant().follow_run(&ctx, res).await,
+        Err(err) => {
+            app.assistant().end_run(ctx.run_id());
+            app.assistant().record_usage(&ctx, None);
+            Err(err)
+        }
+    };
+    match res {
+        Ok(res) => Ok(ctx.finish(res)),
+        Err(err) => {
+            ctx.emit(RunEvent::Failed {
+                error: err.to_string(),
+            });
+            Err(err.into())
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn cancel_run(app: AppHandle, run_id: String) -> Result<bool> {
+    Ok(app.assistant().cancel_run(&run_id))
 }
@@ -0,0 +1,201 @@
+use anda_core::{BoxError, Json};
+use ic_agent::Identity;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    sync::Arc,
+};
+use tauri::{AppHandle, async_runtime};
+
+use super::{Result, save_file_path, write_file};
+use crate::service::{
+    assistant::AndaAssistantExt,
+    export::{ExportFormat, ExportedConversation, write_zip},
+    history::{ConversationHistory, ConversationPage, conversation_owner},
+    icp::ICPClientExt,
+    search::SearchHit,
+};
+
+fn history(app: &AppHandle) -> Result<Arc<ConversationHistory>> {
+    let history = app
+        .assistant()
+        .history()
+        .ok_or_else(|| "Conversation history is not ready".to_string())?;
+    Ok(history)
+}
+
+#[tauri::command]
+pub async fn list_conversations(
+    app: AppHandle,
+    cursor: Option<String>,
+    limit: Option<usize>,
+) -> Result<ConversationPage> {
+    let id = app.icp().identity();
+    let caller = id.sender().unwrap();
+    let engine = app.assistant().engine();
+    let history = history(&app)?;
+    let res = history.list(&engine, caller, cursor, limit).await?;
+    Ok(res)
+}
+
+#[tauri::command]
+pub async fn get_conversation(app: AppHandle, id: u64) -> Result<Json> {
+    let identity = app.icp().identity();
+    let caller = identity.sender().unwrap();
+    let engine = app.assistant().engine();
+    let history = history(&app)?;
+    let res = history.get(&engine, caller, id).await?;
+    Ok(res)
+}
+
+#[tauri::command]
+pub async fn delete_conversation(app: AppHandle, id: u64) -> Result<bool> {
+    let identity = app.icp().identity();
+    let caller = identity.sender().unwrap();
+    let engine = app.assistant().engine();
+    let db = app
+        .assistant()
+        .db()
+        .ok_or_else(|| "Assistant database is not ready".to_string())?;
+    let history = history(&app)?;
+    let res = history.delete(&engine, &db, caller, id).await?;
+    if let Some(index) = app.assistant().search_index() {
+        index.remove_conversation(id).await?;
+    }
+    Ok(res)
+}
+
+#[tauri::command]
+pub async fn rename_conversation(app: AppHandle, id: u64, title: String) -> Result<bool> {
+    let identity = app.icp().identity();
+    let caller = identity.sender().unwrap();
+    let engine = app.assistant().engine();
+    let history = history(&app)?;
+    let res = history.rename(&engine, caller, id, title).await?;
+    Ok(res)
+}
+
+/// Full-text search over the messages of the user's conversations.
+#[tauri::command]
+pub async fn search_conversations(
+    app: AppHandle,
+    query: String,
+    limit: Option<usize>,
+) -> Result<Vec<SearchHit>> {
+    let identity = app.icp().identity();
+    let caller = identity.sender().unwrap();
+    let db = app
+        .assistant()
+        .db()
+        .ok_or_else(|| "Assistant database is not ready".to_string())?;
+    let index = app
+        .assistant()
+        .search_index()
+        .ok_or_else(|| "Search index is not ready".to_string())?;
+
+    let limit = limit.unwrap_or(20).min(100);
+    // over-fetch as hits in conversations of other users are dropped
+    let hits = index.search(&query, limit * 4).await?;
+    let mut owned: BTreeMap<u64, bool> = BTreeMap::new();
+    let mut res = Vec::with_capacity(limit);
+    for hit in hits {
+        let is_owned = match owned.get(&hit.conversation) {
+            Some(is_owned) => *is_owned,
+            None => {
+                let is_owned = conversation_owner(&db, hit.conversation)
+                    .await
+                    .is_ok_and(|owner| owner == caller);
+                owned.insert(hit.conversation, is_owned);
+                is_owned
+            }
+        };
+        if is_owned {
+            res.push(hit);
+            if res.len() >= limit {
+                break;
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Exports a conversation as Markdown or JSON to a file chosen by the user.
+/// Returns the written path, or `None` if the user cancelled the dialog.
+#[tauri::command]
+pub async fn export_conversation(
+    app: AppHandle,
+    id: u64,
+    format: ExportFormat,
+) -> Result<Option<String>> {
+    let identity = app.icp().identity();
+    let caller = identity.sender().unwrap();
+    let engine = app.assistant().engine();
+    let history = history(&app)?;
+    let conversation = history.get(&engine, caller, id).await?;
+    let models = models(&app, id).await?;
+    let conversation = ExportedConversation::new(&conversation, models);
+    let content = conversation.render(format)?;
+
+    let path =
+        match save_file_path(&app, conversation.file_name(format), format.extension()).await? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+    let path = write_file(path, content).await?;
+    log::info!("Conversation {} exported to {:?}", id, path);
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+/// Exports all conversations of the user to a zip archive, one file per
+/// conversation.
+#[tauri::command]
+pub async fn export_all_conversations(
+    app: AppHandle,
+    format: ExportFormat,
+) -> Result<Option<String>> {
+    let identity = app.icp().identity();
+    let caller = identity.sender().unwrap();
+    let engine = app.assistant().engine();
+    let history = history(&app)?;
+
+    let mut conversations = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let page = history.list(&engine, caller, cursor, Some(100)).await?;
+        for conversation in &page.items {
+            let id = conversation["_id"].as_u64().unwrap_or_default();
+            conversations.push(ExportedConversation::new(
+                conversation,
+                models(&app, id).await?,
+            ));
+        }
+        match page.next_cursor {
+            Some(next) if !page.items.is_empty() => cursor = Some(next),
+            _ => break,
+        }
+    }
+
+    let file_name = format!("conversations-{}.zip", format.extension());
+    let path = match save_file_path(&app, file_name, "zip").await? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let count = conversations.len();
+    let path = async_runtime::spawn_blocking(move || {
+        let file =
+            fs::File::create(&path).map_err(|e| format!("Failed to create {:?}: {}", path, e))?;
+        write_zip(io::BufWriter::new(file), &conversations, format)?;
+        Ok::<_, BoxError>(path)
+    })
+    .await??;
+    log::info!("{} conversations exported to {:?}", count, path);
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+/// The models that served a conversation, from the usage ledger.
+async fn models(app: &AppHandle, conversation: u64) -> Result<BTreeSet<String>> {
+    match app.assistant().usage_ledger() {
+        Some(ledger) => Ok(ledger.models_of(conversation).await?),
+        None => Ok(BTreeSet::new()),
+    }
+}
@@ -0,0 +1,192 @@
+use anda_core::Json;
+use anda_engine::memory::MemoryManagement;
+use anda_kip::Response;
+use serde::Serialize;
+use serde_json::{Map, json};
+use std::{fs, sync::Arc};
+use tauri::{AppHandle, Emitter, async_runtime};
+
+use super::{Result, save_file_path, write_file};
+use crate::service::{
+    assistant::{AndaAssistantExt, MEMORY_EVENT},
+    capsule::{self, ImportReport},
+    graph::GraphFormat,
+    nexus::{self, ConceptView, GraphFilter, KipPage, MemoryInspector},
+};
+
+fn memory(app: &AppHandle) -> Result<Arc<MemoryManagement>> {
+    let memory = app
+        .assistant()
+        .memory()
+        .ok_or_else(|| "Assistant memory is not ready".to_string())?;
+    Ok(memory)
+}
+
+#[tauri::command]
+pub async fn execute_kip(
+    app: AppHandle,
+    command: String,
+    dry_run: Option<bool>,
+    write: Option<bool>,
+) -> Result<Response> {
+    let memory = memory(&app)?;
+    let dry_run = dry_run.unwrap_or(false);
+    let write = write.unwrap_or(false);
+    let res = nexus::execute_kip(&memory, &command, dry_run, write).await?;
+    if write && !dry_run {
+        let _ = app.emit(MEMORY_EVENT, json!({}));
+    }
+    Ok(res)
+}
+
+#[tauri::command]
+pub async fn list_concept_types(
+    app: AppHandle,
+    limit: Option<usize>,
+    cursor: Option<String>,
+) -> Result<KipPage> {
+    let memory = memory(&app)?;
+    let res = MemoryInspector::new(&memory)
+        .concept_types(limit, cursor)
+        .await?;
+    Ok(res)
+}
+
+#[tauri::command]
+pub async fn list_concepts(
+    app: AppHandle,
+    concept_type: String,
+    limit: Option<usize>,
+    cursor: Option<String>,
+) -> Result<KipPage> {
+    let memory = memory(&app)?;
+    let res = MemoryInspector::new(&memory)
+        .concepts(&concept_type, limit, cursor)
+        .await?;
+    Ok(res)
+}
+
+#[tauri::command]
+pub async fn list_propositions(
+    app: AppHandle,
+    predicate: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+) -> Result<KipPage> {
+    let memory = memory(&app)?;
+    let res = MemoryInspector::new(&memory)
+        .propositions(predicate.as_deref(), limit, cursor)
+        .await?;
+    Ok(res)
+}
+
+#[tauri::command]
+pub async fn get_concept(app: AppHandle, id: String) -> Result<ConceptView> {
+    let memory = memory(&app)?;
+    let res = MemoryInspector::new(&memory).concept(&id).await?;
+    Ok(res)
+}
+
+#[tauri::command]
+pub async fn update_concept_attributes(
+    app: AppHandle,
+    id: String,
+    attributes: Map<String, Json>,
+) -> Result<Json> {
+    let memory = memory(&app)?;
+    let res = MemoryInspector::new(&memory)
+        .update_attributes(&id, attributes)
+        .await?;
+    let _ = app.emit(MEMORY_EVENT, json!({ "id": id }));
+    Ok(res)
+}
+
+#[tauri::command]
+pub async fn forget_concept(app: AppHandle, id: String) -> Result<Json> {
+    let memory = memory(&app)?;
+    let res = MemoryInspector::new(&memory).forget(&id).await?;
+    let _ = app.emit(MEMORY_EVENT, json!({ "id": id }));
+    Ok(res)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct KnowledgeExport {
+    pub path: String,
+    pub concepts: usize,
+    pub propositions: usize,
+}
+
+/// Exports the selected knowledge as a KML capsule file chosen by the user.
+/// Returns `None` if the user cancelled the dialog.
+#[tauri::command]
+pub async fn export_capsule(
+    app: AppHandle,
+    filter: Option<GraphFilter>,
+) -> Result<Option<KnowledgeExport>> {
+    let memory = memory(&app)?;
+    let capsule = capsule::export_capsule(&memory, &filter.unwrap_or_default()).await?;
+
+    let path = match save_file_path(&app, "capsule.kip".to_string(), "kip").await? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let path = write_file(path, capsule.kml).await?;
+    log::info!(
+        "Knowledge capsule with {} concepts and {} propositions exported to {:?}",
+        capsule.concepts,
+        capsule.propositions,
+        path
+    );
+    Ok(Some(KnowledgeExport {
+        path: path.to_string_lossy().to_string(),
+        concepts: capsule.concepts,
+        propositions: capsule.propositions,
+    }))
+}
+
+/// Exports the selected concept nodes and proposition links to a DOT,
+/// GraphML or JSON-LD file chosen by the user.
+#[tauri::command]
+pub async fn export_graph(
+    app: AppHandle,
+    format: GraphFormat,
+    filter: Option<GraphFilter>,
+) -> Result<Option<KnowledgeExport>> {
+    let memory = memory(&app)?;
+    let graph = MemoryInspector::new(&memory)
+        .subgraph(&filter.unwrap_or_default())
+        .await?;
+    let content = format.render(&graph)?;
+
+    let file_name = format!("knowledge-graph.{}", format.extension());
+    let path = match save_file_path(&app, file_name, format.extension()).await? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let path = write_file(path, content).await?;
+    log::info!(
+        "Knowledge graph with {} concepts and {} propositions exported to {:?}",
+        graph.concepts.len(),
+        graph.links.len(),
+        path
+    );
+    Ok(Some(KnowledgeExport {
+        path: path.to_string_lossy().to_string(),
+        concepts: graph.concepts.len(),
+        propositions: graph.links.len(),
+    }))
+}
+
+#[tauri::command]
+pub async fn import_capsule(app: AppHandle, path: String) -> Result<ImportReport> {
+    let memory = memory(&app)?;
+    let kml = async_runtime::spawn_blocking(move || {
+        fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))
+    })
+    .await??;
+    let res = capsule::import_capsule(&memory, &kml).await?;
+    if res.applied {
+        let _ = app.emit(MEMORY_EVENT, json!({}));
+    }
+    Ok(res)
+}
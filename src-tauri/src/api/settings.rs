@@ -1,11 +1,13 @@
 use anda_core::Json;
 use serde_json::json;
+use std::collections::BTreeMap;
 use tauri::{AppHandle, Emitter, Manager, Theme};
 
 use super::Result;
 use crate::{
-    AppStateCell, BoxError, SecretStateCell, model::app::Settings,
-    service::assistant::AndaAssistantExt,
+    AppStateCell, BoxError, SecretStateCell,
+    model::app::{ModelPrice, ModelProvider, ProviderKind, Settings, TokenBudget},
+    service::provider::{self, LocalModel, ProviderTestResult},
 };
 
 pub const SETTINGS_EVENT: &str = "SettingsChanged";
@@ -44,7 +46,6 @@ pub async fn set_setting(app: AppHandle, key: String, value: Json) -> Result<boo
             "https_proxy" => {
                 match value.as_str() {
                     Some(v) => {
-                        app.propose_reconnect_assistant();
                         state.settings.https_proxy = if v.is_empty() {
                             None
                         } else {
@@ -55,6 +56,17 @@ pub async fn set_setting(app: AppHandle, key: String, value: Json) -> Result<boo
                 }
                 Ok::<bool, BoxError>(true)
             }
+            "model_prices" => {
+                let prices: BTreeMap<String, ModelPrice> = serde_json::from_value(value)?;
+                if let Some((model, _)) = prices
+                    .iter()
+                    .find(|(_, p)| !(p.input >= 0.0 && p.output >= 0.0))
+                {
+                    return Err(format!("Invalid price for model {:?}", model).into());
+                }
+                state.settings.model_prices = prices;
+                Ok::<bool, BoxError>(true)
+            }
             _ => Err(format!("Unknown setting key: {:?}", key).into()),
         }
     })?;
@@ -66,75 +78,135 @@ pub async fn set_setting(app: AppHandle, key: String, value: Json) -> Result<boo
     Ok(updated)
 }
 
+/// The providers that are also addressed by their bare name, as settings
+/// keys of older versions.
+const LEGACY_PROVIDER_KEYS: [&str; 3] = ["gemini", "openai", "deepseek"];
+
+/// Returns the provider name of a `provider.<name>` secret setting key.
+fn provider_key(key: &str) -> Option<&str> {
+    match key.strip_prefix("provider.") {
+        Some(name) if !name.trim().is_empty() => Some(name),
+        Some(_) => None,
+        None => LEGACY_PROVIDER_KEYS.contains(&key).then_some(key),
+    }
+}
+
 #[tauri::command]
 pub async fn get_secret_setting(app: AppHandle, key: String) -> Result<Json> {
     let secret_state = app.state::<SecretStateCell>();
-    secret_state.with(|state| match key.as_str() {
-        "preferred_provider" => match state.assistant.as_ref() {
-            Some(cfg) => Ok(json!(&cfg.preferred_provider)),
-            None => Ok(Json::Null),
-        },
-        "gemini" => match state.assistant.as_ref() {
-            Some(cfg) => Ok(cfg.gemini.as_ref().map(|v| json!(v)).unwrap_or(Json::Null)),
-            None => Ok(Json::Null),
-        },
-        "openai" => match state.assistant.as_ref() {
-            Some(cfg) => Ok(cfg.openai.as_ref().map(|v| json!(v)).unwrap_or(Json::Null)),
-            None => Ok(Json::Null),
-        },
-        "deepseek" => match state.assistant.as_ref() {
-            Some(cfg) => Ok(cfg
-                .deepseek
-                .as_ref()
-                .map(|v| json!(v))
-                .unwrap_or(Json::Null)),
-            None => Ok(Json::Null),
-        },
-        _ => Err(format!("Unknown secret setting key: {:?}", key).into()),
+    secret_state.with(|state| {
+        let cfg = match state.assistant.as_ref() {
+            Some(cfg) => cfg,
+            None => return Ok(Json::Null),
+        };
+
+        match key.as_str() {
+            "preferred_provider" => Ok(json!(&cfg.preferred_provider)),
+            "fallback_providers" => Ok(json!(&cfg.fallback_providers)),
+            "providers" => Ok(json!(&cfg.providers)),
+            "budgets" => Ok(json!(&cfg.budgets)),
+            _ => match provider_key(&key) {
+                Some(name) => Ok(cfg
+                    .providers
+                    .get(name)
+                    .map(|v| json!(v))
+                    .unwrap_or(Json::Null)),
+                None => Err(format!("Unknown secret setting key: {:?}", key).into()),
+            },
+        }
     })
 }
 
 #[tauri::command]
 pub async fn set_secret_setting(app: AppHandle, key: String, value: Json) -> Result<bool> {
     let secret_state = app.state::<SecretStateCell>();
-    let updated = secret_state.with_mut(|state| match key.as_str() {
-        "preferred_provider" => {
-            if let Some(cfg) = state.assistant.as_mut()
-                && let Some(v) = value.as_str()
-            {
-                cfg.preferred_provider = v.to_string();
-                return Ok::<bool, BoxError>(true);
+    let updated = secret_state.with_mut(|state| {
+        let cfg = match state.assistant.as_mut() {
+            Some(cfg) => cfg,
+            None => return Ok::<bool, BoxError>(false),
+        };
+
+        match key.as_str() {
+            "preferred_provider" => match value.as_str() {
+                Some(v) => {
+                    cfg.preferred_provider = v.to_string();
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            "fallback_providers" => {
+                cfg.fallback_providers = serde_json::from_value(value)?;
+                Ok(true)
             }
-            Ok(false)
-        }
-        "gemini" => {
-            if let Some(cfg) = state.assistant.as_mut() {
-                cfg.gemini = Some(serde_json::from_value(value)?);
-                return Ok::<bool, BoxError>(true);
+            "providers" => {
+                let providers: BTreeMap<String, ModelProvider> = serde_json::from_value(value)?;
+                for (name, provider) in &providers {
+                    provider
+                        .validate()
+                        .map_err(|err| format!("Invalid model provider {:?}: {}", name, err))?;
+                }
+                cfg.providers = providers;
+                Ok(true)
             }
-            Ok(false)
-        }
-        "openai" => {
-            if let Some(cfg) = state.assistant.as_mut() {
-                cfg.openai = Some(serde_json::from_value(value)?);
-                return Ok::<bool, BoxError>(true);
+            "budgets" => {
+                let budgets: BTreeMap<String, TokenBudget> = serde_json::from_value(value)?;
+                for (name, budget) in &budgets {
+                    budget
+                        .validate()
+                        .map_err(|err| format!("Invalid budget for {:?}: {}", name, err))?;
+                }
+                cfg.budgets = budgets;
+                Ok(true)
             }
-            Ok(false)
-        }
-        "deepseek" => {
-            if let Some(cfg) = state.assistant.as_mut() {
-                cfg.deepseek = Some(serde_json::from_value(value)?);
-                return Ok::<bool, BoxError>(true);
+            _ => {
+                let name = provider_key(&key)
+                    .ok_or_else(|| format!("Unknown secret setting key: {:?}", key))?;
+                if value.is_null() {
+                    return Ok(cfg.providers.remove(name).is_some());
+                }
+
+                cfg.providers
+                    .insert(name.to_string(), parse_provider(name, value)?);
+                Ok(true)
             }
-            Ok(false)
         }
-        _ => Err(format!("Unknown secret setting key: {:?}", key).into()),
     })?;
 
     if updated {
         secret_state.save()?;
-        app.propose_reconnect_assistant();
         let _ = app.emit(SECRET_SETTINGS_EVENT, key);
     }
     Ok(updated)
 }
+
+#[tauri::command]
+pub async fn list_local_models(api_base: Option<String>) -> Result<Vec<LocalModel>> {
+    let http_client = provider::local_http_client()?;
+    let models = provider::list_local_models(&http_client, api_base.as_deref()).await?;
+    Ok(models)
+}
+
+#[tauri::command]
+pub async fn test_provider(app: AppHandle, key: String, value: Json) -> Result<ProviderTestResult> {
+    let provider = parse_provider(&key, value)?;
+    let proxy = app
+        .state::<AppStateCell>()
+        .with(|state| state.settings.https_proxy.clone());
+    let http_client = provider::http_client(proxy)?;
+    Ok(provider::test_provider(&key, &provider, http_client).await)
+}
+
+fn parse_provider(name: &str, mut value: Json) -> core::result::Result<ModelProvider, BoxError> {
+    // the built-in provider names imply their kind
+    if let Some(obj) = value.as_object_mut()
+        && !obj.contains_key("kind")
+        && let Some(kind) = ProviderKind::from_name(name)
+    {
+        obj.insert("kind".to_string(), json!(kind));
+    }
+    let provider: ModelProvider = serde_json::from_value(value)?;
+    provider
+        .validate()
+        .map_err(|err| format!("Invalid model provider {:?}: {}", name, err))?;
+    Ok(provider)
+}
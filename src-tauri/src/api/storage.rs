@@ -0,0 +1,222 @@
+use anda_db::database::AndaDB;
+use ic_agent::Identity;
+use ic_auth_verifier::AnonymousIdentity;
+use serde_json::json;
+use std::{path::PathBuf, sync::Arc};
+use tauri::{AppHandle, Emitter, Manager, async_runtime};
+
+use super::Result;
+use crate::{
+    AppStateCell, SecretStateCell,
+    service::{
+        assistant::{AndaAssistantExt, MEMORY_EVENT},
+        backup::{self, APP_STATE_FILE, BackupManifest, SECRET_STATE_FILE},
+        icp::ICPClientExt,
+        integrity::{IntegrityReport, check_database},
+        storage::{self, CompactionReport, StorageStats},
+        store::{dropped_collections_file, encrypted_marker, migration_journal},
+    },
+};
+
+fn db(app: &AppHandle) -> Result<Arc<AndaDB>> {
+    let db = app
+        .assistant()
+        .db()
+        .ok_or_else(|| "Database is not ready".to_string())?;
+    Ok(db)
+}
+
+/// The entries of the app data directory that make up a backup, whether they
+/// exist or not. A backup holds the existing ones, a restore replaces all.
+fn backup_entries(app: &AppHandle) -> Result<(PathBuf, Vec<String>)> {
+    let dir = app.assistant().dir();
+    let data_dir = dir
+        .parent()
+        .ok_or_else(|| "Invalid object store directory".to_string())?
+        .to_path_buf();
+    let mut entries = vec![APP_STATE_FILE.to_string(), SECRET_STATE_FILE.to_string()];
+    for path in [
+        dir.to_path_buf(),
+        encrypted_marker(dir),
+        dropped_collections_file(dir),
+        migration_journal(dir),
+    ] {
+        if let Some(name) = path.file_name() {
+            entries.push(name.to_string_lossy().to_string());
+        }
+    }
+    Ok((data_dir, entries))
+}
+
+/// Writes an encrypted backup of the app state, the secret state and the
+/// object store to `path`. The database is read-only while it is copied.
+#[tauri::command]
+pub async fn create_backup(
+    app: AppHandle,
+    path: String,
+    passphrase: String,
+) -> Result<BackupManifest> {
+    app.state::<AppStateCell>().save()?;
+    app.state::<SecretStateCell>().save()?;
+
+    let (data_dir, entries) = backup_entries(&app)?;
+    let app_version = app.package_info().version.to_string();
+    let target = PathBuf::from(&path);
+    let manifest = app
+        .assistant()
+        .with_read_only(async move {
+            async_runtime::spawn_blocking(move || {
+                let entries: Vec<&str> = entries.iter().map(|e| e.as_str()).collect();
+                backup::create_backup(&data_dir, &entries, &passphrase, &app_version, &target)
+            })
+            .await?
+        })
+        .await?;
+
+    log::info!(
+        "Backup with {} files ({} bytes) written to {}",
+        manifest.files,
+        manifest.bytes,
+        path
+    );
+    Ok(manifest)
+}
+
+/// Restores a backup created by [`create_backup`]. The assistant is closed,
+/// the data is swapped in place and the assistant connects again with the
+/// restored identity and memory, or with the current ones if the restore
+/// fails.
+#[tauri::command]
+pub async fn restore_backup(
+    app: AppHandle,
+    path: String,
+    passphrase: String,
+) -> Result<BackupManifest> {
+    let (data_dir, known) = backup_entries(&app)?;
+    let staging_dir = data_dir.join("restore.tmp");
+    let manifest = {
+        let staging_dir = staging_dir.clone();
+        let path = PathBuf::from(&path);
+        async_runtime::spawn_blocking(move || {
+            backup::extract_backup(&path, &passphrase, &staging_dir)
+        })
+        .await??
+    };
+
+    app.assistant().close().await;
+    let res = async {
+        let entries = manifest.entries.clone();
+        async_runtime::spawn_blocking(move || {
+            backup::swap_in(&data_dir, &staging_dir, &entries, &known)
+        })
+        .await??;
+        reload_state(&app)
+    }
+    .await;
+    app.connect_assistant();
+    res?;
+
+    log::warn!(
+        "Backup created at {} restored from {}",
+        manifest.created_at,
+        path
+    );
+    let _ = app.emit(MEMORY_EVENT, json!({}));
+    Ok(manifest)
+}
+
+/// Loads the restored state files into the state cells, which are saved on
+/// exit, and signs in with the restored identity.
+fn reload_state(app: &AppHandle) -> Result<()> {
+    let app_state = app.state::<AppStateCell>();
+    app_state.reload()?;
+    let secret = backup::secret_state_key(app_state.path())?;
+    let secret_state = app.state::<SecretStateCell>();
+    secret_state.reload(secret)?;
+
+    let locale = app_state.with(|state| state.settings.locale.clone());
+    rust_i18n::set_locale(&locale);
+    let identity: Box<dyn Identity> = secret_state.with(|state| match &state.auth {
+        Some(auth) => auth
+            .to_identity(**state.session_secret)
+            .map(|id| Box::new(id) as Box<dyn Identity>),
+        None => Ok(Box::new(AnonymousIdentity) as Box<dyn Identity>),
+    })?;
+    app.icp().set_identity(identity);
+    Ok(())
+}
+
+/// Checks the object store and the database collections for missing chunks,
+/// undecodable documents and orphaned objects, without changing anything.
+#[tauri::command]
+pub async fn verify_database(app: AppHandle) -> Result<IntegrityReport> {
+    let db = db(&app)?;
+    app.assistant().flush_now().await?;
+    let report = check_database(&db, false).await?;
+    Ok(report)
+}
+
+/// Stops the running agents, removes undecodable documents from their
+/// collections and indexes, deletes bad and orphaned objects and rebuilds
+/// the full-text index of the conversations.
+#[tauri::command]
+pub async fn repair_database(app: AppHandle) -> Result<IntegrityReport> {
+    let db = db(&app)?;
+    let report = app
+        .assistant()
+        .quiesce(async {
+            let mut report = check_database(&db, true).await?;
+            if rebuild_conversation_index(&app).await?.is_some()
+                && let Some(index) = app.assistant().search_index()
+            {
+                report.rebuilt_indexes.push(index.name());
+            }
+            Ok::<_, super::Error>(report)
+        })
+        .await?;
+
+    let _ = app.emit(MEMORY_EVENT, json!({}));
+    Ok(report)
+}
+
+/// Reports document counts, index sizes, object counts and bytes on disk.
+#[tauri::command]
+pub async fn storage_stats(app: AppHandle) -> Result<StorageStats> {
+    let db = db(&app)?;
+    app.assistant().flush_now().await?;
+    let stats = storage::storage_stats(&db, app.assistant().dir()).await?;
+    Ok(stats)
+}
+
+/// Re-packs the conversation index and deletes the objects of dropped
+/// collections, with the running agents stopped.
+#[tauri::command]
+pub async fn compact_storage(app: AppHandle) -> Result<CompactionReport> {
+    let db = db(&app)?;
+    let dir = app.assistant().dir().to_path_buf();
+    let report = app
+        .assistant()
+        .quiesce(async {
+            let reindexed_messages = rebuild_conversation_index(&app).await?;
+            let mut report = app
+                .assistant()
+                .with_read_only(storage::compact(&db, &dir))
+                .await?;
+            report.reindexed_messages = reindexed_messages;
+            Ok::<_, super::Error>(report)
+        })
+        .await?;
+    Ok(report)
+}
+
+/// Indexes the conversations again into a new generation of the full-text
+/// index, which drops the messages of deleted conversations. Returns the
+/// number of indexed messages, or `None` if the index is not ready.
+async fn rebuild_conversation_index(app: &AppHandle) -> Result<Option<usize>> {
+    let (db, index) = match (app.assistant().db(), app.assistant().search_index()) {
+        (Some(db), Some(index)) => (db, index),
+        _ => return Ok(None),
+    };
+    let messages = index.rebuild(&db, app.assistant().dir()).await?;
+    Ok(Some(messages))
+}
@@ -56,13 +56,12 @@ pub fn update_supported() -> bool {
 
 #[tauri::command]
 pub async fn quit(app: AppHandle) {
-    app.assistant().close().await;
     app.exit(0)
 }
 
 #[tauri::command]
 pub async fn restart(app: AppHandle) {
-    app.assistant().close().await;
+    app.assistant().shutdown().await;
 
     if !is_mas_build()
         && let Some(app_updater) = app.try_state::<Updater>()
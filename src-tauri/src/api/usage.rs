@@ -0,0 +1,33 @@
+use tauri::{AppHandle, Manager};
+
+use super::Result;
+use crate::{
+    AppStateCell,
+    service::{
+        assistant::AndaAssistantExt,
+        usage::{BudgetStatus, UsageGroupBy, UsageRange, UsageSummary},
+    },
+};
+
+#[tauri::command]
+pub async fn get_usage(
+    app: AppHandle,
+    range: UsageRange,
+    group_by: UsageGroupBy,
+) -> Result<Vec<UsageSummary>> {
+    let ledger = app
+        .assistant()
+        .usage_ledger()
+        .ok_or_else(|| "Usage ledger is not ready".to_string())?;
+    let prices = app
+        .state::<AppStateCell>()
+        .with(|state| state.settings.model_prices.clone());
+    let res = ledger.summarize(&range, group_by, &prices).await?;
+    Ok(res)
+}
+
+#[tauri::command]
+pub async fn get_budgets(app: AppHandle) -> Result<Vec<BudgetStatus>> {
+    let res = app.assistant().budget_statuses().await?;
+    Ok(res)
+}
@@ -290,7 +290,6 @@ impl<R: Runtime> DeepLinkService<R> {
 
         if changed {
             secret_state.save()?;
-            self.app.propose_reconnect_assistant();
             self.app.try_reconnect_assistant();
             log::info!(
             service = "DeepLink",
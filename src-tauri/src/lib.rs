@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use tauri::{Manager, WindowEvent};
 use tauri_plugin_deep_link::DeepLinkExt;
 
@@ -18,7 +19,7 @@ use service::{
 };
 use utils::{SensitiveData, rand_bytes};
 
-const APP_SALT: &[u8] = b"Anda.AI";
+pub const APP_SALT: &[u8] = b"Anda.AI";
 
 pub type BoxError = Box<dyn std::error::Error>;
 pub type Result<T> = core::result::Result<T, BoxError>;
@@ -81,18 +82,49 @@ pub fn run() {
             api::auth::logout,
             api::i18n::get_translation,
             api::assistant::assistant_info,
+            api::assistant::assistant_status,
+            api::assistant::network_restored,
             api::assistant::assistant_name,
             api::assistant::caller_name,
             api::assistant::tool_call,
             api::assistant::agent_run,
+            api::assistant::agent_run_stream,
+            api::assistant::cancel_run,
+            api::conversation::list_conversations,
+            api::conversation::get_conversation,
+            api::conversation::delete_conversation,
+            api::conversation::rename_conversation,
+            api::conversation::export_conversation,
+            api::conversation::export_all_conversations,
+            api::conversation::search_conversations,
+            api::memory::execute_kip,
+            api::memory::list_concept_types,
+            api::memory::list_concepts,
+            api::memory::list_propositions,
+            api::memory::get_concept,
+            api::memory::update_concept_attributes,
+            api::memory::forget_concept,
+            api::memory::export_capsule,
+            api::memory::import_capsule,
+            api::memory::export_graph,
             api::settings::get_settings,
             api::settings::set_setting,
             api::settings::get_secret_setting,
             api::settings::set_secret_setting,
+            api::settings::list_local_models,
+            api::settings::test_provider,
+            api::storage::create_backup,
+            api::storage::restore_backup,
+            api::storage::verify_database,
+            api::storage::repair_database,
+            api::storage::storage_stats,
+            api::storage::compact_storage,
             api::updater::quit,
             api::updater::restart,
             api::updater::check_update,
             api::updater::update_supported,
+            api::usage::get_usage,
+            api::usage::get_budgets,
         ])
         .setup(|app| {
             if tauri::is_dev() {
@@ -162,10 +194,9 @@ pub fn run() {
                     state.assistant = Some(AssistantConfig {
                         root_secret: SensitiveData(rand_bytes::<48>().into()),
                         preferred_provider: "gemini".to_string(),
-                        gemini: None,
-                        deepseek: None,
-                        xai: None,
-                        openai: None,
+                        fallback_providers: Vec::new(),
+                        providers: BTreeMap::new(),
+                        budgets: BTreeMap::new(),
                     });
                 }
 
@@ -11,7 +11,7 @@ use tauri::{
 };
 use tauri_plugin_opener::OpenerExt;
 
-use crate::{Result, api::updater::is_mas_build, service::assistant::AndaAssistantExt};
+use crate::{Result, api::updater::is_mas_build};
 
 static ICON_BYTES: &[u8] = include_bytes!("../icons/icon.png");
 
@@ -199,11 +199,8 @@ fn menu_item_about<R: Runtime>(app: &tauri::AppHandle<R>) -> Result<PredefinedMe
 }
 
 fn quit<R: Runtime>(app: &AppHandle<R>) {
-    let app = app.clone();
-    async_runtime::spawn(async move {
-        app.assistant().close().await;
-        app.exit(0)
-    });
+    // the assistant shuts down on the exit request
+    app.exit(0)
 }
 
 pub fn reopen_window<R: Runtime>(
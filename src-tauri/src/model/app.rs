@@ -8,6 +8,7 @@ use ic_auth_verifier::{
 };
 use ic_cose_types::cose::kdf::{derive_a256gcm_key, hkdf256};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use tauri::Theme;
 
 use crate::utils::SensitiveData;
@@ -41,6 +42,21 @@ pub struct Settings {
     pub locale: String,
     pub theme: Option<Theme>, // "light" | "dark"
     pub https_proxy: Option<String>,
+    #[serde(default)]
+    pub model_prices: BTreeMap<String, ModelPrice>, // keyed by model name
+}
+
+/// Model prices in USD per million tokens.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl ModelPrice {
+    pub fn cost(&self, input_tokens: u64, output_tokens: u64) -> f64 {
+        (input_tokens as f64 * self.input + output_tokens as f64 * self.output) / 1_000_000.0
+    }
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -94,66 +110,439 @@ impl InternetIdentityAuth {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProviderKind {
+    #[default]
+    #[serde(alias = "openai")]
+    OpenaiCompatible,
+    Gemini,
+    Deepseek,
+    Xai,
+    Ollama,
+    Anthropic,
+}
+
+impl ProviderKind {
+    /// Infers the kind of the built-in providers from their names.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "openai" => Some(ProviderKind::OpenaiCompatible),
+            "gemini" => Some(ProviderKind::Gemini),
+            "deepseek" => Some(ProviderKind::Deepseek),
+            "xai" => Some(ProviderKind::Xai),
+            "ollama" => Some(ProviderKind::Ollama),
+            "anthropic" => Some(ProviderKind::Anthropic),
+            _ => None,
+        }
+    }
+
+    /// Local providers run without an API key.
+    pub fn requires_api_key(&self) -> bool {
+        !matches!(self, ProviderKind::Ollama)
+    }
+
+    /// The highest temperature the provider's API accepts, from 0.
+    pub fn max_temperature(&self) -> f64 {
+        match self {
+            ProviderKind::Anthropic => 1.0,
+            _ => 2.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct ModelProvider {
+    #[serde(default)]
+    pub kind: ProviderKind,
     pub model: String,
     pub api_key: String,
     pub api_base: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<f64>, // 0.0 ~ 2.0, 0.0 ~ 1.0 for Anthropic
+    #[serde(default)]
+    pub top_p: Option<f64>, // (0.0, 1.0]
+    #[serde(default)]
+    pub max_output_tokens: Option<usize>,
+    #[serde(default)]
+    pub reasoning_effort: Option<ReasoningEffort>,
+    #[serde(default)]
+    pub context_window: Option<usize>, // overrides the default context size of the provider
+}
+
+impl ModelProvider {
+    pub fn max_input_tokens(&self) -> usize {
+        if let Some(context_window) = self.context_window {
+            return context_window;
+        }
+
+        match self.kind {
+            ProviderKind::Deepseek => 128 * 1000,
+            ProviderKind::Gemini => 1000 * 1000,
+            ProviderKind::Xai => 256 * 1000,
+            ProviderKind::Ollama => 32 * 1000,
+            ProviderKind::Anthropic => 200 * 1000,
+            // the official OpenAI API
+            ProviderKind::OpenaiCompatible if self.api_base.is_none() => 400 * 1000,
+            ProviderKind::OpenaiCompatible => 64 * 1000,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let max_temperature = self.kind.max_temperature();
+        if let Some(temperature) = self.temperature
+            && !(0.0..=max_temperature).contains(&temperature)
+        {
+            return Err(format!(
+                "Temperature {} is out of range [0, {}] of {:?} providers",
+                temperature, max_temperature, self.kind
+            ));
+        }
+        if let Some(top_p) = self.top_p
+            && !(top_p > 0.0 && top_p <= 1.0)
+        {
+            return Err(format!("Top-p {} is out of range (0, 1]", top_p));
+        }
+        if self.max_output_tokens == Some(0) {
+            return Err("Max output tokens must be greater than 0".to_string());
+        }
+        if let Some(context_window) = self.context_window {
+            if context_window < 1024 {
+                return Err(format!(
+                    "Context window {} is less than 1024 tokens",
+                    context_window
+                ));
+            }
+            if let Some(max_output_tokens) = self.max_output_tokens
+                && max_output_tokens >= context_window
+            {
+                return Err(format!(
+                    "Max output tokens {} is not less than the context window {}",
+                    max_output_tokens, context_window
+                ));
+            }
+        }
+
+        if self.kind == ProviderKind::Deepseek && self.reasoning_effort.is_some() {
+            return Err("Reasoning effort is not supported by Deepseek providers".to_string());
+        }
+        if self.kind == ProviderKind::Anthropic && self.reasoning_effort.is_some() {
+            // extended thinking runs with the default temperature and only
+            // allows a top-p from 0.95
+            if self.temperature.is_some() {
+                return Err("Temperature can not be set with reasoning effort".to_string());
+            }
+            if let Some(top_p) = self.top_p
+                && top_p < 0.95
+            {
+                return Err(format!(
+                    "Top-p {} is less than 0.95, the minimum with reasoning effort",
+                    top_p
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "AssistantConfigSerde")]
 pub struct AssistantConfig {
     pub root_secret: SensitiveData<ByteArrayB64<48>>, // root private key
+    pub preferred_provider: String, // preferred model provider name, e.g., "gemini", "my-vllm"
+    pub fallback_providers: Vec<String>, // tried in order when the preferred provider fails
+    pub providers: BTreeMap<String, ModelProvider>, // user-named model providers
+    pub budgets: BTreeMap<String, TokenBudget>, // keyed by provider name
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetPeriod {
+    Daily,
+    #[default]
+    Monthly,
+}
+
+/// Token limits of a provider per period. Reaching the soft limit warns the
+/// user, reaching the hard limit refuses new runs until the period resets.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TokenBudget {
     #[serde(default)]
-    pub preferred_provider: String, // preferred model provider, e.g., "gemini", "openai"
-    pub gemini: Option<ModelProvider>,
-    pub deepseek: Option<ModelProvider>,
-    pub xai: Option<ModelProvider>,
-    pub openai: Option<ModelProvider>,
+    pub period: BudgetPeriod,
+    pub soft_limit: Option<u64>,
+    pub hard_limit: Option<u64>,
+}
+
+impl TokenBudget {
+    pub fn validate(&self) -> Result<(), String> {
+        if let (Some(soft), Some(hard)) = (self.soft_limit, self.hard_limit)
+            && soft > hard
+        {
+            return Err(format!(
+                "Soft limit {} is greater than hard limit {}",
+                soft, hard
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The serialized form of [`AssistantConfig`], which also accepts the legacy
+/// `gemini`, `deepseek`, `xai` and `openai` fields.
+#[derive(Deserialize)]
+struct AssistantConfigSerde {
+    root_secret: SensitiveData<ByteArrayB64<48>>,
+    #[serde(default)]
+    preferred_provider: String,
+    #[serde(default)]
+    fallback_providers: Vec<String>,
+    #[serde(default)]
+    providers: BTreeMap<String, NamedProviderSerde>,
+    #[serde(default)]
+    budgets: BTreeMap<String, TokenBudget>,
+    #[serde(default)]
+    gemini: Option<ModelProvider>,
+    #[serde(default)]
+    deepseek: Option<ModelProvider>,
+    #[serde(default)]
+    xai: Option<ModelProvider>,
+    #[serde(default)]
+    openai: Option<ModelProvider>,
+}
+
+/// A provider entry that may omit its kind, which the built-in provider
+/// names imply then.
+#[derive(Deserialize)]
+struct NamedProviderSerde {
+    #[serde(default)]
+    kind: Option<ProviderKind>,
+    #[serde(flatten)]
+    provider: ModelProvider,
+}
+
+impl From<AssistantConfigSerde> for AssistantConfig {
+    fn from(v: AssistantConfigSerde) -> Self {
+        let mut providers: BTreeMap<String, ModelProvider> = v
+            .providers
+            .into_iter()
+            .map(|(name, v)| {
+                let kind = v
+                    .kind
+                    .or_else(|| ProviderKind::from_name(&name))
+                    .unwrap_or_default();
+                (name, ModelProvider { kind, ..v.provider })
+            })
+            .collect();
+        for (name, provider) in [
+            ("gemini", v.gemini),
+            ("deepseek", v.deepseek),
+            ("xai", v.xai),
+            ("openai", v.openai),
+        ] {
+            if let Some(mut provider) = provider {
+                provider.kind = ProviderKind::from_name(name).unwrap_or_default();
+                providers.entry(name.to_string()).or_insert(provider);
+            }
+        }
+
+        AssistantConfig {
+            root_secret: v.root_secret,
+            preferred_provider: v.preferred_provider,
+            fallback_providers: v.fallback_providers,
+            providers,
+            budgets: v.budgets,
+        }
+    }
 }
 
 impl AssistantConfig {
+    /// Returns the smallest context size of the providers that may serve a request.
     pub fn get_max_input_tokens(&self) -> usize {
-        match self.preferred_provider.as_str() {
-            "deepseek" => 128 * 1000,
-            "gemini" => 1000 * 1000,
-            "openai" => 400 * 1000,
-            "xai" => 256 * 1000,
-            _ => 64 * 1000,
-        }
-    }
-
-    pub fn get_provider(&self) -> Option<(&str, &ModelProvider)> {
-        match self.preferred_provider.as_str() {
-            "deepseek" => self.deepseek.as_ref().and_then(|p| {
-                if p.api_key.is_empty() {
-                    None
-                } else {
-                    Some(("deepseek", p))
-                }
-            }),
-            "gemini" => self.gemini.as_ref().and_then(|p| {
-                if p.api_key.is_empty() {
-                    None
-                } else {
-                    Some(("gemini", p))
-                }
-            }),
-            "openai" => self.openai.as_ref().and_then(|p| {
-                if p.api_key.is_empty() {
-                    None
-                } else {
-                    Some(("openai", p))
-                }
-            }),
-            "xai" => self.xai.as_ref().and_then(|p| {
-                if p.api_key.is_empty() {
-                    None
-                } else {
-                    Some(("xai", p))
-                }
-            }),
-            _ => None,
+        self.get_providers()
+            .into_iter()
+            .map(|(_, provider)| provider.max_input_tokens())
+            .min()
+            .unwrap_or(64 * 1000)
+    }
+
+    pub fn get_provider(&self, name: &str) -> Option<(&str, &ModelProvider)> {
+        let (name, provider) = self.providers.get_key_value(name)?;
+        if provider.kind.requires_api_key() && provider.api_key.is_empty() {
+            None
+        } else {
+            Some((name.as_str(), provider))
         }
     }
+
+    /// Returns the preferred provider followed by the fallback providers,
+    /// skipping duplicates and providers without a required API key.
+    pub fn get_providers(&self) -> Vec<(&str, &ModelProvider)> {
+        let mut providers: Vec<(&str, &ModelProvider)> = Vec::new();
+        for name in std::iter::once(&self.preferred_provider).chain(self.fallback_providers.iter())
+        {
+            if providers.iter().any(|(n, _)| n == name) {
+                continue;
+            }
+            if let Some(provider) = self.get_provider(name) {
+                providers.push(provider);
+            }
+        }
+        providers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn root_secret() -> serde_json::Value {
+        serde_json::to_value(SensitiveData(ByteArrayB64([1u8; 48]))).unwrap()
+    }
+
+    fn provider(kind: ProviderKind) -> ModelProvider {
+        ModelProvider {
+            kind,
+            model: "test-model".to_string(),
+            api_key: "test-key".to_string(),
+            api_base: None,
+            temperature: None,
+            top_p: None,
+            max_output_tokens: None,
+            reasoning_effort: None,
+            context_window: None,
+        }
+    }
+
+    #[test]
+    fn migrates_legacy_providers() {
+        let cfg: AssistantConfig = serde_json::from_value(json!({
+            "root_secret": root_secret(),
+            "preferred_provider": "deepseek",
+            "gemini": {"model": "gemini-2.5-pro", "api_key": "g"},
+            "deepseek": {"model": "deepseek-chat", "api_key": "d", "api_base": null},
+            "openai": {"model": "gpt-5", "api_key": ""},
+        }))
+        .unwrap();
+
+        assert_eq!(cfg.preferred_provider, "deepseek");
+        assert!(cfg.fallback_providers.is_empty());
+        assert_eq!(
+            cfg.providers.keys().collect::<Vec<_>>(),
+            ["deepseek", "gemini", "openai"]
+        );
+        assert_eq!(cfg.providers["gemini"].kind, ProviderKind::Gemini);
+        assert_eq!(cfg.providers["deepseek"].kind, ProviderKind::Deepseek);
+        assert_eq!(cfg.providers["openai"].kind, ProviderKind::OpenaiCompatible);
+        // a provider without its API key is not served
+        assert_eq!(
+            cfg.get_providers()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>(),
+            ["deepseek"]
+        );
+
+        // migrated configs are written without the legacy fields
+        let value = serde_json::to_value(&cfg).unwrap();
+        assert!(value.get("gemini").is_none());
+        assert_eq!(value["providers"]["gemini"]["kind"], "gemini");
+    }
+
+    #[test]
+    fn keeps_named_providers_over_legacy_ones() {
+        let cfg: AssistantConfig = serde_json::from_value(json!({
+            "root_secret": root_secret(),
+            "preferred_provider": "xai",
+            "fallback_providers": ["local", "xai"],
+            "providers": {
+                "xai": {"kind": "xai", "model": "grok-4", "api_key": "new"},
+                "local": {"kind": "ollama", "model": "llama3", "api_key": "", "context_window": 8192},
+            },
+            "xai": {"model": "grok-3", "api_key": "old"},
+        }))
+        .unwrap();
+
+        assert_eq!(cfg.providers["xai"].model, "grok-4");
+        assert_eq!(
+            cfg.get_providers()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>(),
+            ["xai", "local"]
+        );
+        assert_eq!(cfg.get_max_input_tokens(), 8192);
+    }
+
+    #[test]
+    fn infers_the_kind_of_named_providers() {
+        let cfg: AssistantConfig = serde_json::from_value(json!({
+            "root_secret": root_secret(),
+            "providers": {
+                "gemini": {"model": "gemini-2.5-pro", "api_key": "g"},
+                "anthropic": {"model": "claude-sonnet-4", "api_key": "a"},
+                "deepseek": {"kind": "openai-compatible", "model": "deepseek-chat", "api_key": "d"},
+                "my-vllm": {"model": "qwen3", "api_key": "v", "api_base": "http://localhost:8000/v1"},
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(cfg.providers["gemini"].kind, ProviderKind::Gemini);
+        assert_eq!(cfg.providers["anthropic"].kind, ProviderKind::Anthropic);
+        assert_eq!(
+            cfg.providers["deepseek"].kind,
+            ProviderKind::OpenaiCompatible
+        );
+        assert_eq!(
+            cfg.providers["my-vllm"].kind,
+            ProviderKind::OpenaiCompatible
+        );
+    }
+
+    #[test]
+    fn validates_provider_parameters() {
+        let mut openai = provider(ProviderKind::OpenaiCompatible);
+        openai.temperature = Some(1.5);
+        openai.top_p = Some(0.5);
+        openai.reasoning_effort = Some(ReasoningEffort::High);
+        assert_eq!(openai.validate(), Ok(()));
+        openai.temperature = Some(2.5);
+        assert!(openai.validate().is_err());
+
+        let mut deepseek = provider(ProviderKind::Deepseek);
+        deepseek.top_p = Some(0.5);
+        assert_eq!(deepseek.validate(), Ok(()));
+        deepseek.reasoning_effort = Some(ReasoningEffort::Low);
+        assert!(deepseek.validate().is_err());
+
+        let mut anthropic = provider(ProviderKind::Anthropic);
+        anthropic.temperature = Some(1.0);
+        anthropic.top_p = Some(0.5);
+        assert_eq!(anthropic.validate(), Ok(()));
+        anthropic.temperature = Some(1.5);
+        assert!(anthropic.validate().is_err());
+
+        anthropic.temperature = None;
+        anthropic.reasoning_effort = Some(ReasoningEffort::Low);
+        assert!(anthropic.validate().is_err());
+        anthropic.top_p = Some(0.95);
+        assert_eq!(anthropic.validate(), Ok(()));
+        anthropic.temperature = Some(0.5);
+        assert!(anthropic.validate().is_err());
+
+        let mut local = provider(ProviderKind::Ollama);
+        local.context_window = Some(4096);
+        local.max_output_tokens = Some(4096);
+        assert!(local.validate().is_err());
+        local.max_output_tokens = Some(1024);
+        assert_eq!(local.validate(), Ok(()));
+    }
 }
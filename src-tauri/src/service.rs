@@ -1,3 +1,22 @@
+pub mod anthropic;
 pub mod assistant;
+pub mod backup;
+pub mod capsule;
+pub mod export;
+pub mod gemini;
+pub mod graph;
+pub mod history;
 pub mod icp;
+pub mod integrity;
+pub mod nexus;
+pub mod openai;
+pub mod provider;
+pub mod run;
+pub mod search;
+pub mod sse;
 pub mod stablecell;
+pub mod storage;
+pub mod store;
+#[cfg(test)]
+pub mod test_server;
+pub mod usage;
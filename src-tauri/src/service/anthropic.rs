@@ -0,0 +1,845 @@
+use anda_core::{
+    AgentOutput, BoxError, BoxPinFut, CompletionRequest, ContentPart, Json, Message, ToolCall,
+    Usage,
+};
+use anda_db::unix_ms;
+use anda_engine::model::CompletionFeaturesDyn;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use super::{
+    provider::HttpError,
+    run::emit_text_delta,
+    sse::{EventStream, is_event_stream},
+};
+use crate::model::app::ReasoningEffort;
+
+pub const API_BASE: &str = "https://api.anthropic.com/v1";
+pub const API_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: usize = 8192;
+
+/// Anthropic Messages API client.
+#[derive(Clone)]
+pub struct Client {
+    endpoint: String,
+    api_key: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(api_key: &str, endpoint: Option<String>) -> Self {
+        let endpoint = endpoint.unwrap_or_else(|| API_BASE.to_string());
+        Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn with_client(self, http: reqwest::Client) -> Self {
+        Self { http, ..self }
+    }
+
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel {
+            client: self.clone(),
+            model: model.to_string(),
+            top_p: None,
+            reasoning_effort: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CompletionModel {
+    client: Client,
+    model: String,
+    top_p: Option<f64>,
+    reasoning_effort: Option<ReasoningEffort>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    #[serde(default)]
+    content: Vec<Json>,
+    stop_reason: Option<String>,
+    #[serde(default)]
+    usage: ResponseUsage,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ResponseUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let model = self.clone();
+        Box::pin(async move { model.completion(req).await })
+    }
+}
+
+impl CompletionModel {
+    pub fn with_top_p(self, top_p: Option<f64>) -> Self {
+        Self { top_p, ..self }
+    }
+
+    /// Enables extended thinking with a token budget by effort.
+    pub fn with_reasoning_effort(self, reasoning_effort: Option<ReasoningEffort>) -> Self {
+        Self {
+            reasoning_effort,
+            ..self
+        }
+    }
+
+    async fn completion(&self, req: CompletionRequest) -> Result<AgentOutput, BoxError> {
+        // the messages of this round in the generic and in the Anthropic format
+        let mut chat_history: Vec<Message> = Vec::new();
+        let mut content = req.content.clone();
+        if !req.prompt.is_empty() {
+            content.push(ContentPart::Text {
+                text: req.prompt.clone(),
+            });
+        }
+        if !content.is_empty() {
+            chat_history.push(Message {
+                role: req.role.clone().unwrap_or_else(|| "user".to_string()),
+                content,
+                timestamp: Some(unix_ms()),
+                ..Default::default()
+            });
+        }
+        let signatures = signatures(&req.raw_history);
+        let mut raw_history = to_messages(
+            req.chat_history.iter().chain(chat_history.iter()),
+            &signatures,
+        );
+
+        let mut system = req.instructions.clone();
+        if !req.documents.is_empty() {
+            system.push_str("\n\n");
+            system.push_str(&req.documents.to_string());
+        }
+
+        let mut max_tokens = req.max_output_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+        let thinking_budget = self.reasoning_effort.map(|effort| match effort {
+            ReasoningEffort::Low => 1024,
+            ReasoningEffort::Medium => 4096,
+            ReasoningEffort::High => 16384,
+        });
+        // the thinking budget is part of the max tokens
+        if let Some(budget) = thinking_budget {
+            max_tokens += budget;
+        }
+
+        let mut body = json!({
+            "model": self.model,
+            "max_tokens": max_tokens,
+            "messages": merge_messages(req.raw_history.iter().chain(raw_history.iter())),
+            "stream": true,
+        });
+        if !system.is_empty() {
+            body["system"] = json!(system);
+        }
+        match thinking_budget {
+            Some(budget) => {
+                body["thinking"] = json!({"type": "enabled", "budget_tokens": budget});
+            }
+            // extended thinking does not allow to change the temperature
+            None => {
+                if let Some(temperature) = req.temperature {
+                    body["temperature"] = json!(temperature);
+                }
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(stop) = &req.stop {
+            body["stop_sequences"] = json!(stop);
+        }
+        if !req.tools.is_empty() {
+            body["tools"] = req
+                .tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "name": tool.name,
+                        "description": tool.description,
+                        "input_schema": tool.parameters,
+                    })
+                })
+                .collect();
+            if req.tool_choice_required {
+                body["tool_choice"] = json!({"type": "any"});
+            }
+        }
+
+        let res = self
+            .client
+            .http
+            .post(format!("{}/messages", self.client.endpoint))
+            .header("x-api-key", &self.client.api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(&body)
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            let text = res.text().await.unwrap_or_default();
+            return Err(HttpError {
+                status: status.as_u16(),
+                message: format!("{} completions error: {}", self.model, text),
+            }
+            .into());
+        }
+
+        let res: MessagesResponse = if is_event_stream(&res) {
+            read_stream(res)
+                .await
+                .map_err(|err| format!("{} completions error: {}", self.model, err))?
+        } else {
+            res.json().await?
+        };
+        let mut output = AgentOutput {
+            usage: Usage {
+                input_tokens: res.usage.input_tokens,
+                output_tokens: res.usage.output_tokens,
+                requests: 1,
+            },
+            ..Default::default()
+        };
+
+        let mut parts: Vec<ContentPart> = Vec::with_capacity(res.content.len());
+        let mut texts: Vec<&str> = Vec::new();
+        for block in &res.content {
+            match block["type"].as_str() {
+                Some("text") => {
+                    let text = block["text"].as_str().unwrap_or_default();
+                    texts.push(text);
+                    parts.push(ContentPart::Text {
+                        text: text.to_string(),
+                    });
+                }
+                Some("thinking") => {
+                    let text = block["thinking"].as_str().unwrap_or_default().to_string();
+                    parts.push(ContentPart::Reasoning { text });
+                }
+                // sent back as it is, the other providers can not read it
+                Some("redacted_thinking") => parts.push(ContentPart::Any(block.clone())),
+                Some("tool_use") => {
+                    let id = block["id"].as_str().unwrap_or_default().to_string();
+                    let name = block["name"].as_str().unwrap_or_default().to_string();
+                    let args = block["input"].clone();
+                    output.tool_calls.push(ToolCall {
+                        name: name.clone(),
+                        args: args.clone(),
+                        result: None,
+                        call_id: Some(id.clone()),
+                        remote_id: None,
+                    });
+                    parts.push(ContentPart::ToolCall {
+                        name,
+                        args,
+                        call_id: Some(id),
+                    });
+                }
+                _ => {}
+            }
+        }
+        output.content = texts.join("\n");
+
+        match res.stop_reason.as_deref() {
+            Some("end_turn") | Some("tool_use") | Some("stop_sequence") | None => {}
+            Some("max_tokens") if !output.tool_calls.is_empty() => {}
+            Some(reason) => {
+                output.failed_reason =
+                    Some(format!("{} stopped with reason: {}", self.model, reason));
+            }
+        }
+
+        // the raw history keeps the response blocks as they are, with the
+        // signatures of the thinking blocks, the engine sends it back on the
+        // next round of the run
+        if !res.content.is_empty() {
+            raw_history.push(json!({"role": "assistant", "content": res.content}));
+        }
+        chat_history.push(Message {
+            role: "assistant".to_string(),
+            content: parts,
+            timestamp: Some(unix_ms()),
+            ..Default::default()
+        });
+        output.raw_history = raw_history;
+        output.chat_history = chat_history;
+        Ok(output)
+    }
+}
+
+/// Collects the events of a streamed response into the response and emits
+/// its text as it arrives.
+async fn read_stream(res: reqwest::Response) -> Result<MessagesResponse, BoxError> {
+    let mut events = EventStream::new(res);
+    let mut res = MessagesResponse {
+        content: Vec::new(),
+        stop_reason: None,
+        usage: ResponseUsage::default(),
+    };
+    let mut inputs: BTreeMap<usize, String> = BTreeMap::new(); // block index -> partial JSON
+    while let Some(event) = events.next().await? {
+        let data: Json = serde_json::from_str(&event.data)?;
+        let index = data["index"].as_u64().unwrap_or_default() as usize;
+        match data["type"].as_str() {
+            Some("message_start") => {
+                let usage = &data["message"]["usage"];
+                res.usage.input_tokens = usage["input_tokens"].as_u64().unwrap_or_default();
+                res.usage.output_tokens = usage["output_tokens"].as_u64().unwrap_or_default();
+            }
+            Some("content_block_start") => {
+                if res.content.len() <= index {
+                    res.content.resize(index + 1, Json::Null);
+                }
+                res.content[index] = data["content_block"].clone();
+            }
+            Some("content_block_delta") => {
+                let block = match res.content.get_mut(index) {
+                    Some(block) => block,
+                    None => continue,
+                };
+                let delta = &data["delta"];
+                match delta["type"].as_str() {
+                    Some("text_delta") => {
+                        let text = delta["text"].as_str().unwrap_or_default();
+                        emit_text_delta(text);
+                        append(&mut block["text"], text);
+                    }
+                    Some("thinking_delta") => {
+                        append(
+                            &mut block["thinking"],
+                            delta["thinking"].as_str().unwrap_or_default(),
+                        );
+                    }
+                    Some("signature_delta") => block["signature"] = delta["signature"].clone(),
+                    Some("input_json_delta") => inputs
+                        .entry(index)
+                        .or_default()
+                        .push_str(delta["partial_json"].as_str().unwrap_or_default()),
+                    _ => {}
+                }
+            }
+            Some("content_block_stop") => {
+                if let Some(input) = inputs.remove(&index)
+                    && let Some(block) = res.content.get_mut(index)
+                    && !input.trim().is_empty()
+                {
+                    block["input"] = serde_json::from_str(&input)?;
+                }
+            }
+            Some("message_delta") => {
+                if let Some(reason) = data["delta"]["stop_reason"].as_str() {
+                    res.stop_reason = Some(reason.to_string());
+                }
+                if let Some(tokens) = data["usage"]["output_tokens"].as_u64() {
+                    res.usage.output_tokens = tokens;
+                }
+            }
+            Some("error") => return Err(data["error"].to_string().into()),
+            _ => {}
+        }
+    }
+    res.content.retain(|block| !block.is_null());
+    Ok(res)
+}
+
+fn append(value: &mut Json, text: &str) {
+    match value {
+        Json::String(s) => s.push_str(text),
+        _ => *value = Json::String(text.to_string()),
+    }
+}
+
+/// Collects the signatures of the thinking blocks in the raw history, by
+/// thinking text.
+fn signatures(raw_history: &[Json]) -> HashMap<String, String> {
+    raw_history
+        .iter()
+        .filter_map(|msg| msg["content"].as_array())
+        .flatten()
+        .filter(|block| block["type"] == "thinking")
+        .filter_map(|block| {
+            Some((
+                block["thinking"].as_str()?.to_string(),
+                block["signature"].as_str()?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Maps messages to Anthropic messages. Tool outputs are sent as
+/// `tool_result` blocks of user messages.
+fn to_messages<'a>(
+    history: impl IntoIterator<Item = &'a Message>,
+    signatures: &HashMap<String, String>,
+) -> Vec<Json> {
+    let mut ids = CallIds::default();
+    history
+        .into_iter()
+        .filter_map(|msg| {
+            let role = match msg.role.as_str() {
+                "assistant" => "assistant",
+                _ => "user",
+            };
+            let blocks: Vec<Json> = msg
+                .content
+                .iter()
+                .filter_map(|part| to_block(part, &mut ids, signatures))
+                .collect();
+            if blocks.is_empty() {
+                None
+            } else {
+                Some(json!({"role": role, "content": blocks}))
+            }
+        })
+        .collect()
+}
+
+/// Merges consecutive messages of the same role, as the API requires
+/// alternating roles.
+fn merge_messages<'a>(messages: impl IntoIterator<Item = &'a Json>) -> Vec<Json> {
+    let mut merged: Vec<Json> = Vec::new();
+    for msg in messages {
+        let blocks = match &msg["content"] {
+            Json::Array(blocks) => blocks.clone(),
+            Json::String(text) => vec![json!({"type": "text", "text": text})],
+            _ => continue,
+        };
+        match merged.last_mut() {
+            Some(last) if last["role"] == msg["role"] => {
+                if let Some(content) = last["content"].as_array_mut() {
+                    content.extend(blocks);
+                }
+            }
+            _ => merged.push(json!({"role": msg["role"], "content": blocks})),
+        }
+    }
+    merged
+}
+
+/// Assigns ids to the tool calls without one. A tool output without an id
+/// answers the earliest unanswered call of the same tool.
+#[derive(Default)]
+struct CallIds {
+    next: usize,
+    pending: BTreeMap<String, VecDeque<String>>, // tool name -> ids
+}
+
+impl CallIds {
+    fn call(&mut self, name: &str, call_id: &Option<String>) -> String {
+        if let Some(id) = call_id {
+            return id.clone();
+        }
+        self.next += 1;
+        let id = format!("call_{}_{}", self.next, name);
+        self.pending
+            .entry(name.to_string())
+            .or_default()
+            .push_back(id.clone());
+        id
+    }
+
+    fn output(&mut self, name: &str, call_id: &Option<String>) -> String {
+        if let Some(id) = call_id {
+            return id.clone();
+        }
+        match self.pending.get_mut(name).and_then(|ids| ids.pop_front()) {
+            Some(id) => id,
+            None => self.call(name, &None),
+        }
+    }
+}
+
+fn to_block(
+    part: &ContentPart,
+    ids: &mut CallIds,
+    signatures: &HashMap<String, String>,
+) -> Option<Json> {
+    match part {
+        ContentPart::Text { text } => Some(json!({"type": "text", "text": text})),
+        // a thinking block is dropped if its signature is not in the raw
+        // history, which the API allows for all but the last turn
+        ContentPart::Reasoning { text } => signatures
+            .get(text)
+            .map(|signature| json!({"type": "thinking", "thinking": text, "signature": signature})),
+        ContentPart::InlineData { mime_type, data } if mime_type.starts_with("image/") => {
+            Some(json!({
+                "type": "image",
+                "source": {"type": "base64", "media_type": mime_type, "data": data},
+            }))
+        }
+        ContentPart::InlineData { mime_type, .. } => Some(json!({
+            "type": "text",
+            "text": format!("[unsupported inline data: {}]", mime_type),
+        })),
+        ContentPart::FileData {
+            file_uri,
+            mime_type,
+        } => match mime_type.as_deref() {
+            Some(mime_type) if mime_type.starts_with("image/") => Some(json!({
+                "type": "image",
+                "source": {"type": "url", "url": file_uri},
+            })),
+            _ => Some(json!({"type": "text", "text": file_uri})),
+        },
+        ContentPart::ToolCall {
+            name,
+            args,
+            call_id,
+        } => Some(json!({
+            "type": "tool_use",
+            "id": ids.call(name, call_id),
+            "name": name,
+            "input": args,
+        })),
+        ContentPart::ToolOutput {
+            name,
+            output,
+            call_id,
+            ..
+        } => Some(json!({
+            "type": "tool_result",
+            "tool_use_id": ids.output(name, call_id),
+            "content": match output {
+                Json::String(text) => text.clone(),
+                other => other.to_string(),
+            },
+        })),
+        ContentPart::Action { .. } => None,
+        ContentPart::Any(value) => Some(value.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_server::StandIn;
+
+    fn thinking_response(signature: &str) -> String {
+        json!({
+            "content": [
+                {"type": "thinking", "thinking": "Look up the weather first.", "signature": signature},
+                {"type": "tool_use", "id": "toolu_1", "name": "weather", "input": {"city": "Paris"}},
+            ],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 20, "output_tokens": 10},
+        })
+        .to_string()
+    }
+
+    fn text_response(text: &str) -> String {
+        json!({
+            "content": [{"type": "text", "text": text}],
+            "stop_reason": "end_turn",
+            "usage": {"input_tokens": 40, "output_tokens": 5},
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn sends_thinking_back_with_tool_results() {
+        let server = StandIn::start(vec![
+            (200, thinking_response("sig-round-trip")),
+            (200, text_response("Sunny")),
+        ])
+        .await;
+        let model = Client::new("test-key", Some(server.url.clone()))
+            .completion_model("claude-test")
+            .with_reasoning_effort(Some(ReasoningEffort::Low));
+
+        let first = model
+            .completion(CompletionRequest {
+                prompt: "Weather in Paris?".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(first.tool_calls.len(), 1);
+        assert_eq!(first.tool_calls[0].call_id.as_deref(), Some("toolu_1"));
+        assert_eq!(
+            first.chat_history[1].content[0],
+            ContentPart::Reasoning {
+                text: "Look up the weather first.".to_string()
+            }
+        );
+
+        // the next round continues the raw history, as the engine does
+        // after the tool ran
+        let second = model
+            .completion(CompletionRequest {
+                raw_history: first.raw_history.clone(),
+                content: vec![ContentPart::ToolOutput {
+                    name: "weather".to_string(),
+                    output: json!("sunny"),
+                    call_id: Some("toolu_1".to_string()),
+                    remote_id: None,
+                }],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(second.content, "Sunny");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/messages");
+        assert_eq!(requests[0].header("x-api-key"), Some("test-key"));
+        let first_body = requests[0].json();
+        assert_eq!(first_body["thinking"]["budget_tokens"], 1024);
+        assert_eq!(first_body["max_tokens"], DEFAULT_MAX_TOKENS + 1024);
+
+        let messages = requests[1].json()["messages"].clone();
+        assert_eq!(
+            messages,
+            json!([
+                {"role": "user", "content": [{"type": "text", "text": "Weather in Paris?"}]},
+                {"role": "assistant", "content": [
+                    {"type": "thinking", "thinking": "Look up the weather first.", "signature": "sig-round-trip"},
+                    {"type": "tool_use", "id": "toolu_1", "name": "weather", "input": {"city": "Paris"}},
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "sunny"},
+                ]},
+            ])
+        );
+    }
+
+    fn events(events: &[Json]) -> String {
+        events
+            .iter()
+            .map(|event| {
+                format!(
+                    "event: {}\ndata: {}\n\n",
+                    event["type"].as_str().unwrap(),
+                    event
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn collects_streamed_blocks() {
+        let server = StandIn::start(vec![(
+            200,
+            events(&[
+                json!({"type": "message_start", "message": {"usage": {"input_tokens": 20, "output_tokens": 1}}}),
+                json!({"type": "content_block_start", "index": 0, "content_block": {"type": "thinking", "thinking": "", "signature": ""}}),
+                json!({"type": "content_block_delta", "index": 0, "delta": {"type": "thinking_delta", "thinking": "Look up "}}),
+                json!({"type": "content_block_delta", "index": 0, "delta": {"type": "thinking_delta", "thinking": "the weather."}}),
+                json!({"type": "content_block_delta", "index": 0, "delta": {"type": "signature_delta", "signature": "sig-stream"}}),
+                json!({"type": "content_block_stop", "index": 0}),
+                json!({"type": "ping"}),
+                json!({"type": "content_block_start", "index": 1, "content_block": {"type": "text", "text": ""}}),
+                json!({"type": "content_block_delta", "index": 1, "delta": {"type": "text_delta", "text": "Checking."}}),
+                json!({"type": "content_block_stop", "index": 1}),
+                json!({"type": "content_block_start", "index": 2, "content_block": {"type": "tool_use", "id": "toolu_1", "name": "weather", "input": {}}}),
+                json!({"type": "content_block_delta", "index": 2, "delta": {"type": "input_json_delta", "partial_json": "{\"city\":"}}),
+                json!({"type": "content_block_delta", "index": 2, "delta": {"type": "input_json_delta", "partial_json": " \"Paris\"}"}}),
+                json!({"type": "content_block_stop", "index": 2}),
+                json!({"type": "message_delta", "delta": {"stop_reason": "tool_use"}, "usage": {"output_tokens": 15}}),
+                json!({"type": "message_stop"}),
+            ]),
+        )])
+        .await;
+        let model =
+            Client::new("test-key", Some(server.url.clone())).completion_model("claude-test");
+
+        let output = model
+            .completion(CompletionRequest {
+                prompt: "Weather in Paris?".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(output.content, "Checking.");
+        assert_eq!(output.failed_reason, None);
+        assert_eq!(output.usage.input_tokens, 20);
+        assert_eq!(output.usage.output_tokens, 15);
+        assert_eq!(output.tool_calls[0].args, json!({"city": "Paris"}));
+        assert_eq!(
+            output.raw_history.last(),
+            Some(&json!({"role": "assistant", "content": [
+                {"type": "thinking", "thinking": "Look up the weather.", "signature": "sig-stream"},
+                {"type": "text", "text": "Checking."},
+                {"type": "tool_use", "id": "toolu_1", "name": "weather", "input": {"city": "Paris"}},
+            ]}))
+        );
+        assert_eq!(server.requests()[0].json()["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn sends_temperature_unchanged() {
+        let server = StandIn::start(vec![(200, text_response("OK"))]).await;
+        let model = Client::new("test-key", Some(server.url.clone()))
+            .completion_model("claude-test")
+            .with_top_p(Some(0.9));
+        model
+            .completion(CompletionRequest {
+                prompt: "Hi".to_string(),
+                temperature: Some(1.5),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let body = server.requests()[0].json();
+        assert_eq!(body["temperature"], 1.5);
+        assert_eq!(body["top_p"], 0.9);
+        assert!(body.get("thinking").is_none());
+    }
+
+    fn message(role: &str, content: Vec<ContentPart>) -> Message {
+        Message {
+            role: role.to_string(),
+            content,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn maps_messages_to_blocks() {
+        let history = vec![
+            message(
+                "user",
+                vec![
+                    ContentPart::Text {
+                        text: "What is on it?".to_string(),
+                    },
+                    ContentPart::InlineData {
+                        mime_type: "image/png".to_string(),
+                        data: b"hi".to_vec().into(),
+                    },
+                    ContentPart::InlineData {
+                        mime_type: "application/pdf".to_string(),
+                        data: b"hi".to_vec().into(),
+                    },
+                    ContentPart::FileData {
+                        file_uri: "https://example.com/a.jpg".to_string(),
+                        mime_type: Some("image/jpeg".to_string()),
+                    },
+                ],
+            ),
+            // a thinking block with an unknown signature is dropped, and so
+            // is a message left without blocks
+            message(
+                "assistant",
+                vec![ContentPart::Reasoning {
+                    text: "Thinking without a signature.".to_string(),
+                }],
+            ),
+            message(
+                "assistant",
+                vec![
+                    ContentPart::ToolCall {
+                        name: "search".to_string(),
+                        args: json!({"q": "a"}),
+                        call_id: None,
+                    },
+                    ContentPart::ToolCall {
+                        name: "search".to_string(),
+                        args: json!({"q": "b"}),
+                        call_id: None,
+                    },
+                ],
+            ),
+            message(
+                "tool",
+                vec![
+                    ContentPart::ToolOutput {
+                        name: "search".to_string(),
+                        output: json!({"hits": 1}),
+                        call_id: None,
+                        remote_id: None,
+                    },
+                    ContentPart::ToolOutput {
+                        name: "search".to_string(),
+                        output: json!("none"),
+                        call_id: None,
+                        remote_id: None,
+                    },
+                ],
+            ),
+        ];
+
+        assert_eq!(
+            to_messages(&history, &HashMap::new()),
+            vec![
+                json!({"role": "user", "content": [
+                    {"type": "text", "text": "What is on it?"},
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "aGk="}},
+                    {"type": "text", "text": "[unsupported inline data: application/pdf]"},
+                    {"type": "image", "source": {"type": "url", "url": "https://example.com/a.jpg"}},
+                ]}),
+                json!({"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "call_1_search", "name": "search", "input": {"q": "a"}},
+                    {"type": "tool_use", "id": "call_2_search", "name": "search", "input": {"q": "b"}},
+                ]}),
+                json!({"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "call_1_search", "content": "{\"hits\":1}"},
+                    {"type": "tool_result", "tool_use_id": "call_2_search", "content": "none"},
+                ]}),
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_thinking_with_known_signature() {
+        let mut ids = CallIds::default();
+        let part = ContentPart::Reasoning {
+            text: "Known thinking.".to_string(),
+        };
+        assert_eq!(to_block(&part, &mut ids, &HashMap::new()), None);
+
+        let raw_history = [
+            json!({"role": "user", "content": "Hi"}),
+            json!({"role": "assistant", "content": [
+                {"type": "thinking", "thinking": "Known thinking.", "signature": "sig-known"},
+                {"type": "text", "text": "Hello"},
+            ]}),
+        ];
+        let signatures = signatures(&raw_history);
+        assert_eq!(
+            to_block(&part, &mut ids, &signatures),
+            Some(
+                json!({"type": "thinking", "thinking": "Known thinking.", "signature": "sig-known"})
+            )
+        );
+
+        let redacted = json!({"type": "redacted_thinking", "data": "abc"});
+        assert_eq!(
+            to_block(&ContentPart::Any(redacted.clone()), &mut ids, &signatures),
+            Some(redacted)
+        );
+    }
+
+    #[test]
+    fn merges_messages_of_the_same_role() {
+        let messages = [
+            json!({"role": "user", "content": "a"}),
+            json!({"role": "user", "content": [{"type": "text", "text": "b"}]}),
+            json!({"role": "assistant", "content": "c"}),
+        ];
+        assert_eq!(
+            merge_messages(&messages),
+            vec![
+                json!({"role": "user", "content": [
+                    {"type": "text", "text": "a"},
+                    {"type": "text", "text": "b"},
+                ]}),
+                json!({"role": "assistant", "content": [{"type": "text", "text": "c"}]}),
+            ]
+        );
+    }
+}
@@ -1,52 +1,88 @@
 use anda_assistant::Assistant;
-use anda_core::{BoxError, BoxPinFut, Path as DBPath, derivation_path_with};
+use anda_core::{AgentOutput, BoxError, BoxPinFut, Path as DBPath, derivation_path_with};
 use anda_db::{
     database::{AndaDB, DBConfig},
-    storage::StorageConfig,
+    unix_ms,
 };
 use anda_engine::{
     context::{Web3ClientFeatures, Web3SDK},
     engine::{AgentInfo, Engine, EngineBuilder},
     management::{BaseManagement, SYSTEM_PATH, Visibility},
-    memory::MemoryTool,
-    model::{Model, Proxy, deepseek, gemini, openai, request_client_builder, xai},
-    store::{LocalFileSystem, Store},
+    memory::{MemoryManagement, MemoryTool},
+    model::Model,
+    store::Store,
 };
-use anda_object_store::MetaStoreBuilder;
 use anda_web3_client::client::Client as Web3Client;
 use arc_swap::ArcSwap;
 use candid::Principal;
 use futures::try_join;
-use ic_agent::Agent;
+use ic_agent::{Agent, Identity};
 use ic_auth_types::ByteBufB64;
 use ic_auth_verifier::{AtomicIdentity, sha3_256};
 use parking_lot::RwLock;
+use serde::Serialize;
+use serde_json::json;
 use std::{
     collections::{BTreeMap, BTreeSet},
     fs,
-    path::PathBuf,
+    future::Future,
+    path::{Path, PathBuf},
     sync::{
         Arc,
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
     },
     time::Duration,
 };
 use tauri::{
     AppHandle, Emitter, Manager, Runtime, async_runtime,
+    ipc::Channel,
     plugin::{Builder, TauriPlugin},
 };
+use tokio::sync::Notify;
 use tokio_util::sync::CancellationToken;
 
-use crate::{AppStateCell, SecretStateCell, model::app::AssistantConfig};
+use crate::{AppStateCell, SecretStateCell, model::app::AssistantConfig, utils::rand_bytes};
 
-use super::icp::{ICP_HOST, ICPClientExt};
+use super::{
+    history::{ConversationHistory, message_text, wait_finished},
+    icp::{ICP_HOST, ICPClientExt},
+    provider::{self, FallbackCompleter, ProviderCompleter, build_completer},
+    run::{ObservedCompleter, RunContext, RunError, RunEvent, RunRounds},
+    search::ConversationIndex,
+    store::{open_object_store, storage_config},
+    usage::{BudgetStatus, UsageLedger, UsageRecord},
+};
 
 pub const ASSISTANT_EVENT: &str = "AssistantReady";
+pub const STATUS_EVENT: &str = "AssistantStatus";
+pub const BUDGET_EVENT: &str = "BudgetWarning";
+pub const MEMORY_EVENT: &str = "MemoryChanged";
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(2);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(300);
 
 pub static SYSTEM_INSTRUCTIONS: &str = include_str!("../../kip/SystemInstructions.md");
 
+/// The lifecycle status of the assistant.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum AssistantStatus {
+    Disconnected,
+    Connecting,
+    Ready {
+        provider: String,
+        model: String,
+    },
+    /// Connected, but no model provider is configured.
+    NoProvider,
+    Failed {
+        reason: String,
+        retry_at: Option<u64>, // unix timestamp in milliseconds
+    },
+}
+
 pub struct AndaAssistant<R: Runtime> {
-    #[allow(dead_code)]
     app: AppHandle<R>,
     inner: Arc<InnerAssistant>,
 }
@@ -55,9 +91,68 @@ struct InnerAssistant {
     dir: PathBuf,
     db: RwLock<Option<Arc<AndaDB>>>,
     assistant: RwLock<Option<Arc<Assistant>>>,
+    usage: RwLock<Option<Arc<UsageLedger>>>,
+    history: RwLock<Option<Arc<ConversationHistory>>>,
+    search: RwLock<Option<Arc<ConversationIndex>>>,
+    status: RwLock<AssistantStatus>,
     engine: ArcSwap<Engine>,
-    should_restart: Arc<AtomicU64>,
-    cancel_token: CancellationToken,
+    runs: RwLock<BTreeMap<String, CancellationToken>>,
+    rounds: Arc<RunRounds>,
+    quiesced: AtomicBool, // no new runs while set
+    connection: RwLock<Option<Connection>>,
+    cancel_token: RwLock<CancellationToken>, // replaced when closed
+    reconnect: RwLock<Option<CancellationToken>>,
+    connect_lock: tokio::sync::Mutex<()>, // one connection attempt at a time
+    connect_generation: AtomicU64,        // bumped by every connect_assistant
+    wake: RwLock<CancellationToken>,
+    // set by the shutdown on exit only
+    exiting: AtomicBool,
+    exited: AtomicBool,
+    exited_notify: Notify,
+}
+
+/// What the running engine was built from.
+#[derive(Clone)]
+struct Connection {
+    principal: Principal,
+    https_proxy: Option<String>,
+    cfg: AssistantConfig,
+    controller: Principal,
+    web3: Arc<Web3SDK>,
+    http_client: reqwest::Client,
+    assistant: Assistant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConfigChange {
+    None,
+    /// Only the model providers changed, the model can be swapped.
+    Model,
+    /// The identity, the proxy or the root secret changed.
+    Full,
+}
+
+impl Connection {
+    fn diff(
+        &self,
+        principal: Principal,
+        https_proxy: &Option<String>,
+        cfg: &AssistantConfig,
+    ) -> ConfigChange {
+        if self.principal != principal
+            || &self.https_proxy != https_proxy
+            || self.cfg.root_secret.as_slice() != cfg.root_secret.as_slice()
+        {
+            ConfigChange::Full
+        } else if self.cfg.preferred_provider != cfg.preferred_provider
+            || self.cfg.fallback_providers != cfg.fallback_providers
+            || self.cfg.providers != cfg.providers
+        {
+            ConfigChange::Model
+        } else {
+            ConfigChange::None
+        }
+    }
 }
 
 impl<R: Runtime> AndaAssistant<R> {
@@ -84,26 +179,52 @@ impl<R: Runtime> AndaAssistant<R> {
                         dir,
                         db: RwLock::new(None),
                         assistant: RwLock::new(None),
+                        usage: RwLock::new(None),
+                        history: RwLock::new(None),
+                        search: RwLock::new(None),
+                        status: RwLock::new(AssistantStatus::Disconnected),
                         engine: ArcSwap::new(Arc::new(InnerAssistant::builder().empty())),
-                        should_restart: Arc::new(AtomicU64::new(0)),
-                        cancel_token: CancellationToken::new(),
+                        runs: RwLock::new(BTreeMap::new()),
+                        rounds: Arc::new(RunRounds::default()),
+                        quiesced: AtomicBool::new(false),
+                        connection: RwLock::new(None),
+                        cancel_token: RwLock::new(CancellationToken::new()),
+                        reconnect: RwLock::new(None),
+                        connect_lock: tokio::sync::Mutex::new(()),
+                        connect_generation: AtomicU64::new(0),
+                        wake: RwLock::new(CancellationToken::new()),
+                        exiting: AtomicBool::new(false),
+                        exited: AtomicBool::new(false),
+                        exited_notify: Notify::new(),
                     }),
                 });
 
                 Ok(())
             })
-            .on_event(|_app, event| {
-                match event {
-                    tauri::RunEvent::ExitRequested { .. } => {
-                        // app.assistant().flush();
-                        // TODO:: Cannot start a runtime from within a runtime. This happens because a function (like `block_on`) attempted to block the current thread while the thread is being used to drive asynchronous tasks.
-                    }
-                    tauri::RunEvent::Exit => {
-                        // app.assistant().close();
-                        // TODO: Cannot start a runtime from within a runtime. This happens because a function (like `block_on`) attempted to block the current thread while the thread is being used to drive asynchronous tasks.
+            .on_event(|app, event| match event {
+                tauri::RunEvent::ExitRequested { code, api, .. } => {
+                    // `None` when the last window closed, the app exits as
+                    // well then and must be shut down the same way
+                    let code = code.unwrap_or(0);
+                    let inner = &app.assistant().inner;
+                    if inner.exited.load(Ordering::SeqCst) {
+                        return;
                     }
-                    _ => {}
+
+                    // holds the exit until the database is closed and the
+                    // state is saved, then exits again
+                    api.prevent_exit();
+                    let app = app.clone();
+                    async_runtime::spawn(async move {
+                        app.assistant().shutdown().await;
+                        app.exit(code);
+                    });
+                }
+                tauri::RunEvent::Exit if !app.assistant().inner.exited.load(Ordering::SeqCst) => {
+                    log::warn!("Application exits before the assistant was shut down");
+                    save_state(app);
                 }
+                _ => {}
             })
             .build()
     }
@@ -132,10 +253,287 @@ impl<R: Runtime> AndaAssistant<R> {
         })
     }
 
+    /// The directory of the object store.
+    pub fn dir(&self) -> &Path {
+        &self.inner.dir
+    }
+
+    pub fn status(&self) -> AssistantStatus {
+        self.inner.status.read().clone()
+    }
+
+    /// Updates the status and notifies the frontend when it changed.
+    pub fn set_status(&self, status: AssistantStatus) {
+        {
+            let mut current = self.inner.status.write();
+            if *current == status {
+                return;
+            }
+            *current = status.clone();
+        }
+
+        log::info!("Assistant status: {:?}", status);
+        let _ = self.app.emit(STATUS_EVENT, &status);
+        match status {
+            AssistantStatus::Ready { .. } => {
+                let _ = self.app.emit(ASSISTANT_EVENT, true);
+            }
+            AssistantStatus::NoProvider | AssistantStatus::Failed { .. } => {
+                let _ = self.app.emit(ASSISTANT_EVENT, false);
+            }
+            _ => {}
+        }
+    }
+
+    /// Wakes a waiting reconnect loop to retry immediately, e.g. when the
+    /// network is back.
+    pub fn network_restored(&self) {
+        let wake = std::mem::replace(&mut *self.inner.wake.write(), CancellationToken::new());
+        wake.cancel();
+    }
+
     pub fn engine(&self) -> Arc<Engine> {
         self.inner.engine.load().clone()
     }
 
+    /// Registers a new run with a cancellation token derived from the
+    /// assistant's token. A random run ID is generated if none is given.
+    pub fn start_run(
+        &self,
+        run_id: Option<String>,
+        events: Option<Channel<RunEvent>>,
+    ) -> Result<Arc<RunContext>, BoxError> {
+        let run_id = run_id.unwrap_or_else(|| hex::encode(rand_bytes::<12>()));
+        let mut runs = self.inner.runs.write();
+        if self.inner.quiesced.load(Ordering::SeqCst) {
+            return Err("The database is under maintenance, try again later".into());
+        }
+        if runs.contains_key(&run_id) {
+            return Err(format!("Run {run_id} already exists").into());
+        }
+
+        let ctx = RunContext::new(run_id, self.inner.cancel_token().child_token(), events);
+        runs.insert(ctx.run_id().to_string(), ctx.cancel_token());
+        Ok(ctx)
+    }
+
+    /// Lets the completion rounds of an agent run with the prompt find the
+    /// context of the run.
+    pub fn expect_rounds(&self, ctx: &Arc<RunContext>, prompt: &str) {
+        self.inner.rounds.expect(ctx, prompt);
+    }
+
+    pub fn end_run(&self, run_id: &str) {
+        self.inner.runs.write().remove(run_id);
+    }
+
+    pub fn cancel_run(&self, run_id: &str) -> bool {
+        match self.inner.runs.write().remove(run_id) {
+            Some(cancel_token) => {
+                cancel_token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn db(&self) -> Option<Arc<AndaDB>> {
+        self.inner.db.read().clone()
+    }
+
+    pub fn usage_ledger(&self) -> Option<Arc<UsageLedger>> {
+        self.inner.usage.read().clone()
+    }
+
+    pub fn history(&self) -> Option<Arc<ConversationHistory>> {
+        self.inner.history.read().clone()
+    }
+
+    pub fn search_index(&self) -> Option<Arc<ConversationIndex>> {
+        self.inner.search.read().clone()
+    }
+
+    pub fn memory(&self) -> Option<Arc<MemoryManagement>> {
+        self.inner
+            .assistant
+            .read()
+            .as_ref()
+            .map(|assistant| assistant.memory())
+    }
+
+    /// Returns the budget status of every configured provider budget.
+    pub async fn budget_statuses(&self) -> Result<Vec<BudgetStatus>, BoxError> {
+        let budgets = self.app.state::<SecretStateCell>().with(|state| {
+            state
+                .assistant
+                .as_ref()
+                .map(|cfg| cfg.budgets.clone())
+                .unwrap_or_default()
+        });
+        let ledger = match self.usage_ledger() {
+            Some(ledger) => ledger,
+            None => return Ok(Vec::new()),
+        };
+
+        let now_ms = unix_ms();
+        let mut statuses = Vec::with_capacity(budgets.len());
+        for (name, budget) in &budgets {
+            statuses.push(ledger.budget_status(name, budget, now_ms).await?);
+        }
+        Ok(statuses)
+    }
+
+    /// Loads the budget status of the run's providers into the run context.
+    /// Fails when every configured provider has reached its hard limit.
+    pub async fn check_budgets(&self, ctx: &RunContext) -> Result<(), BoxError> {
+        let (providers, budgets) =
+            self.app
+                .state::<SecretStateCell>()
+                .with(|state| match state.assistant.as_ref() {
+                    Some(cfg) => (
+                        cfg.get_providers()
+                            .into_iter()
+                            .map(|(name, _)| name.to_string())
+                            .collect::<Vec<_>>(),
+                        cfg.budgets.clone(),
+                    ),
+                    None => (Vec::new(), BTreeMap::new()),
+                });
+        let ledger = match self.usage_ledger() {
+            Some(ledger) if !budgets.is_empty() => ledger,
+            _ => return Ok(()),
+        };
+
+        let now_ms = unix_ms();
+        let mut statuses = BTreeMap::new();
+        for name in &providers {
+            if let Some(budget) = budgets.get(name) {
+                let status = ledger.budget_status(name, budget, now_ms).await?;
+                statuses.insert(name.clone(), status);
+            }
+        }
+
+        ctx.set_budgets(providers, statuses);
+        let exhausted = ctx.exhausted_budget();
+        if let Some(status) = exhausted {
+            log::warn!(
+                "Run {} refused: token budget of {} exceeded",
+                ctx.run_id(),
+                status.provider
+            );
+            return Err(RunError::BudgetExceeded(status).into());
+        }
+        Ok(())
+    }
+
+    /// Follows an agent run to its end. The assistant works on the
+    /// conversation of a run on a task of its own, so the run ends once the
+    /// conversation is finished. Then the usage of the run is recorded and
+    /// the conversation is indexed for search.
+    pub async fn follow_run(
+        &self,
+        ctx: &RunContext,
+        output: AgentOutput,
+    ) -> Result<AgentOutput, BoxError> {
+        let (id, db) = match (output.conversation, self.db()) {
+            (Some(id), Some(db)) => (id, db),
+            _ => {
+                self.end_run(ctx.run_id());
+                self.record_usage(ctx, output.conversation);
+                return Ok(output);
+            }
+        };
+
+        let res = self
+            .inner
+            .cancel_token()
+            .run_until_cancelled(wait_finished(&db, id))
+            .await;
+        self.end_run(ctx.run_id());
+        self.record_usage(ctx, Some(id));
+        let conversation = match res {
+            Some(conversation) => conversation?,
+            None => return Err("The assistant was closed".into()),
+        };
+
+        if let Some(index) = self.search_index()
+            && let Err(err) = index.update(&json!(conversation)).await
+        {
+            log::error!("Failed to index conversation {}: {}", id, err);
+        }
+        if ctx.cancel_token().is_cancelled() {
+            return Err(RunError::Cancelled {
+                run_id: ctx.run_id().to_string(),
+            }
+            .into());
+        }
+
+        let content = conversation
+            .messages
+            .last()
+            .filter(|msg| msg["role"] == "assistant")
+            .map(message_text)
+            .unwrap_or_default();
+        Ok(AgentOutput {
+            content,
+            failed_reason: conversation.failed_reason,
+            usage: conversation.usage,
+            conversation: Some(id),
+            ..Default::default()
+        })
+    }
+
+    /// Writes the token usage of a finished run to the usage ledger and warns
+    /// the frontend when the run crossed a budget limit.
+    pub fn record_usage(&self, ctx: &RunContext, conversation: Option<u64>) {
+        let usage = ctx.take_usage();
+        let budgets = ctx.budgets();
+        for (name, status) in budgets {
+            let tokens: u64 = usage
+                .iter()
+                .filter(|((provider, _), _)| provider == &name)
+                .map(|(_, u)| u.input_tokens + u.output_tokens)
+                .sum();
+            if status.crosses_limit(tokens) {
+                let status = BudgetStatus {
+                    used_tokens: status.used_tokens + tokens,
+                    ..status
+                };
+                log::warn!(
+                    "Token budget limit of {} reached: {} tokens used",
+                    status.provider,
+                    status.used_tokens
+                );
+                let _ = self.app.emit(BUDGET_EVENT, status);
+            }
+        }
+
+        let ledger = self.inner.usage.read().clone();
+        let ledger = match ledger {
+            Some(ledger) if !usage.is_empty() => ledger,
+            _ => return,
+        };
+
+        let timestamp = unix_ms();
+        async_runtime::spawn(async move {
+            for ((provider, model), usage) in usage {
+                let record = UsageRecord {
+                    timestamp,
+                    provider,
+                    model,
+                    conversation,
+                    input_tokens: usage.input_tokens,
+                    output_tokens: usage.output_tokens,
+                    requests: usage.requests,
+                    ..Default::default()
+                };
+                if let Err(err) = ledger.record(record).await {
+                    log::error!("Failed to record usage: {err}");
+                }
+            }
+        });
+    }
+
     pub fn flush(&self) {
         let db = self.inner.db.read().clone();
         if let Some(db) = db {
@@ -148,10 +546,112 @@ impl<R: Runtime> AndaAssistant<R> {
         }
     }
 
-    pub async fn close(&self) {
-        self.inner.cancel_token.cancel();
-        let engine = self.inner.engine.load().clone();
+    /// Flushes the database and waits for it to finish.
+    pub async fn flush_now(&self) -> Result<(), BoxError> {
         let db = self.inner.db.read().clone();
+        if let Some(db) = db {
+            db.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Flushes and closes the database, then saves the state cells, before
+    /// the app exits. Waits at most `SHUTDOWN_TIMEOUT` for the database. Runs
+    /// once, concurrent calls wait for the first one to finish, and exit
+    /// requests pass through afterwards. Use `close` to reconnect later.
+    pub async fn shutdown(&self) {
+        if self.inner.exiting.swap(true, Ordering::SeqCst) {
+            // registered before the check, so that the notification is not
+            // missed in between
+            let notified = self.inner.exited_notify.notified();
+            if !self.inner.exited.load(Ordering::SeqCst) {
+                let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, notified).await;
+            }
+            return;
+        }
+
+        log::info!("Shutting down Anda Assistant");
+        let res = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+            if let Err(err) = self.flush_now().await {
+                log::error!("Failed to flush Anda Assistant: {}", err);
+            }
+            self.close().await;
+        })
+        .await;
+        if res.is_err() {
+            log::error!(
+                "Timed out closing Anda Assistant after {:?}",
+                SHUTDOWN_TIMEOUT
+            );
+        }
+        save_state(&self.app);
+        self.inner.exited.store(true, Ordering::SeqCst);
+        self.inner.exited_notify.notify_waiters();
+    }
+
+    /// Flushes the database and keeps it read-only while `fut` runs, so that
+    /// the files of the object store do not change under it. Writes of
+    /// running agents fail meanwhile.
+    pub async fn with_read_only<F, T>(&self, fut: F) -> Result<T, BoxError>
+    where
+        F: Future<Output = Result<T, BoxError>>,
+    {
+        let db = self.inner.db.read().clone();
+        if let Some(db) = &db {
+            db.flush().await?;
+            db.set_read_only(true);
+        }
+        let res = fut.await;
+        if let Some(db) = &db {
+            db.set_read_only(false);
+        }
+        res
+    }
+
+    /// Cancels the running agents and refuses new runs while `fut` runs, so
+    /// that only `fut` writes to the database. The database is flushed
+    /// before and after.
+    pub async fn quiesce<F, T, E>(&self, fut: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: From<BoxError>,
+    {
+        if self.inner.quiesced.swap(true, Ordering::SeqCst) {
+            return Err(E::from("The database is already under maintenance".into()));
+        }
+        let runs = std::mem::take(&mut *self.inner.runs.write());
+        for cancel_token in runs.into_values() {
+            cancel_token.cancel();
+        }
+        let res = async {
+            self.flush_now().await?;
+            let rt = fut.await?;
+            self.flush_now().await?;
+            Ok(rt)
+        }
+        .await;
+        self.inner.quiesced.store(false, Ordering::SeqCst);
+        res
+    }
+
+    /// Cancels the runs and the background tasks, closes the engine and the
+    /// database and drops the connection, so that the assistant can connect
+    /// again, e.g. with the data of a restored backup.
+    pub async fn close(&self) {
+        let cancel_token = std::mem::take(&mut *self.inner.cancel_token.write());
+        cancel_token.cancel();
+        // waits for a connection attempt to stop
+        let _guard = self.inner.connect_lock.lock().await;
+        let engine = self
+            .inner
+            .engine
+            .swap(Arc::new(InnerAssistant::builder().empty()));
+        let db = self.inner.db.write().take();
+        self.inner.assistant.write().take();
+        self.inner.usage.write().take();
+        self.inner.history.write().take();
+        self.inner.search.write().take();
+        self.inner.connection.write().take();
         match try_join!(engine.close(), async {
             if let Some(db) = db {
                 db.close().await?;
@@ -161,10 +661,36 @@ impl<R: Runtime> AndaAssistant<R> {
             Ok(_) => log::info!("Anda Assistant closed successfully"),
             Err(e) => log::error!("Failed to close Anda Assistant: {}", e),
         }
+        self.set_status(AssistantStatus::Disconnected);
+    }
+}
+
+/// Exponential backoff: 2s, 4s, 8s... up to `RECONNECT_MAX_DELAY`.
+fn reconnect_delay(attempt: u32) -> Duration {
+    RECONNECT_MIN_DELAY
+        .saturating_mul(1u32 << attempt.saturating_sub(1).min(16))
+        .min(RECONNECT_MAX_DELAY)
+}
+
+/// Saves the app state and the secret state to disk.
+fn save_state<R: Runtime>(app: &AppHandle<R>) {
+    if let Some(state) = app.try_state::<AppStateCell>()
+        && let Err(err) = state.save()
+    {
+        log::error!("Failed to save app state: {}", err);
+    }
+    if let Some(state) = app.try_state::<SecretStateCell>()
+        && let Err(err) = state.save()
+    {
+        log::error!("Failed to save secret state: {}", err);
     }
 }
 
 impl InnerAssistant {
+    fn cancel_token(&self) -> CancellationToken {
+        self.cancel_token.read().clone()
+    }
+
     fn builder() -> EngineBuilder {
         EngineBuilder::new().with_info(AgentInfo {
             handle: "assistant".to_string(),
@@ -178,18 +704,28 @@ impl InnerAssistant {
         })
     }
 
+    /// Connects the Web3 client, opens the database on the first connection
+    /// and builds the engine. Errors are worth a retry. An invalid proxy or
+    /// model provider config is reported as a `Failed` status without a
+    /// retry instead, it needs a settings change.
     async fn connect(
         &self,
         identity: Arc<AtomicIdentity>,
         agent: Agent,
         cfg: AssistantConfig,
         https_proxy: Option<String>,
-    ) -> Result<bool, BoxError> {
-        let mut http_client = request_client_builder();
-        if let Some(proxy) = https_proxy {
-            http_client = http_client.proxy(Proxy::all(proxy)?);
-        }
-        let http_client = http_client.build()?;
+    ) -> Result<AssistantStatus, BoxError> {
+        let principal = identity.sender()?;
+        let http_client = match provider::http_client(https_proxy.clone()) {
+            Ok(http_client) => http_client,
+            Err(err) => {
+                log::error!("Invalid https proxy {:?}: {}", https_proxy, err);
+                return Ok(AssistantStatus::Failed {
+                    reason: err.to_string(),
+                    retry_at: None,
+                });
+            }
+        };
 
         let web3 = Web3Client::builder()
             .with_ic_host(ICP_HOST)
@@ -209,67 +745,135 @@ impl InnerAssistant {
         );
 
         let db = self.db.read().clone();
-        let db = if let Some(db) = db {
-            db
-        } else {
-            let os_secret = web3
-                .a256gcm_key(derivation_path_with(
-                    &DBPath::from(SYSTEM_PATH),
-                    vec![b"object_store".to_vec(), b"A256GCM".to_vec()],
-                ))
-                .await?;
-
-            let lock = sha3_256(&os_secret);
-            let object_store = LocalFileSystem::new_with_prefix(&self.dir)?;
-            let object_store = MetaStoreBuilder::new(object_store, 10000).build();
-            let object_store = Arc::new(object_store);
-
-            let db_config = DBConfig {
-                name: "anda_db".to_string(),
-                description: "Anda DB".to_string(),
-                storage: StorageConfig {
-                    cache_max_capacity: 10000,
-                    compress_level: 3,
-                    object_chunk_size: 256 * 1024,
-                    bucket_overload_size: 1024 * 1024,
-                    max_small_object_size: 1024 * 1024 * 10,
-                },
-                lock: Some(ByteBufB64(lock.into())),
-            };
-
-            let db = AndaDB::connect(object_store.clone(), db_config).await?;
-
-            let db = Arc::new(db);
-            *self.db.write() = Some(db.clone());
-
-            let db_ = db.clone();
-            let cancel_token = self.cancel_token.child_token();
-            tokio::spawn(async move {
-                db_.auto_flush(cancel_token, Duration::from_millis(60 * 1000))
-                    .await;
-            });
-
-            db
+        let db = match db {
+            Some(db) => db,
+            None => self.open_db(&web3).await?,
         };
 
         let web3 = Arc::new(Web3SDK::from_web3(web3));
-        let object_store = db.object_store().clone();
         let assistant = Assistant::connect(db.clone(), None)
             .await?
-            .with_system_instructions(SYSTEM_INSTRUCTIONS)
-            .with_max_input_tokens(cfg.get_max_input_tokens());
-        let memory_tool = MemoryTool::new(assistant.memory());
+            .with_system_instructions(SYSTEM_INSTRUCTIONS);
 
         {
             *self.assistant.write() = Some(Arc::new(assistant.clone()));
         }
 
+        let conn = Connection {
+            principal,
+            https_proxy,
+            cfg,
+            controller: my_principal,
+            web3,
+            http_client,
+            assistant,
+        };
+        let status = self.build_engine(&db, &conn).await?;
+        *self.connection.write() = Some(conn);
+        Ok(status)
+    }
+
+    /// Opens the database with its collections and starts the background
+    /// tasks. Nothing is kept if a step fails, so that the next attempt
+    /// starts over.
+    async fn open_db(&self, web3: &Web3Client) -> Result<Arc<AndaDB>, BoxError> {
+        let os_secret = web3
+            .a256gcm_key(derivation_path_with(
+                &DBPath::from(SYSTEM_PATH),
+                vec![b"object_store".to_vec(), b"A256GCM".to_vec()],
+            ))
+            .await?;
+
+        let lock = sha3_256(&os_secret);
+        let object_store = open_object_store(&self.dir, os_secret).await?;
+
+        let db_config = DBConfig {
+            name: "anda_db".to_string(),
+            description: "Anda DB".to_string(),
+            storage: storage_config(),
+            lock: Some(ByteBufB64(lock.into())),
+        };
+
+        let db = Arc::new(AndaDB::connect(object_store.clone(), db_config).await?);
+        let res = async {
+            let usage = UsageLedger::connect(&db).await?;
+            let history = ConversationHistory::connect(&db).await?;
+            let search = ConversationIndex::connect(&db, &self.dir).await?;
+            Ok::<_, BoxError>((usage, history, search))
+        }
+        .await;
+        let (usage, history, search) = match res {
+            Ok(rt) => rt,
+            Err(err) => {
+                if let Err(err) = db.close().await {
+                    log::error!("Failed to close the database: {err}");
+                }
+                return Err(err);
+            }
+        };
+
+        let search = Arc::new(search);
+        *self.db.write() = Some(db.clone());
+        *self.usage.write() = Some(Arc::new(usage));
+        *self.history.write() = Some(Arc::new(history));
+        *self.search.write() = Some(search.clone());
+
+        let db_ = db.clone();
+        let cancel_token = self.cancel_token().child_token();
+        tokio::spawn(async move {
+            if let Some(Err(err)) = cancel_token
+                .run_until_cancelled(search.backfill(&db_))
+                .await
+            {
+                log::error!("Failed to backfill the conversation index: {err}");
+            }
+        });
+
+        let db_ = db.clone();
+        let cancel_token = self.cancel_token().child_token();
+        tokio::spawn(async move {
+            db_.auto_flush(cancel_token, Duration::from_millis(60 * 1000))
+                .await;
+        });
+
+        Ok(db)
+    }
+
+    /// Builds a new engine with the model providers of the current config
+    /// and swaps it in for the runs that start from now on. Runs that already
+    /// started keep the engine, and so the providers, they started with.
+    async fn swap_model(&self, cfg: AssistantConfig) -> Result<AssistantStatus, BoxError> {
+        let _guard = self.connect_lock.lock().await;
+        let db = self.db.read().clone();
+        let conn = self.connection.read().clone();
+        let (db, mut conn) = match (db, conn) {
+            (Some(db), Some(conn)) => (db, conn),
+            _ => return Err("Assistant is not connected".into()),
+        };
+
+        conn.cfg = cfg;
+        let status = self.build_engine(&db, &conn).await?;
+        *self.connection.write() = Some(conn);
+        Ok(status)
+    }
+
+    async fn build_engine(
+        &self,
+        db: &AndaDB,
+        conn: &Connection,
+    ) -> Result<AssistantStatus, BoxError> {
+        let assistant = conn
+            .assistant
+            .clone()
+            .with_max_input_tokens(conn.cfg.get_max_input_tokens());
+        let memory_tool = MemoryTool::new(assistant.memory());
+
         // Build agent engine with all configured components
         let engine = Self::builder()
-            .with_web3_client(web3)
-            .with_store(Store::new(object_store))
+            .with_web3_client(conn.web3.clone())
+            .with_store(Store::new(db.object_store().clone()))
             .with_management(Arc::new(BaseManagement {
-                controller: my_principal,
+                controller: conn.controller,
                 managers: BTreeSet::new(),
                 visibility: Visibility::Private,
             }))
@@ -278,54 +882,96 @@ impl InnerAssistant {
             .register_agent(assistant)?
             .export_tools(vec![MemoryTool::NAME.to_string()]);
 
-        if let Some((name, provider)) = cfg.get_provider() {
-            let model = match name {
-                "gemini" => Model::with_completer(Arc::new(
-                    gemini::Client::new(&provider.api_key, provider.api_base.clone())
-                        .with_client(http_client)
-                        .completion_model(&provider.model),
-                )),
-                "deepseek" => Model::with_completer(Arc::new(
-                    deepseek::Client::new(&provider.api_key, provider.api_base.clone())
-                        .with_client(http_client)
-                        .completion_model(&provider.model),
-                )),
-                "xai" => Model::with_completer(Arc::new(
-                    xai::Client::new(&provider.api_key, provider.api_base.clone())
-                        .with_client(http_client)
-                        .completion_model(&provider.model),
-                )),
-                "openai" => Model::with_completer(Arc::new(
-                    openai::Client::new(&provider.api_key, provider.api_base.clone())
-                        .with_client(http_client)
-                        .completion_model(&provider.model),
-                )),
-                _ => return Err(format!("Unknown model provider: {}", name).into()),
-            };
-
-            let engine = engine
-                .with_model(model)
-                .build(Assistant::NAME.to_string())
-                .await?;
-            self.engine.store(Arc::new(engine));
-            log::info!(
-                "Connected to {} model provider with model: {}",
-                name,
-                provider.model
-            );
-            Ok(true)
-        } else {
-            self.engine.store(Arc::new(engine.empty()));
+        if conn.cfg.get_providers().is_empty() {
+            self.clear_model();
             log::error!("LLM API key is missing");
-            Ok(false)
+            return Ok(AssistantStatus::NoProvider);
+        }
+
+        let completers = match build_completers(&conn.cfg, &conn.http_client) {
+            Ok(completers) => completers,
+            Err(status) => {
+                self.clear_model();
+                return Ok(status);
+            }
+        };
+        let fallback = Arc::new(FallbackCompleter::new(completers));
+        let model = Model::with_completer(Arc::new(ObservedCompleter::new(
+            fallback,
+            self.rounds.clone(),
+        )));
+
+        let engine = engine
+            .with_model(model)
+            .build(Assistant::NAME.to_string())
+            .await?;
+        self.engine.store(Arc::new(engine));
+        Ok(Self::ready(&conn.cfg))
+    }
+
+    /// Replaces the engine with one without a model, so that runs fail until
+    /// a model provider is configured.
+    fn clear_model(&self) {
+        self.engine.store(Arc::new(Self::builder().empty()));
+    }
+
+    fn ready(cfg: &AssistantConfig) -> AssistantStatus {
+        let providers = cfg.get_providers();
+        for (i, (name, provider)) in providers.iter().enumerate() {
+            if i == 0 {
+                log::info!(
+                    "Connected to {} model provider with model: {}",
+                    name,
+                    provider.model
+                );
+            } else {
+                log::info!(
+                    "Fallback #{} to {} model provider with model: {}",
+                    i,
+                    name,
+                    provider.model
+                );
+            }
+        }
+        let (provider, cfg) = providers[0];
+        AssistantStatus::Ready {
+            provider: provider.to_string(),
+            model: cfg.model.clone(),
+        }
+    }
+}
+
+/// Builds the completion models of the configured providers, in fallback
+/// order. An invalid provider config is returned as a `Failed` status, a
+/// retry does not fix it.
+fn build_completers(
+    cfg: &AssistantConfig,
+    http_client: &reqwest::Client,
+) -> Result<Vec<ProviderCompleter>, AssistantStatus> {
+    let providers = cfg.get_providers();
+    let mut completers = Vec::with_capacity(providers.len());
+    for (name, provider) in &providers {
+        match build_completer(name, provider, http_client.clone()) {
+            Ok(completer) => completers.push(ProviderCompleter {
+                name: name.to_string(),
+                model: provider.model.clone(),
+                completer,
+            }),
+            Err(err) => {
+                log::error!("Invalid config of model provider {}: {}", name, err);
+                return Err(AssistantStatus::Failed {
+                    reason: err.to_string(),
+                    retry_at: None,
+                });
+            }
         }
     }
+    Ok(completers)
 }
 
 pub trait AndaAssistantExt<R: Runtime> {
     fn assistant(&self) -> &AndaAssistant<R>;
     fn connect_assistant(&self);
-    fn propose_reconnect_assistant(&self);
     fn try_reconnect_assistant(&self);
     fn save_assistant(&self);
 }
@@ -336,39 +982,127 @@ impl<R: Runtime, T: Manager<R>> AndaAssistantExt<R> for T {
     }
 
     fn connect_assistant(&self) {
-        let cfg = self
-            .state::<SecretStateCell>()
-            .with(|state| state.assistant.clone().unwrap());
-        let proxy = self
-            .state::<AppStateCell>()
-            .with(|state| state.settings.https_proxy.clone());
-        let assistant = self.assistant().inner.clone();
-        let identity = self.icp().identity();
-        let agent = self.icp().agent().clone();
-
         let app = self.app_handle().clone();
+        let inner = self.assistant().inner.clone();
+        // a new connection supersedes a pending reconnect loop
+        let generation = inner.connect_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let cancel_token = inner.cancel_token().child_token();
+        if let Some(prev) = inner.reconnect.write().replace(cancel_token.clone()) {
+            prev.cancel();
+        }
+
         async_runtime::spawn(async move {
-            match assistant.connect(identity, agent, cfg, proxy).await {
-                Ok(is_ready) => {
-                    let _ = app.emit(ASSISTANT_EVENT, is_ready);
+            let mut attempt: u32 = 0;
+            loop {
+                attempt += 1;
+                // waits for the attempt of a superseded loop to stop, it
+                // runs to the end if it has no await point left
+                let Some(guard) = cancel_token
+                    .run_until_cancelled(inner.connect_lock.lock())
+                    .await
+                else {
+                    log::info!(
+                        "Assistant connection superseded after {} attempts",
+                        attempt - 1
+                    );
+                    return;
+                };
+
+                // settings are read on every attempt, they may change meanwhile
+                let cfg = app
+                    .state::<SecretStateCell>()
+                    .with(|state| state.assistant.clone().unwrap());
+                let proxy = app
+                    .state::<AppStateCell>()
+                    .with(|state| state.settings.https_proxy.clone());
+                let identity = app.icp().identity();
+                let agent = app.icp().agent().clone();
+
+                app.assistant().set_status(AssistantStatus::Connecting);
+                let res = cancel_token
+                    .run_until_cancelled(inner.connect(identity, agent, cfg, proxy))
+                    .await;
+                if inner.connect_generation.load(Ordering::SeqCst) != generation {
+                    log::info!("Assistant connection superseded after {} attempts", attempt);
+                    return;
+                }
+                match res {
+                    None => {
+                        log::info!("Assistant connection cancelled after {} attempts", attempt);
+                        return;
+                    }
+                    Some(Ok(status)) => {
+                        if attempt > 1 {
+                            log::info!("Assistant connected after {} attempts", attempt);
+                        }
+                        app.assistant().set_status(status);
+                        return;
+                    }
+                    Some(Err(err)) => {
+                        let delay = reconnect_delay(attempt);
+                        log::error!(
+                            "Failed to connect assistant (attempt {}), retrying in {:?}: {}",
+                            attempt,
+                            delay,
+                            err
+                        );
+                        app.assistant().set_status(AssistantStatus::Failed {
+                            reason: err.to_string(),
+                            retry_at: Some(unix_ms() + delay.as_millis() as u64),
+                        });
+                    }
                 }
-                Err(err) => {
-                    log::error!("Failed to connect assistant: {err}");
+                drop(guard);
+
+                // waits for the delay or a network-restored signal
+                let wake = inner.wake.read().clone();
+                let wait = wake.run_until_cancelled(tokio::time::sleep(reconnect_delay(attempt)));
+                if cancel_token.run_until_cancelled(wait).await.is_none() {
+                    log::info!("Assistant reconnect cancelled after {} attempts", attempt);
+                    return;
                 }
-            };
+            }
         });
     }
 
-    fn propose_reconnect_assistant(&self) {
-        let assistant = self.assistant().inner.clone();
-        assistant.should_restart.fetch_add(1, Ordering::Relaxed);
-    }
-
     fn try_reconnect_assistant(&self) {
-        let assistant = self.assistant().inner.clone();
-        let should_restart = assistant.should_restart.swap(0, Ordering::Relaxed);
-        if should_restart > 0 {
-            self.connect_assistant();
+        let cfg = self
+            .state::<SecretStateCell>()
+            .with(|state| state.assistant.clone().unwrap());
+        let proxy = self
+            .state::<AppStateCell>()
+            .with(|state| state.settings.https_proxy.clone());
+        let principal = self.icp().identity().sender().ok();
+
+        let inner = self.assistant().inner.clone();
+        let change = match (inner.connection.read().as_ref(), principal) {
+            (Some(conn), Some(principal)) => conn.diff(principal, &proxy, &cfg),
+            // the pending connection reads the settings when it starts
+            (None, _) if self.assistant().status() == AssistantStatus::Connecting => {
+                ConfigChange::None
+            }
+            _ => ConfigChange::Full,
+        };
+
+        match change {
+            ConfigChange::None => {}
+            ConfigChange::Full => {
+                log::info!("Assistant config changed, reconnecting");
+                self.connect_assistant();
+            }
+            ConfigChange::Model => {
+                log::info!("Model providers changed, swapping the model");
+                let app = self.app_handle().clone();
+                async_runtime::spawn(async move {
+                    match inner.swap_model(cfg).await {
+                        Ok(status) => app.assistant().set_status(status),
+                        Err(err) => {
+                            log::error!("Failed to swap the model, reconnecting: {err}");
+                            app.connect_assistant();
+                        }
+                    }
+                });
+            }
         }
     }
 
@@ -0,0 +1,626 @@
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{
+        Aead, Payload,
+        generic_array::GenericArray,
+        stream::{DecryptorBE32, EncryptorBE32},
+    },
+};
+use anda_core::BoxError;
+use anda_db::unix_ms;
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    APP_SALT,
+    model::app::{AppState, SecretState},
+    service::store::remove_path,
+    utils::rand_bytes,
+};
+
+const MAGIC: &[u8; 8] = b"ANDABAK\0";
+const HEADER_LEN: usize = 8 + 2 + 16 + 7; // magic, version, salt, stream nonce
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const MANIFEST: &str = "manifest.json";
+pub const BACKUP_VERSION: u16 = 1;
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+
+pub const APP_STATE_FILE: &str = "app_state.cbor";
+pub const SECRET_STATE_FILE: &str = "secret_state.cbor";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BackupManifest {
+    pub version: u16,
+    pub app_version: String,
+    pub created_at: u64, // unix timestamp in milliseconds
+    /// Files and directories of the app data directory in the backup.
+    pub entries: Vec<String>,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Packs the entries of `data_dir` into a tar archive, encrypts it with
+/// AES-256-GCM under a key derived from the passphrase with Argon2id and
+/// writes it to `path`. Files are streamed, so a backup never has to fit in
+/// memory, and a failed backup leaves no file behind.
+///
+/// Layout: `magic | version (u16 BE) | salt (16) | nonce (7) | chunks`, where
+/// the archive is encrypted in chunks of `CHUNK_SIZE` with the STREAM
+/// construction and the header is authenticated as associated data of every
+/// chunk. The manifest is the first file of the archive.
+pub fn create_backup(
+    data_dir: &Path,
+    entries: &[&str],
+    passphrase: &str,
+    app_version: &str,
+    path: &Path,
+) -> Result<BackupManifest, BoxError> {
+    check_passphrase(passphrase)?;
+
+    let mut manifest = BackupManifest {
+        version: BACKUP_VERSION,
+        app_version: app_version.to_string(),
+        created_at: unix_ms(),
+        entries: Vec::new(),
+        files: 0,
+        bytes: 0,
+    };
+    let mut files: Vec<(PathBuf, String)> = Vec::new(); // (path, name in the archive)
+    for entry in entries {
+        let path = data_dir.join(entry);
+        if !path.exists() {
+            continue;
+        }
+        manifest.entries.push(entry.to_string());
+        for file in walk_files(&path)? {
+            let name = file
+                .strip_prefix(data_dir)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            manifest.files += 1;
+            manifest.bytes += fs::metadata(&file)?.len();
+            files.push((file, name));
+        }
+    }
+
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let res = (|| -> Result<(), BoxError> {
+        let file = fs::File::create(&partial)
+            .map_err(|e| format!("Failed to create {:?}: {}", partial, e))?;
+        let salt = rand_bytes::<16>();
+        let nonce = rand_bytes::<7>();
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&BACKUP_VERSION.to_be_bytes());
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&nonce);
+        let writer = EncryptWriter::new(BufWriter::new(file), cipher(passphrase, &salt)?, header)?;
+
+        let mut tar = tar::Builder::new(writer);
+        tar.mode(tar::HeaderMode::Deterministic);
+        let data = serde_json::to_vec_pretty(&manifest)?;
+        let mut entry = tar::Header::new_gnu();
+        entry.set_size(data.len() as u64);
+        entry.set_mode(0o600);
+        tar.append_data(&mut entry, MANIFEST, &data[..])?;
+        for (file, name) in &files {
+            tar.append_path_with_name(file, name)
+                .map_err(|e| format!("Failed to read {:?}: {}", file, e))?;
+        }
+        tar.into_inner()?.finish()?.into_inner()?.sync_all()?;
+        Ok(())
+    })();
+
+    match res {
+        Ok(()) => {
+            fs::rename(&partial, path)?;
+            Ok(manifest)
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&partial);
+            Err(err)
+        }
+    }
+}
+
+/// Decrypts the backup at `path` and extracts it into `staging_dir`. The
+/// restored state files are checked to decrypt with each other, so that a
+/// restore can not leave the app with unreadable secrets.
+pub fn extract_backup(
+    path: &Path,
+    passphrase: &str,
+    staging_dir: &Path,
+) -> Result<BackupManifest, BoxError> {
+    let file = fs::File::open(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+    let mut file = BufReader::new(file);
+    let mut header = [0u8; HEADER_LEN];
+    file.read_exact(&mut header)
+        .map_err(|_| "Not an Anda AI backup file")?;
+    if &header[..8] != MAGIC {
+        return Err("Not an Anda AI backup file".into());
+    }
+    let version = u16::from_be_bytes([header[8], header[9]]);
+    if version > BACKUP_VERSION {
+        return Err(format!(
+            "Backup version {} is not supported, please update the app",
+            version
+        )
+        .into());
+    }
+
+    let reader = DecryptReader::new(file, cipher(passphrase, &header[10..26])?, &header);
+    let mut tar = tar::Archive::new(reader);
+
+    if staging_dir.exists() {
+        fs::remove_dir_all(staging_dir)?;
+    }
+    fs::create_dir_all(staging_dir)?;
+    let mut manifest: Option<BackupManifest> = None;
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        match &manifest {
+            None if name == MANIFEST => {
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf)?;
+                let m: BackupManifest = serde_json::from_slice(&buf)?;
+                for required in [APP_STATE_FILE, SECRET_STATE_FILE] {
+                    if !m.entries.iter().any(|e| e == required) {
+                        return Err(format!("Backup is missing {}", required).into());
+                    }
+                }
+                manifest = Some(m);
+            }
+            None => return Err("Backup has no manifest".into()),
+            // rejects absolute paths and `..` components
+            Some(_) => {
+                if !entry.unpack_in(staging_dir)? {
+                    return Err(format!("Invalid file name in backup: {}", name).into());
+                }
+            }
+        }
+    }
+    // reads to the end, so that the last chunk is authenticated and a
+    // truncated backup is rejected
+    io::copy(&mut tar.into_inner(), &mut io::sink())?;
+
+    let manifest = manifest.ok_or("Backup has no manifest")?;
+    check_state_files(staging_dir)?;
+    Ok(manifest)
+}
+
+/// Encrypts what is written to it in chunks of `CHUNK_SIZE`. The last chunk
+/// is written by [`EncryptWriter::finish`].
+struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<Aes256Gcm>>,
+    header: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Writes the header and starts a STREAM with the nonce at its end.
+    fn new(mut inner: W, cipher: Aes256Gcm, header: Vec<u8>) -> io::Result<Self> {
+        inner.write_all(&header)?;
+        let nonce = GenericArray::from_slice(&header[HEADER_LEN - 7..]);
+        Ok(Self {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(cipher, nonce)),
+            header,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        let encryptor = self.encryptor.take().ok_or_else(encryption_error)?;
+        let chunk = encryptor
+            .encrypt_last(Payload {
+                msg: &self.buf,
+                aad: &self.header,
+            })
+            .map_err(|_| encryption_error())?;
+        self.inner.write_all(&chunk)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // a full chunk is only written when more data follows, the last
+        // chunk may be full too
+        if self.buf.len() == CHUNK_SIZE {
+            let encryptor = self.encryptor.as_mut().ok_or_else(encryption_error)?;
+            let chunk = encryptor
+                .encrypt_next(Payload {
+                    msg: &self.buf,
+                    aad: &self.header,
+                })
+                .map_err(|_| encryption_error())?;
+            self.inner.write_all(&chunk)?;
+            self.buf.clear();
+        }
+        let n = data.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts the chunks written by [`EncryptWriter`].
+struct DecryptReader<R: Read> {
+    inner: BufReader<R>,
+    decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+    header: Vec<u8>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    fn new(inner: R, cipher: Aes256Gcm, header: &[u8]) -> Self {
+        let nonce = GenericArray::from_slice(&header[HEADER_LEN - 7..]);
+        Self {
+            inner: BufReader::new(inner),
+            decryptor: Some(DecryptorBE32::from_aead(cipher, nonce)),
+            header: header.to_vec(),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0u8; CHUNK_SIZE + TAG_LEN];
+        let mut len = 0;
+        while len < chunk.len() {
+            match self.inner.read(&mut chunk[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        chunk.truncate(len);
+
+        let payload = Payload {
+            msg: &chunk,
+            aad: &self.header,
+        };
+        let is_last = len < CHUNK_SIZE + TAG_LEN || self.inner.fill_buf()?.is_empty();
+        self.buf = if is_last {
+            let decryptor = self.decryptor.take().ok_or_else(decryption_error)?;
+            decryptor.decrypt_last(payload)
+        } else {
+            let decryptor = self.decryptor.as_mut().ok_or_else(decryption_error)?;
+            decryptor.decrypt_next(payload)
+        }
+        .map_err(|_| decryption_error())?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn encryption_error() -> io::Error {
+    io::Error::other("Failed to encrypt backup")
+}
+
+fn decryption_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Failed to decrypt backup: wrong passphrase or corrupted file",
+    )
+}
+
+/// Moves the extracted entries into `data_dir`, replacing the current ones.
+/// The `known` entries that the backup does not hold are moved away as well,
+/// so that no stale file of the current data survives the restore. The
+/// current entries are put back if any move fails.
+pub fn swap_in(
+    data_dir: &Path,
+    staging_dir: &Path,
+    entries: &[String],
+    known: &[String],
+) -> Result<(), BoxError> {
+    let mut moved: Vec<(PathBuf, Option<PathBuf>)> = Vec::new(); // (target, backup)
+    let mut res: Result<(), BoxError> = Ok(());
+    let stale = known.iter().filter(|name| !entries.contains(name));
+    for entry in entries.iter().chain(stale) {
+        let target = data_dir.join(entry);
+        let backup = data_dir.join(format!("{}.before-restore", entry));
+        let step = (|| -> Result<Option<PathBuf>, BoxError> {
+            remove_path(&backup)?;
+            let backup = if target.exists() {
+                fs::rename(&target, &backup)?;
+                Some(backup)
+            } else {
+                None
+            };
+            if entries.contains(entry) {
+                fs::rename(staging_dir.join(entry), &target)?;
+            }
+            Ok(backup)
+        })();
+        match step {
+            Ok(backup) => moved.push((target, backup)),
+            Err(err) => {
+                res = Err(format!("Failed to restore {}: {}", entry, err).into());
+                break;
+            }
+        }
+    }
+
+    if res.is_err() {
+        for (target, backup) in moved.into_iter().rev() {
+            let _ = remove_path(&target);
+            if let Some(backup) = backup {
+                let _ = fs::rename(&backup, &target);
+            }
+        }
+    } else {
+        for (_, backup) in moved {
+            if let Some(backup) = backup {
+                let _ = remove_path(&backup);
+            }
+        }
+    }
+    let _ = fs::remove_dir_all(staging_dir);
+    res
+}
+
+/// Derives the key that encrypts the secret state from the app state file.
+pub fn secret_state_key(app_state_file: &Path) -> Result<[u8; 32], BoxError> {
+    let data = fs::read(app_state_file)?;
+    let state: AppState = ciborium::from_reader(&data[..])?;
+    Ok(state.derive_a256gcm_key(APP_SALT))
+}
+
+fn check_state_files(dir: &Path) -> Result<(), BoxError> {
+    let key = secret_state_key(&dir.join(APP_STATE_FILE))?;
+    let data = fs::read(dir.join(SECRET_STATE_FILE))?;
+    if data.len() < 28 {
+        return Err("Invalid secret state in backup".into());
+    }
+    let cipher = Aes256Gcm::new(&Key::<Aes256Gcm>::from(key));
+    let data = cipher
+        .decrypt(Nonce::from_slice(&data[..12]), &data[12..])
+        .map_err(|_| "Secret state in backup does not match its app state")?;
+    let _: SecretState = ciborium::from_reader(&data[..])?;
+    Ok(())
+}
+
+fn check_passphrase(passphrase: &str) -> Result<(), BoxError> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(format!(
+            "Passphrase must have at least {} characters",
+            MIN_PASSPHRASE_LEN
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm, BoxError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| format!("Failed to derive backup key: {err}"))?;
+    Ok(Aes256Gcm::new(&Key::<Aes256Gcm>::from(key)))
+}
+
+fn walk_files(path: &Path) -> Result<Vec<PathBuf>, BoxError> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = Vec::new();
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SensitiveData;
+    use ic_auth_types::ByteArrayB64;
+
+    const PASSPHRASE: &str = "correct horse battery";
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anda-backup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes matching state files and a nested store directory of more
+    /// than one encryption chunk.
+    fn write_data_dir(dir: &Path) {
+        let state = AppState {
+            seed: SensitiveData(ByteArrayB64(rand_bytes::<32>())),
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        ciborium::into_writer(&state, &mut data).unwrap();
+        fs::write(dir.join(APP_STATE_FILE), data).unwrap();
+
+        let mut data = Vec::new();
+        ciborium::into_writer(&SecretState::default(), &mut data).unwrap();
+        let key = state.derive_a256gcm_key(APP_SALT);
+        let nonce = rand_bytes::<12>();
+        let encrypted = Aes256Gcm::new(&Key::<Aes256Gcm>::from(key))
+            .encrypt(Nonce::from_slice(&nonce), data.as_ref())
+            .unwrap();
+        fs::write(
+            dir.join(SECRET_STATE_FILE),
+            [nonce.as_slice(), &encrypted].concat(),
+        )
+        .unwrap();
+
+        let store = dir.join("object_store").join("db");
+        fs::create_dir_all(&store).unwrap();
+        fs::write(store.join("large"), vec![7u8; CHUNK_SIZE * 2 + 100]).unwrap();
+        fs::write(store.join("small"), b"small").unwrap();
+    }
+
+    #[test]
+    fn round_trips_a_backup() {
+        let dir = test_dir("round-trip");
+        let data_dir = dir.join("data");
+        fs::create_dir_all(&data_dir).unwrap();
+        write_data_dir(&data_dir);
+        let path = dir.join("anda.bak");
+
+        let entries = [APP_STATE_FILE, SECRET_STATE_FILE, "object_store", "missing"];
+        let manifest = create_backup(&data_dir, &entries, PASSPHRASE, "1.0.0", &path).unwrap();
+        assert_eq!(
+            manifest.entries,
+            [APP_STATE_FILE, SECRET_STATE_FILE, "object_store"]
+        );
+        assert_eq!(manifest.files, 4);
+        assert!(!dir.join("anda.bak.partial").exists());
+
+        let staging = dir.join("staging");
+        let extracted = extract_backup(&path, PASSPHRASE, &staging).unwrap();
+        assert_eq!(extracted.entries, manifest.entries);
+        assert_eq!(extracted.bytes, manifest.bytes);
+        for file in [
+            APP_STATE_FILE,
+            SECRET_STATE_FILE,
+            "object_store/db/large",
+            "object_store/db/small",
+        ] {
+            assert_eq!(
+                fs::read(staging.join(file)).unwrap(),
+                fs::read(data_dir.join(file)).unwrap(),
+                "{}",
+                file
+            );
+        }
+
+        // a ledger of the current data that the backup does not hold
+        fs::write(data_dir.join("object_store.dropped"), b"[]").unwrap();
+        let known = [
+            APP_STATE_FILE,
+            SECRET_STATE_FILE,
+            "object_store",
+            "object_store.dropped",
+        ]
+        .map(String::from);
+        swap_in(&data_dir, &staging, &extracted.entries, &known).unwrap();
+        assert!(!staging.exists());
+        assert!(!data_dir.join("object_store.dropped").exists());
+        assert!(
+            !data_dir
+                .join("object_store.dropped.before-restore")
+                .exists()
+        );
+        assert_eq!(
+            fs::read(data_dir.join("object_store/db/small")).unwrap(),
+            b"small"
+        );
+        assert!(!data_dir.join("object_store.before-restore").exists());
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase_and_tampering() {
+        let dir = test_dir("reject");
+        let data_dir = dir.join("data");
+        fs::create_dir_all(&data_dir).unwrap();
+        write_data_dir(&data_dir);
+        let path = dir.join("anda.bak");
+        let entries = [APP_STATE_FILE, SECRET_STATE_FILE, "object_store"];
+
+        assert!(create_backup(&data_dir, &entries, "short", "1.0.0", &path).is_err());
+        assert!(!path.exists());
+
+        create_backup(&data_dir, &entries, PASSPHRASE, "1.0.0", &path).unwrap();
+        let staging = dir.join("staging");
+        let err = extract_backup(&path, "wrong passphrase", &staging).unwrap_err();
+        assert!(err.to_string().contains("wrong passphrase"), "{}", err);
+
+        // a flipped bit in the header fails every chunk
+        let mut data = fs::read(&path).unwrap();
+        data[12] ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(extract_backup(&path, PASSPHRASE, &staging).is_err());
+
+        // a truncated backup fails the last chunk
+        data[12] ^= 1;
+        data.truncate(data.len() - TAG_LEN);
+        fs::write(&path, &data).unwrap();
+        assert!(extract_backup(&path, PASSPHRASE, &staging).is_err());
+
+        fs::write(&path, b"not a backup").unwrap();
+        let err = extract_backup(&path, PASSPHRASE, &staging).unwrap_err();
+        assert_eq!(err.to_string(), "Not an Anda AI backup file");
+    }
+
+    #[test]
+    fn requires_matching_state_files() {
+        let dir = test_dir("state");
+        let data_dir = dir.join("data");
+        fs::create_dir_all(&data_dir).unwrap();
+        write_data_dir(&data_dir);
+        let path = dir.join("anda.bak");
+        let entries = [APP_STATE_FILE, SECRET_STATE_FILE];
+
+        // the secret state of another app state
+        let other = dir.join("other");
+        fs::create_dir_all(&other).unwrap();
+        write_data_dir(&other);
+        fs::copy(
+            other.join(SECRET_STATE_FILE),
+            data_dir.join(SECRET_STATE_FILE),
+        )
+        .unwrap();
+        create_backup(&data_dir, &entries, PASSPHRASE, "1.0.0", &path).unwrap();
+        let err = extract_backup(&path, PASSPHRASE, &dir.join("staging")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Secret state in backup does not match its app state"
+        );
+
+        create_backup(&data_dir, &[APP_STATE_FILE], PASSPHRASE, "1.0.0", &path).unwrap();
+        let err = extract_backup(&path, PASSPHRASE, &dir.join("staging")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Backup is missing {}", SECRET_STATE_FILE)
+        );
+    }
+}
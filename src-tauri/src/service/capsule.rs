@@ -0,0 +1,445 @@
+use anda_core::{BoxError, Json};
+use anda_db::unix_ms;
+use anda_engine::memory::MemoryManagement;
+use anda_kip::{
+    ConceptMatcher, KmlStatement, META_CONCEPT_TYPE, META_PROPOSITION_TYPE, PropositionMatcher,
+    TargetTerm, UpsertItem, parse_kml,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Map;
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::nexus::{
+    GraphFilter, KipPage, MemoryInspector, Subgraph, execute_kip, is_identifier, is_protected,
+    kip_result, meta_rank, quote,
+};
+
+/// A Knowledge Capsule: one idempotent KML `UPSERT` statement.
+#[derive(Clone, Debug)]
+pub struct Capsule {
+    pub kml: String,
+    pub concepts: usize,
+    pub propositions: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub applied: bool,
+    pub concepts_created: usize,
+    pub concepts_updated: usize,
+    pub propositions_created: usize,
+    pub propositions_updated: usize,
+    /// Schema violations and other errors that prevented the import.
+    pub violations: Vec<String>,
+}
+
+/// Exports the selected concept nodes and proposition links as a capsule.
+/// The type and predicate definitions they use are included, so that the
+/// capsule can be imported into an empty Cognitive Nexus. Protected concepts
+/// and their links are left out.
+pub async fn export_capsule(
+    memory: &MemoryManagement,
+    filter: &GraphFilter,
+) -> Result<Capsule, BoxError> {
+    let inspector = MemoryInspector::new(memory);
+    let Subgraph { concepts, links } = inspector.subgraph(filter).await?;
+    let mut concepts: BTreeMap<String, Json> = concepts
+        .into_iter()
+        .filter(|node| !is_protected(node["name"].as_str().unwrap_or_default()))
+        .filter_map(|node| {
+            node["id"]
+                .as_str()
+                .map(|id| id.to_string())
+                .map(|id| (id, node))
+        })
+        .collect();
+
+    // the definitions of the used types and predicates
+    let mut definitions: BTreeSet<(&str, String)> = BTreeSet::new();
+    for node in concepts.values() {
+        let concept_type = node["type"].as_str().unwrap_or_default();
+        definitions.insert((META_CONCEPT_TYPE, concept_type.to_string()));
+    }
+    for link in &links {
+        let predicate = link["predicate"].as_str().unwrap_or_default();
+        definitions.insert((META_PROPOSITION_TYPE, predicate.to_string()));
+    }
+    for (meta_type, name) in definitions {
+        if is_protected(&name) {
+            continue;
+        }
+        let exists = concepts.values().any(|node| {
+            node["type"].as_str() == Some(meta_type) && node["name"].as_str() == Some(&name)
+        });
+        if exists {
+            continue;
+        }
+        let page = inspector
+            .query(&format!(
+                "FIND(?node) WHERE {{ ?node {{type: {}, name: {}}} }}",
+                quote(meta_type),
+                quote(&name)
+            ))
+            .await?;
+        for node in page.items {
+            if let Some(id) = node["id"].as_str() {
+                concepts.insert(id.to_string(), node);
+            }
+        }
+    }
+
+    Ok(render_capsule(concepts.into_values().collect(), &links))
+}
+
+/// Validates a capsule with a dry run and applies it. Nothing is applied
+/// when the capsule violates the schema or touches a protected concept.
+pub async fn import_capsule(
+    memory: &MemoryManagement,
+    kml: &str,
+) -> Result<ImportReport, BoxError> {
+    let statement = kml
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with("//"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !statement.starts_with("UPSERT") {
+        return Ok(ImportReport {
+            violations: vec!["A Knowledge Capsule must be a single UPSERT statement".to_string()],
+            ..Default::default()
+        });
+    }
+
+    let inspector = MemoryInspector::new(memory);
+    let protected = ConceptRefs::from_kml(&statement)
+        .protected(&inspector)
+        .await?;
+    if !protected.is_empty() {
+        return Ok(ImportReport {
+            violations: protected
+                .into_iter()
+                .map(|name| format!("Concept {} is protected", name))
+                .collect(),
+            ..Default::default()
+        });
+    }
+
+    if let Err(err) = execute_kip(memory, kml, true, true)
+        .await
+        .and_then(kip_result)
+    {
+        return Ok(ImportReport {
+            violations: vec![err.to_string()],
+            ..Default::default()
+        });
+    }
+
+    let (concepts_before, links_before) = counts(&inspector).await?;
+    let (result, _) = kip_result(execute_kip(memory, kml, false, true).await?)?;
+    let (concepts_after, links_after) = counts(&inspector).await?;
+
+    let upserted = |key: &str| result[key].as_array().map(|v| v.len()).unwrap_or(0);
+    let concepts_created = concepts_after.saturating_sub(concepts_before);
+    let propositions_created = links_after.saturating_sub(links_before);
+    let report = ImportReport {
+        applied: true,
+        concepts_created,
+        concepts_updated: upserted("upsert_concept_nodes").saturating_sub(concepts_created),
+        propositions_created,
+        propositions_updated: upserted("upsert_proposition_links")
+            .saturating_sub(propositions_created),
+        violations: Vec::new(),
+    };
+    log::info!("Knowledge capsule imported: {:?}", report);
+    Ok(report)
+}
+
+/// The concepts that a capsule refers to, by name or by id.
+#[derive(Default)]
+struct ConceptRefs {
+    names: BTreeSet<String>,
+    ids: BTreeSet<String>,
+}
+
+impl ConceptRefs {
+    fn from_kml(kml: &str) -> Self {
+        let mut refs = Self::default();
+        // statements that do not parse are reported by the dry run
+        let Ok(KmlStatement::Upsert(blocks)) = parse_kml(kml) else {
+            return refs;
+        };
+        for item in blocks.iter().flat_map(|block| &block.items) {
+            match item {
+                UpsertItem::Concept(block) => {
+                    refs.concept(&block.concept);
+                    for prop in block.set_propositions.iter().flatten() {
+                        refs.target(&prop.object);
+                    }
+                }
+                UpsertItem::Proposition(block) => refs.proposition(&block.proposition),
+            }
+        }
+        refs
+    }
+
+    fn concept(&mut self, matcher: &ConceptMatcher) {
+        match matcher {
+            ConceptMatcher::Name(name) | ConceptMatcher::Object { name, .. } => {
+                self.names.insert(name.clone());
+            }
+            ConceptMatcher::ID(id) => {
+                self.ids.insert(id.clone());
+            }
+            ConceptMatcher::Type(_) => {}
+        }
+    }
+
+    fn target(&mut self, term: &TargetTerm) {
+        match term {
+            TargetTerm::Concept(matcher) => self.concept(matcher),
+            TargetTerm::Proposition(matcher) => self.proposition(matcher),
+            TargetTerm::Variable(_) => {}
+        }
+    }
+
+    fn proposition(&mut self, matcher: &PropositionMatcher) {
+        if let PropositionMatcher::Object {
+            subject, object, ..
+        } = matcher
+        {
+            self.target(subject);
+            self.target(object);
+        }
+    }
+
+    /// Returns the protected concepts among the references.
+    async fn protected(
+        &self,
+        inspector: &MemoryInspector<'_>,
+    ) -> Result<BTreeSet<String>, BoxError> {
+        let mut names: BTreeSet<String> = self
+            .names
+            .iter()
+            .filter(|name| is_protected(name))
+            .cloned()
+            .collect();
+        for id in &self.ids {
+            // unknown ids are reported by the dry run
+            if let Ok(node) = inspector.get_concept(id).await
+                && let Some(name) = node["name"].as_str()
+                && is_protected(name)
+            {
+                names.insert(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Counts the concept nodes and proposition links of the Cognitive Nexus.
+async fn counts(inspector: &MemoryInspector<'_>) -> Result<(usize, usize), BoxError> {
+    let count = |page: KipPage| {
+        page.items
+            .first()
+            .and_then(|v| v.as_u64().or_else(|| v[0].as_u64()))
+            .unwrap_or(0) as usize
+    };
+
+    let mut concepts = 0;
+    for name in inspector.type_names().await? {
+        let page = inspector
+            .query(&format!(
+                "FIND(COUNT(?node)) WHERE {{ ?node {{type: {}}} }}",
+                quote(&name)
+            ))
+            .await?;
+        concepts += count(page);
+    }
+    let page = inspector
+        .query("FIND(COUNT(?link)) WHERE { ?link (?subject, ?predicate, ?object) }")
+        .await?;
+    Ok((concepts, count(page)))
+}
+
+fn render_capsule(mut concepts: Vec<Json>, links: &[Json]) -> Capsule {
+    concepts.sort_by_key(|node| meta_rank(node["type"].as_str().unwrap_or_default()));
+
+    let now = DateTime::<Utc>::from_timestamp_millis(unix_ms() as i64)
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default();
+    let mut kml = String::new();
+    let mut handles: BTreeMap<&str, String> = BTreeMap::new(); // id -> handle
+    for (i, node) in concepts.iter().enumerate() {
+        let handle = format!("?c{}", i);
+        kml.push_str(&format!(
+            "    CONCEPT {} {{\n        {{type: {}, name: {}}}\n",
+            handle,
+            quote(node["type"].as_str().unwrap_or_default()),
+            quote(node["name"].as_str().unwrap_or_default())
+        ));
+        if let Some(attributes) = non_empty(&node["attributes"]) {
+            kml.push_str(&format!("        SET ATTRIBUTES {}\n", object(attributes)));
+        }
+        kml.push_str("    }");
+        if let Some(metadata) = non_empty(&node["metadata"]) {
+            kml.push_str(&format!(" WITH METADATA {}", object(metadata)));
+        }
+        kml.push_str("\n\n");
+        if let Some(id) = node["id"].as_str() {
+            handles.insert(id, handle);
+        }
+    }
+
+    let mut propositions = 0;
+    for link in links {
+        let endpoint = |key: &str| link[key].as_str().and_then(|id| handles.get(id));
+        let (subject, target) = match (endpoint("subject"), endpoint("object")) {
+            (Some(subject), Some(target)) => (subject, target),
+            // a link to a proposition or a concept outside the subgraph
+            _ => {
+                log::warn!(
+                    "Skipped proposition {} of the capsule: endpoint not exported",
+                    link["id"]
+                );
+                continue;
+            }
+        };
+        kml.push_str(&format!(
+            "    PROPOSITION ?p{} {{\n        ({}, {}, {})\n",
+            propositions,
+            subject,
+            quote(link["predicate"].as_str().unwrap_or_default()),
+            target
+        ));
+        if let Some(attributes) = non_empty(&link["attributes"]) {
+            kml.push_str(&format!("        SET ATTRIBUTES {}\n", object(attributes)));
+        }
+        kml.push_str("    }");
+        if let Some(metadata) = non_empty(&link["metadata"]) {
+            kml.push_str(&format!(" WITH METADATA {}", object(metadata)));
+        }
+        kml.push_str("\n\n");
+        propositions += 1;
+    }
+    kml.push_str("}\n");
+
+    let header = format!(
+        "// Knowledge Capsule exported by Anda AI at {}\n// {} concepts, {} propositions\nUPSERT {{\n",
+        now,
+        concepts.len(),
+        propositions
+    );
+    Capsule {
+        kml: header + &kml,
+        concepts: concepts.len(),
+        propositions,
+    }
+}
+
+fn non_empty(value: &Json) -> Option<&Map<String, Json>> {
+    value.as_object().filter(|obj| !obj.is_empty())
+}
+
+/// Renders an attributes or metadata object with bare identifier keys.
+fn object(map: &Map<String, Json>) -> String {
+    let fields: Vec<String> = map
+        .iter()
+        .map(|(key, value)| {
+            if is_identifier(key) {
+                format!("{}: {}", key, value)
+            } else {
+                format!("{}: {}", quote(key), value)
+            }
+        })
+        .collect();
+    format!("{{ {} }}", fields.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renders_definitions_first() {
+        let concepts = vec![
+            json!({
+                "id": "c3",
+                "type": "Person",
+                "name": "Ada",
+                "attributes": {"born": 1815, "full name": "Ada Lovelace"},
+                "metadata": {"source": "book"},
+            }),
+            json!({"id": "c2", "type": META_PROPOSITION_TYPE, "name": "knows", "attributes": {}}),
+            json!({"id": "c1", "type": META_CONCEPT_TYPE, "name": "Person"}),
+            json!({"id": "c4", "type": "Person", "name": "Charles"}),
+        ];
+        let links = vec![
+            json!({
+                "id": "p1",
+                "subject": "c3",
+                "predicate": "knows",
+                "object": "c4",
+                "attributes": {"since": 1833},
+            }),
+            // the object is not part of the capsule
+            json!({"id": "p2", "subject": "c3", "predicate": "knows", "object": "c9"}),
+        ];
+
+        let capsule = render_capsule(concepts, &links);
+        assert_eq!(capsule.concepts, 4);
+        assert_eq!(capsule.propositions, 1);
+
+        let (header, body) = capsule.kml.split_once("UPSERT {\n").unwrap();
+        assert!(header.starts_with("// Knowledge Capsule exported by Anda AI at "));
+        assert!(header.ends_with("// 4 concepts, 1 propositions\n"));
+        assert_eq!(
+            body,
+            concat!(
+                "    CONCEPT ?c0 {\n",
+                "        {type: \"$ConceptType\", name: \"Person\"}\n",
+                "    }\n\n",
+                "    CONCEPT ?c1 {\n",
+                "        {type: \"$PropositionType\", name: \"knows\"}\n",
+                "    }\n\n",
+                "    CONCEPT ?c2 {\n",
+                "        {type: \"Person\", name: \"Ada\"}\n",
+                "        SET ATTRIBUTES { born: 1815, \"full name\": \"Ada Lovelace\" }\n",
+                "    } WITH METADATA { source: \"book\" }\n\n",
+                "    CONCEPT ?c3 {\n",
+                "        {type: \"Person\", name: \"Charles\"}\n",
+                "    }\n\n",
+                "    PROPOSITION ?p0 {\n",
+                "        (?c2, \"knows\", ?c3)\n",
+                "        SET ATTRIBUTES { since: 1833 }\n",
+                "    }\n\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn collects_the_referred_concepts() {
+        let refs = ConceptRefs::from_kml(concat!(
+            "UPSERT {\n",
+            "    CONCEPT ?c0 {\n",
+            "        {type: \"$ConceptType\", name: \"Person\"}\n",
+            "    }\n",
+            "    CONCEPT ?c1 {\n",
+            "        {type: \"Person\", name: \"Ada\"}\n",
+            "        SET PROPOSITIONS { (\"knows\", {type: \"Person\", name: \"$self\"}) }\n",
+            "    }\n",
+            "    PROPOSITION ?p0 {\n",
+            "        (?c1, \"knows\", {id: \"C:42\"})\n",
+            "    }\n",
+            "}\n",
+        ));
+        assert_eq!(
+            refs.names.iter().collect::<Vec<_>>(),
+            ["$self", "Ada", "Person"]
+        );
+        assert_eq!(refs.ids.iter().collect::<Vec<_>>(), ["C:42"]);
+        assert!(ConceptRefs::from_kml("not a capsule").names.is_empty());
+    }
+}
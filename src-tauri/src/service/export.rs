@@ -0,0 +1,194 @@
+use anda_core::{BoxError, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, io::Write};
+
+use super::history::message_text;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+/// A stored conversation in the structured export format.
+#[derive(Clone, Debug, Serialize)]
+pub struct ExportedConversation {
+    pub id: u64,
+    pub title: String,
+    pub created_at: u64, // unix timestamp in milliseconds
+    pub updated_at: u64, // unix timestamp in milliseconds
+    /// The models that served the conversation, from the usage ledger.
+    pub models: Vec<String>,
+    pub messages: Vec<Json>,
+}
+
+impl ExportedConversation {
+    pub fn new(conversation: &Json, models: BTreeSet<String>) -> Self {
+        Self {
+            id: conversation["_id"].as_u64().unwrap_or_default(),
+            title: conversation["title"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            created_at: conversation["created_at"].as_u64().unwrap_or_default(),
+            updated_at: conversation["updated_at"].as_u64().unwrap_or_default(),
+            models: models.into_iter().collect(),
+            messages: conversation["messages"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The file name used in exports, e.g. `123-how-to-cook-rice.md`.
+    pub fn file_name(&self, format: ExportFormat) -> String {
+        let slug = self
+            .title
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '-' })
+            .collect::<String>()
+            .split('-')
+            .filter(|s| !s.is_empty())
+            .take(8)
+            .collect::<Vec<_>>()
+            .join("-")
+            .to_lowercase();
+        if slug.is_empty() {
+            format!("{}.{}", self.id, format.extension())
+        } else {
+            format!("{}-{}.{}", self.id, slug, format.extension())
+        }
+    }
+
+    pub fn render(&self, format: ExportFormat) -> Result<String, BoxError> {
+        match format {
+            ExportFormat::Markdown => Ok(self.to_markdown()),
+            ExportFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+        }
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::new();
+        let title = if self.title.is_empty() {
+            format!("Conversation {}", self.id)
+        } else {
+            self.title.clone()
+        };
+        md.push_str(&format!("# {}\n\n", title));
+        md.push_str(&format!("- Conversation: {}\n", self.id));
+        md.push_str(&format!("- Created: {}\n", format_time(self.created_at)));
+        md.push_str(&format!("- Updated: {}\n", format_time(self.updated_at)));
+        if !self.models.is_empty() {
+            md.push_str(&format!("- Models: {}\n", self.models.join(", ")));
+        }
+
+        for msg in &self.messages {
+            let role = match msg["role"].as_str().unwrap_or_default() {
+                "user" => "User",
+                "assistant" => "Assistant",
+                "system" => "System",
+                "tool" => "Tool",
+                other => other,
+            };
+            md.push_str("\n---\n\n");
+            match msg["timestamp"].as_u64() {
+                Some(ts) => md.push_str(&format!("### {} · {}\n\n", role, format_time(ts))),
+                None => md.push_str(&format!("### {}\n\n", role)),
+            }
+            md.push_str(&message_markdown(msg));
+        }
+        md
+    }
+}
+
+/// Writes the conversations to a zip archive, one file per conversation.
+pub fn write_zip<W: Write + std::io::Seek>(
+    writer: W,
+    conversations: &[ExportedConversation],
+    format: ExportFormat,
+) -> Result<(), BoxError> {
+    let mut zip = zip::ZipWriter::new(writer);
+    let options =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for conversation in conversations {
+        zip.start_file(conversation.file_name(format), options)?;
+        zip.write_all(conversation.render(format)?.as_bytes())?;
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+fn format_time(ms: u64) -> String {
+    DateTime::<Utc>::from_timestamp_millis(ms as i64)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_default()
+}
+
+fn message_markdown(msg: &Json) -> String {
+    let parts = match &msg["content"] {
+        Json::Array(parts) => parts,
+        _ => return format!("{}\n", message_text(msg)),
+    };
+
+    let mut md = String::new();
+    for part in parts {
+        match part["type"].as_str().unwrap_or_default() {
+            "Text" => {
+                md.push_str(part["text"].as_str().unwrap_or_default());
+                md.push('\n');
+            }
+            "Reasoning" => {
+                for line in part["text"].as_str().unwrap_or_default().lines() {
+                    md.push_str(&format!("> {}\n", line));
+                }
+            }
+            "ToolCall" => {
+                md.push_str(&format!(
+                    "**Tool call** `{}`\n\n```json\n{}\n```\n",
+                    part["name"].as_str().unwrap_or_default(),
+                    pretty_json(&part["args"])
+                ));
+            }
+            "ToolOutput" => {
+                md.push_str(&format!(
+                    "**Tool output** `{}`\n\n```json\n{}\n```\n",
+                    part["name"].as_str().unwrap_or_default(),
+                    pretty_json(&part["output"])
+                ));
+            }
+            "FileData" => {
+                md.push_str(&format!(
+                    "[{}]({})\n",
+                    part["mime_type"].as_str().unwrap_or("file"),
+                    part["file_uri"].as_str().unwrap_or_default()
+                ));
+            }
+            "InlineData" => {
+                md.push_str(&format!(
+                    "*[inline data: {}]*\n",
+                    part["mime_type"].as_str().unwrap_or_default()
+                ));
+            }
+            _ => {
+                md.push_str(&format!("```json\n{}\n```\n", pretty_json(part)));
+            }
+        }
+        md.push('\n');
+    }
+    md
+}
+
+fn pretty_json(value: &Json) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_default()
+}
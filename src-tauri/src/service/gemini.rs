@@ -0,0 +1,363 @@
+use anda_core::{AgentOutput, BoxError, BoxPinFut, CompletionRequest, Json, Message};
+use anda_db::unix_ms;
+use anda_engine::{
+    model::{CompletionFeaturesDyn, gemini::types},
+    rfc3339_datetime,
+};
+
+use super::{
+    provider::HttpError,
+    run::emit_text_delta,
+    sse::{EventStream, is_event_stream},
+};
+use crate::model::app::ReasoningEffort;
+
+pub const API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+
+/// Streaming Gemini API client. Requests and responses use the Gemini types
+/// of anda_engine.
+#[derive(Clone)]
+pub struct Client {
+    endpoint: String,
+    api_key: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(api_key: &str, endpoint: Option<String>) -> Self {
+        let endpoint = endpoint
+            .filter(|endpoint| !endpoint.is_empty())
+            .unwrap_or_else(|| API_BASE.to_string());
+        Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn with_client(self, http: reqwest::Client) -> Self {
+        Self { http, ..self }
+    }
+
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel {
+            client: self.clone(),
+            model: model.to_string(),
+            top_p: None,
+            reasoning_effort: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CompletionModel {
+    client: Client,
+    model: String,
+    top_p: Option<f64>,
+    reasoning_effort: Option<ReasoningEffort>,
+}
+
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let model = self.clone();
+        Box::pin(async move { model.completion(req).await })
+    }
+}
+
+impl CompletionModel {
+    pub fn with_top_p(self, top_p: Option<f64>) -> Self {
+        Self { top_p, ..self }
+    }
+
+    /// Sets the thinking budget by effort. The thoughts are not returned.
+    pub fn with_reasoning_effort(self, reasoning_effort: Option<ReasoningEffort>) -> Self {
+        Self {
+            reasoning_effort,
+            ..self
+        }
+    }
+
+    async fn completion(&self, req: CompletionRequest) -> Result<AgentOutput, BoxError> {
+        let timestamp = unix_ms();
+        let mut raw_history: Vec<Json> = Vec::new();
+        let mut chat_history: Vec<Message> = Vec::new();
+        let mut greq = types::GenerateContentRequest::default();
+
+        if !req.instructions.is_empty() {
+            greq.system_instruction = Some(types::Content {
+                role: Some(types::Role::Model),
+                parts: vec![types::Part {
+                    data: types::PartKind::Text(req.instructions),
+                    ..Default::default()
+                }],
+            });
+        }
+        // the contents before are already in the history of the run
+        for msg in req.raw_history {
+            greq.contents.push(serde_json::from_value(msg)?);
+        }
+        for msg in req.chat_history {
+            let content = types::Content::from(msg);
+            raw_history.push(serde_json::to_value(&content)?);
+            greq.contents.push(content);
+        }
+        if let Some(mut msg) = req
+            .documents
+            .to_message(&rfc3339_datetime(timestamp).unwrap_or_default())
+        {
+            msg.timestamp = Some(timestamp);
+            chat_history.push(msg.clone());
+            let content = types::Content::from(msg);
+            raw_history.push(serde_json::to_value(&content)?);
+            greq.contents.push(content);
+        }
+        let mut content = req.content;
+        if !req.prompt.is_empty() {
+            content.push(req.prompt.into());
+        }
+        if !content.is_empty() {
+            let msg = Message {
+                role: req.role.unwrap_or_else(|| "user".to_string()),
+                content,
+                timestamp: Some(timestamp),
+                ..Default::default()
+            };
+            chat_history.push(msg.clone());
+            let content = types::Content::from(msg);
+            raw_history.push(serde_json::to_value(&content)?);
+            greq.contents.push(content);
+        }
+
+        greq.generation_config.temperature = req.temperature;
+        greq.generation_config.top_p = self.top_p;
+        greq.generation_config.thinking_config =
+            self.reasoning_effort.map(|effort| types::ThinkingConfig {
+                include_thoughts: false,
+                thinking_budget: Some(match effort {
+                    ReasoningEffort::Low => 1024,
+                    ReasoningEffort::Medium => 8192,
+                    ReasoningEffort::High => 24576,
+                }),
+            });
+        greq.generation_config.max_output_tokens = req.max_output_tokens.map(|v| v as i32);
+        if let Some(output_schema) = req.output_schema {
+            greq.generation_config.response_mime_type = Some("application/json".to_string());
+            greq.generation_config.response_schema = Some(output_schema);
+        }
+        greq.generation_config.stop_sequences = req.stop;
+        if !req.tools.is_empty() {
+            greq.tools = vec![req.tools.into()];
+            greq.tool_config = Some(types::ToolConfig::default());
+        }
+
+        let res = self
+            .client
+            .http
+            .post(format!(
+                "{}/{}:streamGenerateContent?alt=sse",
+                self.client.endpoint, self.model
+            ))
+            .header("x-goog-api-key", &self.client.api_key)
+            .json(&greq)
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            let text = res.text().await.unwrap_or_default();
+            return Err(HttpError {
+                status: status.as_u16(),
+                message: format!("{} completions error: {}", self.model, text),
+            }
+            .into());
+        }
+
+        let mut reply = Reply::default();
+        if is_event_stream(&res) {
+            let mut events = EventStream::new(res);
+            while let Some(event) = events.next().await? {
+                let chunk: Json = serde_json::from_str(&event.data)?;
+                reply.add(&chunk).map_err(|err| self.error(err))?;
+            }
+        } else {
+            // without SSE, the chunks arrive as one JSON array
+            match res.json().await? {
+                Json::Array(chunks) => {
+                    for chunk in &chunks {
+                        reply.add(chunk).map_err(|err| self.error(err))?;
+                    }
+                }
+                chunk => reply.add(&chunk).map_err(|err| self.error(err))?,
+            }
+        }
+
+        reply.into_response().try_into(raw_history, chat_history)
+    }
+
+    fn error(&self, err: String) -> BoxError {
+        format!("{} completions error: {}", self.model, err).into()
+    }
+}
+
+/// The response of a completion, collected from the streamed chunks. The
+/// text parts of a chunk continue the text parts of the chunk before.
+#[derive(Debug, Default)]
+struct Reply {
+    candidate: bool,
+    parts: Vec<types::Part>,
+    finish_reason: Option<types::FinishReason>,
+    prompt_feedback: Option<types::PromptFeedback>,
+    usage: Option<types::UsageMetadata>,
+    model_version: Option<String>,
+    response_id: Option<String>,
+}
+
+impl Reply {
+    /// Adds a streamed chunk and emits its text.
+    fn add(&mut self, chunk: &Json) -> Result<(), String> {
+        if let Some(err) = chunk.get("error")
+            && !err.is_null()
+        {
+            return Err(err.to_string());
+        }
+        if let Some(feedback) = chunk.get("promptFeedback") {
+            self.prompt_feedback = serde_json::from_value(feedback.clone()).ok();
+        }
+        // the token counts of early chunks may be incomplete
+        if let Some(usage) = chunk.get("usageMetadata")
+            && let Ok(usage) = serde_json::from_value(usage.clone())
+        {
+            self.usage = Some(usage);
+        }
+        if let Some(version) = chunk["modelVersion"].as_str() {
+            self.model_version = Some(version.to_string());
+        }
+        if let Some(id) = chunk["responseId"].as_str() {
+            self.response_id = Some(id.to_string());
+        }
+
+        let candidate = &chunk["candidates"][0];
+        if !candidate.is_object() {
+            return Ok(());
+        }
+        self.candidate = true;
+        if let Some(reason) = candidate.get("finishReason") {
+            self.finish_reason =
+                Some(serde_json::from_value(reason.clone()).map_err(|err| err.to_string())?);
+        }
+        for part in candidate["content"]["parts"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            let part: types::Part =
+                serde_json::from_value(part.clone()).map_err(|err| err.to_string())?;
+            if let types::PartKind::Text(text) = &part.data
+                && part.thought != Some(true)
+            {
+                emit_text_delta(text);
+            }
+            self.push(part);
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, part: types::Part) {
+        if let Some(last) = self.parts.last_mut()
+            && let types::PartKind::Text(last_text) = &mut last.data
+            && let types::PartKind::Text(text) = &part.data
+            && last.thought == part.thought
+            && (last.thought_signature.is_none() || part.thought_signature.is_none())
+        {
+            last_text.push_str(text);
+            if part.thought_signature.is_some() {
+                last.thought_signature = part.thought_signature;
+            }
+            return;
+        }
+        self.parts.push(part);
+    }
+
+    fn into_response(self) -> types::GenerateContentResponse {
+        let candidates = if self.candidate {
+            vec![types::Candidate {
+                content: types::Content {
+                    role: Some(types::Role::Model),
+                    parts: self.parts,
+                },
+                finish_reason: self.finish_reason,
+                satefy_ratings: None,
+                citation_metadata: None,
+                token_count: None,
+                avg_logprobs: None,
+                index: None,
+            }]
+        } else {
+            Vec::new()
+        };
+        types::GenerateContentResponse {
+            candidates,
+            prompt_feedback: self.prompt_feedback,
+            usage_metadata: self.usage.unwrap_or(types::UsageMetadata {
+                prompt_token_count: 0,
+                candidates_token_count: 0,
+                total_token_count: 0,
+                thoughts_token_count: 0,
+            }),
+            model_version: self.model_version,
+            response_id: self.response_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_server::StandIn;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn merges_streamed_chunks() {
+        let chunks = [
+            // the token counts are incomplete until the last chunk
+            json!({"candidates": [{"content": {"role": "model", "parts": [{"text": "Sunny "}]}}],
+                "usageMetadata": {"promptTokenCount": 10, "totalTokenCount": 10}}),
+            json!({"candidates": [{"content": {"role": "model", "parts": [{"text": "in Paris."}]}}]}),
+            json!({"candidates": [{"content": {"role": "model", "parts": [
+                {"functionCall": {"name": "note", "args": {"city": "Paris"}}, "thoughtSignature": "sig"},
+            ]}, "finishReason": "STOP"}],
+                "usageMetadata": {"promptTokenCount": 10, "candidatesTokenCount": 6, "totalTokenCount": 16}}),
+        ];
+        let body: String = chunks
+            .iter()
+            .map(|chunk| format!("data: {}\r\n\r\n", chunk))
+            .collect();
+        let server = StandIn::start(vec![(200, body)]).await;
+        let model =
+            Client::new("test-key", Some(server.url.clone())).completion_model("test-model");
+
+        let output = model
+            .completion(CompletionRequest {
+                prompt: "Weather in Paris?".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(output.content, "Sunny in Paris.");
+        assert_eq!(output.failed_reason, None);
+        assert_eq!(output.usage.input_tokens, 10);
+        assert_eq!(output.usage.output_tokens, 6);
+        assert_eq!(output.tool_calls[0].name, "note");
+        assert_eq!(output.tool_calls[0].args, json!({"city": "Paris"}));
+
+        let reply = output.raw_history.last().unwrap();
+        assert_eq!(reply["parts"][0], json!({"text": "Sunny in Paris."}));
+        assert_eq!(reply["parts"][1]["thoughtSignature"], "sig");
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].path,
+            "/test-model:streamGenerateContent?alt=sse"
+        );
+        assert_eq!(requests[0].header("x-goog-api-key"), Some("test-key"));
+    }
+}
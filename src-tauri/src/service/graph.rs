@@ -0,0 +1,290 @@
+use anda_core::{BoxError, Json};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::nexus::Subgraph;
+
+const JSONLD_VOCAB: &str = "urn:kip:";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphFormat {
+    Dot,
+    Graphml,
+    JsonLd,
+}
+
+impl GraphFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            GraphFormat::Dot => "dot",
+            GraphFormat::Graphml => "graphml",
+            GraphFormat::JsonLd => "jsonld",
+        }
+    }
+
+    pub fn render(&self, graph: &Subgraph) -> Result<String, BoxError> {
+        match self {
+            GraphFormat::Dot => Ok(to_dot(graph)),
+            GraphFormat::Graphml => Ok(to_graphml(graph)),
+            GraphFormat::JsonLd => Ok(serde_json::to_string_pretty(&to_jsonld(graph))?),
+        }
+    }
+}
+
+/// Renders the graph in Graphviz DOT. Attributes and metadata are kept as
+/// JSON strings on the nodes and edges.
+pub fn to_dot(graph: &Subgraph) -> String {
+    let mut dot = String::from("digraph nexus {\n    node [shape=box];\n");
+    for node in &graph.concepts {
+        dot.push_str(&format!(
+            "    {} [label={}, type={}, name={}, attributes={}, metadata={}];\n",
+            dot_string(str_field(node, "id")),
+            dot_string(&format!(
+                "{}\n{}",
+                str_field(node, "type"),
+                str_field(node, "name")
+            )),
+            dot_string(str_field(node, "type")),
+            dot_string(str_field(node, "name")),
+            dot_string(&json_field(node, "attributes")),
+            dot_string(&json_field(node, "metadata"))
+        ));
+    }
+    for link in &graph.links {
+        dot.push_str(&format!(
+            "    {} -> {} [id={}, label={}, attributes={}, metadata={}];\n",
+            dot_string(str_field(link, "subject")),
+            dot_string(str_field(link, "object")),
+            dot_string(str_field(link, "id")),
+            dot_string(str_field(link, "predicate")),
+            dot_string(&json_field(link, "attributes")),
+            dot_string(&json_field(link, "metadata"))
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Renders the graph in GraphML. Attributes and metadata are kept as JSON
+/// strings in `data` elements.
+pub fn to_graphml(graph: &Subgraph) -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+        "  <key id=\"type\" for=\"node\" attr.name=\"type\" attr.type=\"string\"/>\n",
+        "  <key id=\"name\" for=\"node\" attr.name=\"name\" attr.type=\"string\"/>\n",
+        "  <key id=\"predicate\" for=\"edge\" attr.name=\"predicate\" attr.type=\"string\"/>\n",
+        "  <key id=\"attributes\" for=\"all\" attr.name=\"attributes\" attr.type=\"string\"/>\n",
+        "  <key id=\"metadata\" for=\"all\" attr.name=\"metadata\" attr.type=\"string\"/>\n",
+        "  <graph id=\"nexus\" edgedefault=\"directed\">\n",
+    ));
+    for node in &graph.concepts {
+        xml.push_str(&format!(
+            "    <node id=\"{}\">\n",
+            xml_escape(str_field(node, "id"))
+        ));
+        push_data(&mut xml, "type", str_field(node, "type"));
+        push_data(&mut xml, "name", str_field(node, "name"));
+        push_data(&mut xml, "attributes", &json_field(node, "attributes"));
+        push_data(&mut xml, "metadata", &json_field(node, "metadata"));
+        xml.push_str("    </node>\n");
+    }
+    for link in &graph.links {
+        xml.push_str(&format!(
+            "    <edge id=\"{}\" source=\"{}\" target=\"{}\">\n",
+            xml_escape(str_field(link, "id")),
+            xml_escape(str_field(link, "subject")),
+            xml_escape(str_field(link, "object"))
+        ));
+        push_data(&mut xml, "predicate", str_field(link, "predicate"));
+        push_data(&mut xml, "attributes", &json_field(link, "attributes"));
+        push_data(&mut xml, "metadata", &json_field(link, "metadata"));
+        xml.push_str("    </edge>\n");
+    }
+    xml.push_str("  </graph>\n</graphml>\n");
+    xml
+}
+
+/// Renders the graph as a JSON-LD document. Concepts are typed by their
+/// concept type, propositions reference their subject and object by `@id`.
+pub fn to_jsonld(graph: &Subgraph) -> Json {
+    let concept_id = |id: &str| format!("{}concept:{}", JSONLD_VOCAB, id);
+    let mut items: Vec<Json> = Vec::with_capacity(graph.concepts.len() + graph.links.len());
+    for node in &graph.concepts {
+        items.push(json!({
+            "@id": concept_id(str_field(node, "id")),
+            "@type": str_field(node, "type"),
+            "name": str_field(node, "name"),
+            "attributes": node["attributes"],
+            "metadata": node["metadata"],
+        }));
+    }
+    for link in &graph.links {
+        items.push(json!({
+            "@id": format!("{}proposition:{}", JSONLD_VOCAB, str_field(link, "id")),
+            "@type": "Proposition",
+            "subject": concept_id(str_field(link, "subject")),
+            "predicate": str_field(link, "predicate"),
+            "object": concept_id(str_field(link, "object")),
+            "attributes": link["attributes"],
+            "metadata": link["metadata"],
+        }));
+    }
+
+    json!({
+        "@context": {
+            "@vocab": JSONLD_VOCAB,
+            "subject": {"@type": "@id"},
+            "object": {"@type": "@id"},
+            "attributes": {"@type": "@json"},
+            "metadata": {"@type": "@json"},
+        },
+        "@graph": items,
+    })
+}
+
+fn str_field<'a>(value: &'a Json, key: &str) -> &'a str {
+    value[key].as_str().unwrap_or_default()
+}
+
+fn json_field(value: &Json, key: &str) -> String {
+    match &value[key] {
+        Json::Null => "{}".to_string(),
+        other => other.to_string(),
+    }
+}
+
+fn dot_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn push_data(xml: &mut String, key: &str, value: &str) {
+    xml.push_str(&format!(
+        "      <data key=\"{}\">{}</data>\n",
+        key,
+        xml_escape(value)
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> Subgraph {
+        Subgraph {
+            concepts: vec![
+                json!({
+                    "id": "c1",
+                    "type": "Person",
+                    "name": "Ada \"the first\"",
+                    "attributes": {"born": 1815},
+                }),
+                json!({"id": "c2", "type": "Topic", "name": "<Math> & more"}),
+            ],
+            links: vec![json!({
+                "id": "p1",
+                "subject": "c1",
+                "predicate": "studies",
+                "object": "c2",
+                "metadata": {"source": "book"},
+            })],
+        }
+    }
+
+    #[test]
+    fn renders_dot() {
+        assert_eq!(
+            to_dot(&graph()),
+            concat!(
+                "digraph nexus {\n",
+                "    node [shape=box];\n",
+                "    \"c1\" [label=\"Person\\nAda \\\"the first\\\"\", type=\"Person\", name=\"Ada \\\"the first\\\"\", attributes=\"{\\\"born\\\":1815}\", metadata=\"{}\"];\n",
+                "    \"c2\" [label=\"Topic\\n<Math> & more\", type=\"Topic\", name=\"<Math> & more\", attributes=\"{}\", metadata=\"{}\"];\n",
+                "    \"c1\" -> \"c2\" [id=\"p1\", label=\"studies\", attributes=\"{}\", metadata=\"{\\\"source\\\":\\\"book\\\"}\"];\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn renders_graphml() {
+        let xml = to_graphml(&graph());
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<graphml"));
+        assert!(xml.ends_with("  </graph>\n</graphml>\n"));
+        assert!(xml.contains(concat!(
+            "    <node id=\"c2\">\n",
+            "      <data key=\"type\">Topic</data>\n",
+            "      <data key=\"name\">&lt;Math&gt; &amp; more</data>\n",
+            "      <data key=\"attributes\">{}</data>\n",
+            "      <data key=\"metadata\">{}</data>\n",
+            "    </node>\n",
+        )));
+        assert!(xml.contains("<data key=\"name\">Ada &quot;the first&quot;</data>"));
+        assert!(xml.contains(concat!(
+            "    <edge id=\"p1\" source=\"c1\" target=\"c2\">\n",
+            "      <data key=\"predicate\">studies</data>\n",
+            "      <data key=\"attributes\">{}</data>\n",
+            "      <data key=\"metadata\">{&quot;source&quot;:&quot;book&quot;}</data>\n",
+            "    </edge>\n",
+        )));
+    }
+
+    #[test]
+    fn renders_jsonld() {
+        let doc = to_jsonld(&graph());
+        assert_eq!(doc["@context"]["@vocab"], JSONLD_VOCAB);
+        assert_eq!(
+            doc["@graph"][0],
+            json!({
+                "@id": "urn:kip:concept:c1",
+                "@type": "Person",
+                "name": "Ada \"the first\"",
+                "attributes": {"born": 1815},
+                "metadata": null,
+            })
+        );
+        assert_eq!(
+            doc["@graph"][2],
+            json!({
+                "@id": "urn:kip:proposition:p1",
+                "@type": "Proposition",
+                "subject": "urn:kip:concept:c1",
+                "predicate": "studies",
+                "object": "urn:kip:concept:c2",
+                "attributes": null,
+                "metadata": {"source": "book"},
+            })
+        );
+
+        let rendered = GraphFormat::JsonLd.render(&graph()).unwrap();
+        assert_eq!(serde_json::from_str::<Json>(&rendered).unwrap(), doc);
+    }
+}
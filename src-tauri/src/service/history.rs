@@ -0,0 +1,296 @@
+use anda_core::{BoxError, Json, ToolInput};
+use anda_db::{
+    collection::{Collection, CollectionConfig},
+    database::AndaDB,
+    error::DBError,
+    query::{Filter, Query, RangeQuery},
+    schema::{AndaDBSchema, FieldEntry, FieldType, Fv, Schema, SchemaError},
+    unix_ms,
+};
+use anda_engine::{
+    engine::Engine,
+    memory::{Conversation, ConversationStatus, MemoryTool},
+};
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+const TITLE_MAX_CHARS: usize = 60;
+const MAX_POLL_DELAY: Duration = Duration::from_secs(2);
+/// The collection of conversations owned by the memory of the engine.
+pub const CONVERSATIONS_COLLECTION: &str = "conversations";
+
+/// A user defined title of a conversation.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, AndaDBSchema)]
+pub struct ConversationTitle {
+    pub _id: u64,
+    pub conversation: u64,
+    pub title: String,
+    pub updated_at: u64, // unix timestamp in milliseconds
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ConversationPage {
+    /// Conversations, newest first, each with a `title` field.
+    pub items: Vec<Json>,
+    pub next_cursor: Option<String>,
+}
+
+/// Conversation history on top of the memory owned by the assistant.
+/// Conversations are read through the memory tool, so that they are scoped
+/// to the caller, titles are kept in a collection of our own.
+pub struct ConversationHistory {
+    titles: Arc<Collection>,
+}
+
+impl ConversationHistory {
+    const COLLECTION: &'static str = "conversation_titles";
+
+    pub async fn connect(db: &AndaDB) -> Result<Self, BoxError> {
+        let schema = ConversationTitle::schema()?;
+        let titles = db
+            .open_or_create_collection(
+                schema,
+                CollectionConfig {
+                    name: Self::COLLECTION.to_string(),
+                    description: "Conversation titles".to_string(),
+                },
+                async |collection| {
+                    collection.create_btree_index_nx(&["conversation"]).await?;
+                    Ok::<(), DBError>(())
+                },
+            )
+            .await?;
+
+        Ok(Self { titles })
+    }
+
+    pub async fn list(
+        &self,
+        engine: &Engine,
+        caller: Principal,
+        cursor: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<ConversationPage, BoxError> {
+        let (result, next_cursor) = memory_api(
+            engine,
+            caller,
+            json!({
+                "type": "ListPrevConversations",
+                "cursor": cursor,
+                "limit": limit.unwrap_or(20),
+            }),
+        )
+        .await?;
+
+        let mut items: Vec<Json> = serde_json::from_value(result)?;
+        // the memory tool returns a page in chronological order
+        items.reverse();
+        for item in items.iter_mut() {
+            self.with_title(item).await?;
+        }
+
+        Ok(ConversationPage { items, next_cursor })
+    }
+
+    pub async fn get(&self, engine: &Engine, caller: Principal, id: u64) -> Result<Json, BoxError> {
+        let (mut conversation, _) = memory_api(
+            engine,
+            caller,
+            json!({
+                "type": "GetConversation",
+                "_id": id,
+            }),
+        )
+        .await?;
+        if conversation.is_null() {
+            return Err(format!("Conversation {} not found", id).into());
+        }
+
+        self.with_title(&mut conversation).await?;
+        Ok(conversation)
+    }
+
+    pub async fn delete(
+        &self,
+        engine: &Engine,
+        db: &AndaDB,
+        caller: Principal,
+        id: u64,
+    ) -> Result<bool, BoxError> {
+        // ensures that the conversation belongs to the caller
+        self.get(engine, caller, id).await?;
+
+        let conversations = open_conversations(db).await?;
+        let deleted = conversations.remove(id).await?.is_some();
+        if let Some(title) = self.find_title(id).await? {
+            self.titles.remove(title._id).await?;
+        }
+        log::info!("Conversation {} deleted", id);
+        Ok(deleted)
+    }
+
+    pub async fn rename(
+        &self,
+        engine: &Engine,
+        caller: Principal,
+        id: u64,
+        title: String,
+    ) -> Result<bool, BoxError> {
+        let title = title.trim().to_string();
+        if title.is_empty() {
+            return Err("Conversation title cannot be empty".into());
+        }
+        self.get(engine, caller, id).await?;
+
+        match self.find_title(id).await? {
+            Some(existing) => {
+                self.titles
+                    .update(
+                        existing._id,
+                        BTreeMap::from([
+                            ("title".to_string(), Fv::Text(title)),
+                            ("updated_at".to_string(), Fv::U64(unix_ms())),
+                        ]),
+                    )
+                    .await?;
+            }
+            None => {
+                self.titles
+                    .add_from(&ConversationTitle {
+                        _id: 0,
+                        conversation: id,
+                        title,
+                        updated_at: unix_ms(),
+                    })
+                    .await?;
+            }
+        }
+        Ok(true)
+    }
+
+    pub async fn title(&self, conversation: &Json) -> Result<String, BoxError> {
+        let id = conversation["_id"].as_u64().unwrap_or_default();
+        match self.find_title(id).await? {
+            Some(title) => Ok(title.title),
+            None => Ok(default_title(conversation)),
+        }
+    }
+
+    async fn with_title(&self, conversation: &mut Json) -> Result<(), BoxError> {
+        let title = self.title(conversation).await?;
+        if let Some(obj) = conversation.as_object_mut() {
+            obj.insert("title".to_string(), json!(title));
+        }
+        Ok(())
+    }
+
+    async fn find_title(&self, conversation: u64) -> Result<Option<ConversationTitle>, BoxError> {
+        let mut res: Vec<ConversationTitle> = self
+            .titles
+            .search_as(Query {
+                filter: Some(Filter::Field((
+                    "conversation".to_string(),
+                    RangeQuery::Eq(Fv::U64(conversation)),
+                ))),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .await?;
+        Ok(res.pop())
+    }
+}
+
+/// Opens the collection of conversations. The collection is created by the
+/// memory and shared by the database.
+pub async fn open_conversations(db: &AndaDB) -> Result<Arc<Collection>, BoxError> {
+    let conversations = db
+        .open_collection(CONVERSATIONS_COLLECTION.to_string(), async |_| {
+            Ok::<(), DBError>(())
+        })
+        .await?;
+    Ok(conversations)
+}
+
+/// Returns the user a conversation belongs to.
+pub async fn conversation_owner(db: &AndaDB, id: u64) -> Result<Principal, BoxError> {
+    let conversation: Conversation = open_conversations(db).await?.get_as(id).await?;
+    Ok(conversation.user)
+}
+
+/// Waits until the agent is done with a conversation. The agent works on a
+/// conversation on a task of its own and only updates it in the collection,
+/// so its status is polled.
+pub async fn wait_finished(db: &AndaDB, id: u64) -> Result<Conversation, BoxError> {
+    let conversations = open_conversations(db).await?;
+    let mut delay = Duration::from_millis(100);
+    loop {
+        let conversation: Conversation = conversations.get_as(id).await?;
+        if !matches!(
+            conversation.status,
+            ConversationStatus::Submitted | ConversationStatus::Working
+        ) {
+            return Ok(conversation);
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_POLL_DELAY);
+    }
+}
+
+/// Calls the memory tool and returns its result and next cursor.
+pub async fn memory_api(
+    engine: &Engine,
+    caller: Principal,
+    args: Json,
+) -> Result<(Json, Option<String>), BoxError> {
+    let res = engine
+        .tool_call(caller, ToolInput::new(MemoryTool::NAME.to_string(), args))
+        .await?;
+    let mut output = res.output;
+    if let Some(err) = output.get("error")
+        && !err.is_null()
+    {
+        return Err(format!("Memory API error: {}", err).into());
+    }
+
+    let next_cursor = output["next_cursor"].as_str().map(|s| s.to_string());
+    Ok((output["result"].take(), next_cursor))
+}
+
+/// Returns the text of a message's content parts.
+pub fn message_text(message: &Json) -> String {
+    match &message["content"] {
+        Json::String(text) => text.clone(),
+        Json::Array(parts) => parts
+            .iter()
+            .filter(|part| part["type"].as_str().unwrap_or("Text") == "Text")
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Uses the beginning of the first user message as the title.
+fn default_title(conversation: &Json) -> String {
+    let text = conversation["messages"]
+        .as_array()
+        .and_then(|messages| {
+            messages
+                .iter()
+                .filter(|msg| msg["role"] == "user")
+                .map(message_text)
+                .find(|text| !text.trim().is_empty())
+        })
+        .unwrap_or_default();
+
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() > TITLE_MAX_CHARS {
+        let mut title: String = text.chars().take(TITLE_MAX_CHARS).collect();
+        title.push('…');
+        title
+    } else {
+        text
+    }
+}
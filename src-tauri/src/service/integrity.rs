@@ -0,0 +1,284 @@
+use anda_core::BoxError;
+use anda_db::{
+    collection::Collection, database::AndaDB, error::DBError, index::from_virtual_field_name,
+    schema::DocumentId, unix_ms,
+};
+use futures::TryStreamExt;
+use object_store::{ObjectStore, path::Path as ObjectPath};
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct IntegrityReport {
+    pub repaired: bool,
+    pub objects: usize,
+    pub bytes: u64,
+    pub collections: Vec<CollectionCheck>,
+    /// Objects whose metadata is present but some chunks are missing.
+    pub missing_chunks: Vec<String>,
+    /// Objects that can not be read or decrypted.
+    pub undecodable_objects: Vec<String>,
+    /// Objects of collections that no longer exist in the database.
+    pub orphaned_objects: Vec<String>,
+    /// Objects deleted from the object store by the repair.
+    pub deleted_objects: Vec<String>,
+    /// Indexes rebuilt by the repair.
+    pub rebuilt_indexes: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CollectionCheck {
+    pub name: String,
+    /// Why the collection could not be opened.
+    pub error: Option<String>,
+    pub documents: usize,
+    pub undecodable_documents: Vec<u64>,
+    /// Undecodable documents removed by the repair.
+    pub removed_documents: Vec<u64>,
+}
+
+impl IntegrityReport {
+    pub fn is_healthy(&self) -> bool {
+        self.missing_chunks.is_empty()
+            && self.undecodable_objects.is_empty()
+            && self.orphaned_objects.is_empty()
+            && self.collections.iter().all(|c| {
+                c.error.is_none() && c.undecodable_documents.len() == c.removed_documents.len()
+            })
+    }
+}
+
+/// Checks every object of the object store and every document of the
+/// database collections. With `repair`, undecodable documents are removed
+/// from their collections and from the collection indexes, and their objects
+/// are deleted together with the objects of dropped collections. Objects of
+/// the indexes and the metadata are only reported, the database can not be
+/// opened without them.
+///
+/// The caller must keep agents from writing to the database meanwhile.
+pub async fn check_database(db: &AndaDB, repair: bool) -> Result<IntegrityReport, BoxError> {
+    let names: BTreeSet<String> = db.metadata().collections;
+    let mut report = IntegrityReport {
+        repaired: repair,
+        ..Default::default()
+    };
+
+    let object_store = db.object_store().clone();
+    let mut objects = object_store.list(None);
+    while let Some(meta) = objects.try_next().await? {
+        report.objects += 1;
+        report.bytes += meta.size;
+        let location = meta.location.to_string();
+        if collection_of(db.name(), &location).is_some_and(|c| !names.contains(c)) {
+            report.orphaned_objects.push(location.clone());
+        }
+
+        match read_object(object_store.as_ref(), &meta.location).await {
+            Ok(_) => {}
+            Err(object_store::Error::NotFound { .. }) => report.missing_chunks.push(location),
+            Err(err) => {
+                log::warn!("Undecodable object {}: {}", location, err);
+                report.undecodable_objects.push(location);
+            }
+        }
+    }
+
+    for name in names {
+        let check = match db
+            .open_collection(name.clone(), async |_| Ok::<(), DBError>(()))
+            .await
+        {
+            Ok(collection) => {
+                let check = check_collection(&collection, repair).await?;
+                if !check.removed_documents.is_empty() {
+                    report
+                        .rebuilt_indexes
+                        .extend(prune_indexes(&collection, &check.removed_documents).await?);
+                }
+                check
+            }
+            Err(err) => {
+                log::error!("Failed to open collection {}: {}", name, err);
+                CollectionCheck {
+                    name,
+                    error: Some(err.to_string()),
+                    ..Default::default()
+                }
+            }
+        };
+        report.collections.push(check);
+    }
+
+    if repair {
+        let removed: BTreeSet<String> = report
+            .collections
+            .iter()
+            .flat_map(|c| {
+                c.removed_documents
+                    .iter()
+                    .map(|id| format!("{}/{}/data/{}.cbor", db.name(), c.name, id))
+            })
+            .collect();
+        let bad: BTreeSet<String> = report
+            .missing_chunks
+            .iter()
+            .chain(report.undecodable_objects.iter())
+            .filter(|location| removed.contains(*location))
+            .chain(report.orphaned_objects.iter())
+            .cloned()
+            .collect();
+        for location in bad {
+            match object_store
+                .delete(&ObjectPath::from(location.as_str()))
+                .await
+            {
+                Ok(_) | Err(object_store::Error::NotFound { .. }) => {
+                    log::warn!("Object {} deleted by the repair", location);
+                    report.deleted_objects.push(location);
+                }
+                Err(err) => log::error!("Failed to delete object {}: {}", location, err),
+            }
+        }
+    }
+
+    log::info!(
+        "Database checked: {} objects, {} bytes, {} missing chunks, {} undecodable objects, {} orphaned objects, {} deleted objects",
+        report.objects,
+        report.bytes,
+        report.missing_chunks.len(),
+        report.undecodable_objects.len(),
+        report.orphaned_objects.len(),
+        report.deleted_objects.len()
+    );
+    if !report.is_healthy() {
+        log::warn!("Database is damaged, repaired: {}", report.repaired);
+    }
+    Ok(report)
+}
+
+/// The collection that an object belongs to. AndaDB keeps its own metadata
+/// under `<db>/` and every collection under `<db>/<collection>/`.
+pub fn collection_of<'a>(db: &str, location: &'a str) -> Option<&'a str> {
+    let rest = location.strip_prefix(db)?.strip_prefix('/')?;
+    rest.split_once('/').map(|(collection, _)| collection)
+}
+
+async fn read_object(
+    object_store: &dyn ObjectStore,
+    location: &ObjectPath,
+) -> Result<(), object_store::Error> {
+    object_store.get(location).await?.bytes().await?;
+    Ok(())
+}
+
+/// Reads every document of the collection by ID. Deleted documents are not
+/// found and skipped.
+async fn check_collection(
+    collection: &Collection,
+    repair: bool,
+) -> Result<CollectionCheck, BoxError> {
+    let mut check = CollectionCheck {
+        name: collection.name().to_string(),
+        ..Default::default()
+    };
+    let max_id = collection.stats().max_document_id;
+    for id in 1..=max_id {
+        match collection.get(id).await {
+            Ok(_) => check.documents += 1,
+            Err(DBError::NotFound { .. }) => {}
+            Err(err) => {
+                log::warn!(
+                    "Undecodable document {} in collection {}: {}",
+                    id,
+                    check.name,
+                    err
+                );
+                check.undecodable_documents.push(id);
+            }
+        }
+    }
+
+    if repair {
+        for id in &check.undecodable_documents {
+            match collection.remove(*id).await {
+                Ok(_) => check.removed_documents.push(*id),
+                Err(err) => log::error!(
+                    "Failed to remove document {} from collection {}: {}",
+                    id,
+                    check.name,
+                    err
+                ),
+            }
+        }
+    }
+    Ok(check)
+}
+
+/// Drops the removed documents from the indexes of the collection. AndaDB
+/// reads a document to find its index entries when it is removed, which an
+/// undecodable document can not give. BTree entries of documents that no
+/// longer exist are found by scanning the keys, the BM25 and HNSW indexes
+/// are keyed by document ID. Returns the names of the changed indexes.
+async fn prune_indexes(
+    collection: &Collection,
+    removed: &[DocumentId],
+) -> Result<Vec<String>, BoxError> {
+    let now_ms = unix_ms();
+    let meta = collection.metadata();
+    let mut rebuilt = Vec::new();
+    for name in meta.btree_indexes.keys() {
+        let fields = from_virtual_field_name(name);
+        let fields: Vec<&str> = fields.iter().map(|f| f.as_str()).collect();
+        let index = collection.get_btree_index(&fields)?;
+        let mut pruned = 0;
+        for fv in index.keys(None, None) {
+            let stale = index
+                .query_with(&fv, |ids| {
+                    Some(
+                        ids.iter()
+                            .filter(|id| !collection.contains(**id))
+                            .copied()
+                            .collect::<Vec<_>>(),
+                    )
+                })
+                .unwrap_or_default();
+            for id in stale {
+                if index.remove(id, &fv, now_ms) {
+                    pruned += 1;
+                }
+            }
+        }
+        if pruned > 0 {
+            rebuilt.push(format!("{}/{}", collection.name(), name));
+        }
+    }
+
+    for name in meta.bm25_indexes.keys() {
+        let fields = from_virtual_field_name(name);
+        let fields: Vec<&str> = fields.iter().map(|f| f.as_str()).collect();
+        let index = collection.get_bm25_index(&fields)?;
+        // Without the text the postings stay, but the document no longer
+        // counts in the scoring and searches skip it.
+        let pruned = removed
+            .iter()
+            .filter(|id| index.remove(**id, "", now_ms))
+            .count();
+        if pruned > 0 {
+            rebuilt.push(format!("{}/{}", collection.name(), name));
+        }
+    }
+
+    for name in meta.hnsw_indexes.keys() {
+        let index = collection.get_hnsw_index(name)?;
+        let pruned = removed
+            .iter()
+            .filter(|id| index.remove(**id, now_ms))
+            .count();
+        if pruned > 0 {
+            rebuilt.push(format!("{}/{}", collection.name(), name));
+        }
+    }
+
+    collection.flush(now_ms).await?;
+    Ok(rebuilt)
+}
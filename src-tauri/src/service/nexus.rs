@@ -0,0 +1,396 @@
+use anda_core::{BoxError, Json};
+use anda_engine::memory::MemoryManagement;
+use anda_kip::{Command, Executor, META_CONCEPT_TYPE, META_PROPOSITION_TYPE, Response, parse_kip};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, json};
+use std::collections::BTreeMap;
+
+const PAGE_SIZE: usize = 500;
+
+/// Runs a KIP command directly against the Cognitive Nexus of the assistant.
+/// KQL and META commands are read-only, KML commands change the memory and
+/// are refused unless `write` is set.
+pub async fn execute_kip(
+    memory: &MemoryManagement,
+    command: &str,
+    dry_run: bool,
+    write: bool,
+) -> Result<Response, BoxError> {
+    let cmd = parse_kip(command)?;
+    if matches!(cmd, Command::Kml(_)) {
+        if !write {
+            return Err("KML commands change the memory and require the write flag".into());
+        }
+        log::warn!("Executing KML command, dry run: {}", dry_run);
+    }
+
+    Ok(memory.nexus().execute(cmd, dry_run).await)
+}
+
+/// A page of concept nodes, proposition links or types.
+#[derive(Clone, Debug, Serialize)]
+pub struct KipPage {
+    pub items: Vec<Json>,
+    pub next_cursor: Option<String>,
+}
+
+/// A concept node with the proposition links it takes part in.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConceptView {
+    pub concept: Json,
+    pub outgoing: Vec<Json>,
+    pub incoming: Vec<Json>,
+}
+
+/// Selects a part of the Cognitive Nexus by concept type and predicate. An
+/// empty filter selects everything.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct GraphFilter {
+    #[serde(default)]
+    pub concept_types: Vec<String>,
+    #[serde(default)]
+    pub predicates: Vec<String>,
+}
+
+/// Concept nodes and the proposition links between them.
+#[derive(Clone, Debug, Default)]
+pub struct Subgraph {
+    pub concepts: Vec<Json>,
+    pub links: Vec<Json>,
+}
+
+/// Browsing and editing of the Cognitive Nexus for the memory inspector. All
+/// user provided values are embedded as JSON string literals, which are valid
+/// KIP strings, so they can not change the structure of a command.
+pub struct MemoryInspector<'a> {
+    memory: &'a MemoryManagement,
+}
+
+impl<'a> MemoryInspector<'a> {
+    pub fn new(memory: &'a MemoryManagement) -> Self {
+        Self { memory }
+    }
+
+    pub async fn concept_types(
+        &self,
+        limit: Option<usize>,
+        cursor: Option<String>,
+    ) -> Result<KipPage, BoxError> {
+        let command = format!("DESCRIBE CONCEPT TYPES{}", page(limit, cursor));
+        self.query(&command).await
+    }
+
+    pub async fn concepts(
+        &self,
+        concept_type: &str,
+        limit: Option<usize>,
+        cursor: Option<String>,
+    ) -> Result<KipPage, BoxError> {
+        let command = format!(
+            "FIND(?node) WHERE {{ ?node {{type: {}}} }} ORDER BY ?node.id ASC{}",
+            quote(concept_type),
+            page(limit, cursor)
+        );
+        self.query(&command).await
+    }
+
+    pub async fn propositions(
+        &self,
+        predicate: Option<&str>,
+        limit: Option<usize>,
+        cursor: Option<String>,
+    ) -> Result<KipPage, BoxError> {
+        let predicate = predicate
+            .map(quote)
+            .unwrap_or_else(|| "?predicate".to_string());
+        let command = format!(
+            "FIND(?link) WHERE {{ ?link (?subject, {}, ?object) }} ORDER BY ?link.id ASC{}",
+            predicate,
+            page(limit, cursor)
+        );
+        self.query(&command).await
+    }
+
+    pub async fn concept(&self, id: &str) -> Result<ConceptView, BoxError> {
+        let concept = self.get_concept(id).await?;
+        let node = format!("?node {{id: {}}}", quote(id));
+        let outgoing = self
+            .query(&format!(
+                "FIND(?link) WHERE {{ {} ?link (?node, ?predicate, ?object) }}",
+                node
+            ))
+            .await?;
+        let incoming = self
+            .query(&format!(
+                "FIND(?link) WHERE {{ {} ?link (?subject, ?predicate, ?node) }}",
+                node
+            ))
+            .await?;
+
+        Ok(ConceptView {
+            concept,
+            outgoing: outgoing.items,
+            incoming: incoming.items,
+        })
+    }
+
+    /// Merges the attributes into a concept node, `null` values remove the
+    /// attribute.
+    pub async fn update_attributes(
+        &self,
+        id: &str,
+        attributes: Map<String, Json>,
+    ) -> Result<Json, BoxError> {
+        self.get_concept(id).await?;
+
+        let mut set = Vec::new();
+        let mut delete = Vec::new();
+        for (key, value) in attributes {
+            if !is_identifier(&key) {
+                return Err(format!("Invalid attribute name: {}", key).into());
+            }
+            if value.is_null() {
+                delete.push(quote(&key));
+            } else {
+                set.push(format!("{}: {}", key, value));
+            }
+        }
+
+        if !set.is_empty() {
+            let command = format!(
+                "UPSERT {{ CONCEPT ?node {{ {{id: {}}} SET ATTRIBUTES {{ {} }} }} }}",
+                quote(id),
+                set.join(", ")
+            );
+            self.kml(&command).await?;
+        }
+        if !delete.is_empty() {
+            let command = format!(
+                "DELETE ATTRIBUTES {{{}}} FROM ?node WHERE {{ ?node {{id: {}}} }}",
+                delete.join(", "),
+                quote(id)
+            );
+            self.kml(&command).await?;
+        }
+
+        self.get_concept(id).await
+    }
+
+    /// Deletes a concept node together with all its proposition links. The
+    /// schema definitions and core identities can not be forgotten.
+    pub async fn forget(&self, id: &str) -> Result<Json, BoxError> {
+        let concept = self.get_concept(id).await?;
+        let type_name = concept["type"].as_str().unwrap_or_default();
+        let name = concept["name"].as_str().unwrap_or_default();
+        if type_name.starts_with('$') || is_protected(name) {
+            return Err(format!("Concept {}:{} is protected", type_name, name).into());
+        }
+
+        let command = format!(
+            "DELETE CONCEPT ?node DETACH WHERE {{ ?node {{id: {}}} }}",
+            quote(id)
+        );
+        let res = self.kml(&command).await?;
+        log::warn!("Concept {}:{} forgotten", type_name, name);
+        Ok(res)
+    }
+
+    /// Returns the names of all concept types, the meta types first.
+    pub async fn type_names(&self) -> Result<Vec<String>, BoxError> {
+        let mut names = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let command = format!(
+                "FIND(?type.name) WHERE {{ ?type {{type: {}}} }} ORDER BY ?type.id ASC{}",
+                quote(META_CONCEPT_TYPE),
+                page(Some(PAGE_SIZE), cursor)
+            );
+            let res = self.query(&command).await?;
+            names.extend(
+                res.items
+                    .iter()
+                    .filter_map(|name| name.as_str().map(|s| s.to_string())),
+            );
+            match res.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        // definitions come first, so that every type is defined before use
+        names.sort_by_key(|name| meta_rank(name));
+        Ok(names)
+    }
+
+    /// Selects the concept nodes of the filtered types and the links of the
+    /// filtered predicates, together with the concepts the links connect.
+    /// Without a predicate filter, only links between selected concepts are
+    /// included. Links whose subject or object is another link are skipped.
+    pub async fn subgraph(&self, filter: &GraphFilter) -> Result<Subgraph, BoxError> {
+        let select_all = filter.concept_types.is_empty() && filter.predicates.is_empty();
+        let concept_types = if select_all {
+            self.type_names().await?
+        } else {
+            filter.concept_types.clone()
+        };
+
+        let mut order: Vec<String> = Vec::new();
+        let mut concepts: BTreeMap<String, Json> = BTreeMap::new(); // id -> node
+        for concept_type in &concept_types {
+            let mut cursor = None;
+            loop {
+                let res = self.concepts(concept_type, Some(PAGE_SIZE), cursor).await?;
+                for node in res.items {
+                    if let Some(id) = node["id"].as_str()
+                        && concepts.insert(id.to_string(), node.clone()).is_none()
+                    {
+                        order.push(id.to_string());
+                    }
+                }
+                match res.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+        }
+
+        let predicates: Vec<Option<&str>> = if filter.predicates.is_empty() {
+            vec![None]
+        } else {
+            filter.predicates.iter().map(|p| Some(p.as_str())).collect()
+        };
+        let mut links: Vec<Json> = Vec::new();
+        for predicate in predicates {
+            let mut cursor = None;
+            loop {
+                let res = self
+                    .propositions(predicate, Some(PAGE_SIZE), cursor)
+                    .await?;
+                for link in res.items {
+                    let subject = link["subject"].as_str().unwrap_or_default();
+                    let object = link["object"].as_str().unwrap_or_default();
+                    if predicate.is_some()
+                        || (concepts.contains_key(subject) && concepts.contains_key(object))
+                    {
+                        links.push(link);
+                    }
+                }
+                match res.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+        }
+
+        let mut kept = Vec::with_capacity(links.len());
+        'links: for link in links {
+            for end in ["subject", "object"] {
+                let id = link[end].as_str().unwrap_or_default();
+                if concepts.contains_key(id) {
+                    continue;
+                }
+                match self.get_concept(id).await {
+                    Ok(node) => {
+                        concepts.insert(id.to_string(), node);
+                        order.push(id.to_string());
+                    }
+                    Err(_) => {
+                        log::warn!(
+                            "Proposition {} skipped: {} {} is not a concept",
+                            link["id"],
+                            end,
+                            id
+                        );
+                        continue 'links;
+                    }
+                }
+            }
+            kept.push(link);
+        }
+
+        Ok(Subgraph {
+            concepts: order
+                .into_iter()
+                .filter_map(|id| concepts.remove(&id))
+                .collect(),
+            links: kept,
+        })
+    }
+
+    pub async fn get_concept(&self, id: &str) -> Result<Json, BoxError> {
+        let command = format!("FIND(?node) WHERE {{ ?node {{id: {}}} }}", quote(id));
+        let res = self.query(&command).await?;
+        res.items
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("Concept {} not found", id).into())
+    }
+
+    /// Runs a read-only KIP command and returns its result as a page.
+    pub async fn query(&self, command: &str) -> Result<KipPage, BoxError> {
+        let (result, next_cursor) =
+            kip_result(execute_kip(self.memory, command, false, false).await?)?;
+        let items = match result {
+            Json::Array(items) => items,
+            Json::Null => Vec::new(),
+            other => vec![other],
+        };
+        Ok(KipPage { items, next_cursor })
+    }
+
+    async fn kml(&self, command: &str) -> Result<Json, BoxError> {
+        let (result, _) = kip_result(execute_kip(self.memory, command, false, true).await?)?;
+        Ok(result)
+    }
+}
+
+/// Splits a KIP response into its result and next cursor.
+pub fn kip_result(res: Response) -> Result<(Json, Option<String>), BoxError> {
+    let mut res = json!(res);
+    if let Some(err) = res.get("error")
+        && !err.is_null()
+    {
+        return Err(format!("KIP error: {}", err).into());
+    }
+
+    let next_cursor = res["next_cursor"].as_str().map(|s| s.to_string());
+    Ok((res["result"].take(), next_cursor))
+}
+
+/// Whether a concept name is reserved for the schema definitions and core
+/// identities, such as `$self`, `$system` and the meta types.
+pub fn is_protected(name: &str) -> bool {
+    name.starts_with('$')
+}
+
+/// Sorts `$ConceptType` first and `$PropositionType` second.
+pub fn meta_rank(type_name: &str) -> u8 {
+    if type_name == META_CONCEPT_TYPE {
+        0
+    } else if type_name == META_PROPOSITION_TYPE {
+        1
+    } else {
+        2
+    }
+}
+
+/// Quotes a value as a KIP string literal.
+pub fn quote(value: &str) -> String {
+    Json::String(value.to_string()).to_string()
+}
+
+fn page(limit: Option<usize>, cursor: Option<String>) -> String {
+    let mut s = format!(" LIMIT {}", limit.unwrap_or(50).min(500));
+    if let Some(cursor) = cursor {
+        s.push_str(&format!(" CURSOR {}", quote(&cursor)));
+    }
+    s
+}
+
+pub fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
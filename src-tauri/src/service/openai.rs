@@ -0,0 +1,552 @@
+use anda_core::{
+    AgentOutput, BoxError, BoxPinFut, CompletionRequest, ContentPart, Json, Message, ToolCall,
+    Usage,
+};
+use anda_db::unix_ms;
+use anda_engine::{model::CompletionFeaturesDyn, rfc3339_datetime};
+use serde_json::json;
+
+use super::{
+    provider::HttpError,
+    run::emit_text_delta,
+    sse::{EventStream, is_event_stream},
+};
+use crate::model::app::ReasoningEffort;
+
+pub const API_BASE: &str = "https://api.openai.com/v1";
+pub const DEEPSEEK_API_BASE: &str = "https://api.deepseek.com";
+pub const XAI_API_BASE: &str = "https://api.x.ai/v1";
+
+/// The request differences between the OpenAI compatible APIs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    OpenAI,
+    /// Limits the output by `max_tokens` and supports JSON objects only as
+    /// the response format.
+    DeepSeek,
+}
+
+/// Streaming chat completions client for OpenAI and the providers with an
+/// OpenAI compatible API.
+#[derive(Clone)]
+pub struct Client {
+    endpoint: String,
+    api_key: String,
+    dialect: Dialect,
+    http: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(api_key: &str, endpoint: Option<String>) -> Self {
+        let endpoint = endpoint
+            .filter(|endpoint| !endpoint.is_empty())
+            .unwrap_or_else(|| API_BASE.to_string());
+        Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            dialect: Dialect::OpenAI,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn with_client(self, http: reqwest::Client) -> Self {
+        Self { http, ..self }
+    }
+
+    pub fn with_dialect(self, dialect: Dialect) -> Self {
+        Self { dialect, ..self }
+    }
+
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel {
+            client: self.clone(),
+            model: model.to_string(),
+            top_p: None,
+            reasoning_effort: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CompletionModel {
+    client: Client,
+    model: String,
+    top_p: Option<f64>,
+    reasoning_effort: Option<ReasoningEffort>,
+}
+
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let model = self.clone();
+        Box::pin(async move { model.completion(req).await })
+    }
+}
+
+impl CompletionModel {
+    pub fn with_top_p(self, top_p: Option<f64>) -> Self {
+        Self { top_p, ..self }
+    }
+
+    /// Sets the reasoning effort of reasoning models. DeepSeek does not
+    /// support it.
+    pub fn with_reasoning_effort(self, reasoning_effort: Option<ReasoningEffort>) -> Self {
+        Self {
+            reasoning_effort,
+            ..self
+        }
+    }
+
+    async fn completion(&self, mut req: CompletionRequest) -> Result<AgentOutput, BoxError> {
+        let timestamp = unix_ms();
+        let mut raw_history: Vec<Json> = Vec::new();
+        let mut chat_history: Vec<Message> = Vec::new();
+
+        if !req.instructions.is_empty() {
+            raw_history.push(json!({"role": "system", "content": req.instructions}));
+        }
+        raw_history.append(&mut req.raw_history);
+        // the messages before are already in the history of the run
+        let skip_raw = raw_history.len();
+
+        for msg in &req.chat_history {
+            raw_history.extend(to_messages(msg));
+        }
+        if let Some(mut msg) = req
+            .documents
+            .to_message(&rfc3339_datetime(timestamp).unwrap_or_default())
+        {
+            msg.timestamp = Some(timestamp);
+            raw_history.extend(to_messages(&msg));
+            chat_history.push(msg);
+        }
+        let mut content = std::mem::take(&mut req.content);
+        if !req.prompt.is_empty() {
+            content.push(ContentPart::Text {
+                text: std::mem::take(&mut req.prompt),
+            });
+        }
+        if !content.is_empty() {
+            let msg = Message {
+                role: req.role.clone().unwrap_or_else(|| "user".to_string()),
+                content,
+                timestamp: Some(timestamp),
+                ..Default::default()
+            };
+            raw_history.extend(to_messages(&msg));
+            chat_history.push(msg);
+        }
+
+        let mut body = json!({
+            "model": self.model,
+            "messages": &raw_history,
+            "stream": true,
+            "stream_options": {"include_usage": true},
+        });
+        if let Some(temperature) = req.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(effort) = self.reasoning_effort
+            && self.client.dialect == Dialect::OpenAI
+        {
+            body["reasoning_effort"] = json!(effort);
+        }
+        if let Some(max_tokens) = req.max_output_tokens {
+            match self.client.dialect {
+                Dialect::OpenAI => body["max_completion_tokens"] = json!(max_tokens),
+                Dialect::DeepSeek => body["max_tokens"] = json!(max_tokens),
+            }
+        }
+        if let Some(output_schema) = &req.output_schema {
+            body["response_format"] = match self.client.dialect {
+                Dialect::OpenAI => json!({"type": "json_schema", "json_schema": output_schema}),
+                Dialect::DeepSeek => json!({"type": "json_object"}),
+            };
+        }
+        if let Some(stop) = &req.stop {
+            body["stop"] = json!(stop);
+        }
+        if !req.tools.is_empty() {
+            body["tools"] = req
+                .tools
+                .iter()
+                .map(|tool| json!({"type": "function", "function": tool}))
+                .collect();
+            body["tool_choice"] = json!(if req.tool_choice_required {
+                "required"
+            } else {
+                "auto"
+            });
+        }
+
+        let res = self
+            .client
+            .http
+            .post(format!("{}/chat/completions", self.client.endpoint))
+            .bearer_auth(&self.client.api_key)
+            .json(&body)
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            let text = res.text().await.unwrap_or_default();
+            return Err(HttpError {
+                status: status.as_u16(),
+                message: format!("{} completions error: {}", self.model, text),
+            }
+            .into());
+        }
+
+        let mut reply = Reply::default();
+        if is_event_stream(&res) {
+            let mut events = EventStream::new(res);
+            while let Some(event) = events.next().await? {
+                if event.data == "[DONE]" {
+                    break;
+                }
+                let chunk: Json = serde_json::from_str(&event.data)?;
+                reply.add(&chunk).map_err(|err| self.error(err))?;
+            }
+        } else {
+            // a server may answer with the whole response
+            let res: Json = res.json().await?;
+            reply.add(&res).map_err(|err| self.error(err))?;
+        }
+
+        raw_history.drain(..skip_raw);
+        Ok(reply.into_output(&self.model, raw_history, chat_history))
+    }
+
+    fn error(&self, err: String) -> BoxError {
+        format!("{} completions error: {}", self.model, err).into()
+    }
+}
+
+/// The assistant message of a completion, collected from the deltas of the
+/// streamed chunks or from a whole response.
+#[derive(Debug, Default)]
+struct Reply {
+    content: String,
+    reasoning: String,
+    refusal: String,
+    tool_calls: Vec<CallDelta>, // by index
+    finish_reason: Option<String>,
+    usage: Usage,
+}
+
+#[derive(Debug, Default)]
+struct CallDelta {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+impl Reply {
+    /// Adds a streamed chunk, or a whole response, and emits its text.
+    fn add(&mut self, chunk: &Json) -> Result<(), String> {
+        if let Some(err) = chunk.get("error")
+            && !err.is_null()
+        {
+            return Err(err.to_string());
+        }
+        if let Some(usage) = chunk.get("usage")
+            && !usage.is_null()
+        {
+            self.usage = Usage {
+                input_tokens: usage["prompt_tokens"].as_u64().unwrap_or_default(),
+                output_tokens: usage["completion_tokens"].as_u64().unwrap_or_default(),
+                requests: 1,
+            };
+        }
+
+        let choice = &chunk["choices"][0];
+        if let Some(reason) = choice["finish_reason"].as_str() {
+            self.finish_reason = Some(reason.to_string());
+        }
+        let delta = match choice.get("delta") {
+            Some(delta) => delta,
+            None => &choice["message"],
+        };
+        if let Some(text) = delta["content"].as_str() {
+            emit_text_delta(text);
+            self.content.push_str(text);
+        }
+        if let Some(text) = delta["reasoning_content"].as_str() {
+            self.reasoning.push_str(text);
+        }
+        if let Some(text) = delta["refusal"].as_str() {
+            self.refusal.push_str(text);
+        }
+        for (i, call) in delta["tool_calls"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            let index = call["index"].as_u64().map_or(i, |index| index as usize);
+            if self.tool_calls.len() <= index {
+                self.tool_calls.resize_with(index + 1, CallDelta::default);
+            }
+            let collected = &mut self.tool_calls[index];
+            if let Some(id) = call["id"].as_str().filter(|id| !id.is_empty()) {
+                collected.id = id.to_string();
+            }
+            if let Some(name) = call["function"]["name"].as_str().filter(|n| !n.is_empty()) {
+                collected.name = name.to_string();
+            }
+            if let Some(arguments) = call["function"]["arguments"].as_str() {
+                collected.arguments.push_str(arguments);
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the assistant message to the histories of the round.
+    fn into_output(
+        self,
+        model: &str,
+        mut raw_history: Vec<Json>,
+        mut chat_history: Vec<Message>,
+    ) -> AgentOutput {
+        let mut output = AgentOutput {
+            usage: self.usage,
+            ..Default::default()
+        };
+        match self.finish_reason.as_deref() {
+            Some("stop") | Some("tool_calls") => {}
+            Some(reason) => {
+                output.failed_reason = Some(format!("{} stopped with reason: {}", model, reason));
+            }
+            None => {
+                output.failed_reason = Some(format!("{} stopped without a reason", model));
+            }
+        }
+        if !self.refusal.is_empty() {
+            output.failed_reason = Some(self.refusal);
+        }
+
+        let mut parts: Vec<ContentPart> = Vec::new();
+        if !self.reasoning.is_empty() {
+            parts.push(ContentPart::Reasoning {
+                text: self.reasoning,
+            });
+        }
+        if !self.content.is_empty() {
+            parts.push(ContentPart::Text {
+                text: self.content.clone(),
+            });
+        }
+        let mut raw_calls: Vec<Json> = Vec::with_capacity(self.tool_calls.len());
+        for call in self.tool_calls {
+            let args: Json = if call.arguments.trim().is_empty() {
+                json!({})
+            } else {
+                serde_json::from_str(&call.arguments).unwrap_or_default()
+            };
+            let call_id = Some(call.id.clone()).filter(|id| !id.is_empty());
+            raw_calls.push(json!({
+                "id": call.id,
+                "type": "function",
+                "function": {"name": call.name, "arguments": call.arguments},
+            }));
+            output.tool_calls.push(ToolCall {
+                name: call.name.clone(),
+                args: args.clone(),
+                result: None,
+                call_id: call_id.clone(),
+                remote_id: None,
+            });
+            parts.push(ContentPart::ToolCall {
+                name: call.name,
+                args,
+                call_id,
+            });
+        }
+
+        // the reasoning is not sent back, the APIs do not accept it
+        let mut raw = json!({
+            "role": "assistant",
+            "content": if self.content.is_empty() { Json::Null } else { json!(self.content) },
+        });
+        if !raw_calls.is_empty() {
+            raw["tool_calls"] = json!(raw_calls);
+        }
+        raw_history.push(raw);
+        chat_history.push(Message {
+            role: "assistant".to_string(),
+            content: parts,
+            timestamp: Some(unix_ms()),
+            ..Default::default()
+        });
+        output.content = self.content;
+        output.raw_history = raw_history;
+        output.chat_history = chat_history;
+        output
+    }
+}
+
+/// Maps a message to chat messages, one per content part. Reasoning is left
+/// out, the APIs do not accept it back.
+fn to_messages(msg: &Message) -> Vec<Json> {
+    let role = msg.role.as_str();
+    msg.content
+        .iter()
+        .filter_map(|part| match part {
+            ContentPart::Text { text } => Some(json!({"role": role, "content": text})),
+            ContentPart::Reasoning { .. } => None,
+            ContentPart::ToolOutput {
+                output, call_id, ..
+            } => Some(json!({
+                "role": "tool",
+                "content": match output {
+                    Json::String(text) => text.clone(),
+                    other => other.to_string(),
+                },
+                "tool_call_id": call_id,
+            })),
+            ContentPart::ToolCall {
+                name,
+                args,
+                call_id,
+            } => Some(json!({
+                "role": "assistant",
+                "content": Json::Null,
+                "tool_calls": [{
+                    "id": call_id,
+                    "type": "function",
+                    "function": {"name": name, "arguments": args.to_string()},
+                }],
+            })),
+            ContentPart::InlineData { mime_type, data } if mime_type.starts_with("image/") => {
+                Some(json!({"role": role, "content": [{
+                    "type": "image_url",
+                    "image_url": {"url": format!("data:{};base64,{}", mime_type, data)},
+                }]}))
+            }
+            ContentPart::FileData {
+                file_uri,
+                mime_type: Some(mime_type),
+            } if mime_type.starts_with("image/") => Some(json!({"role": role, "content": [{
+                "type": "image_url",
+                "image_url": {"url": file_uri},
+            }]})),
+            other => Some(json!({
+                "role": role,
+                "content": serde_json::to_string(other).unwrap_or_default(),
+            })),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::{provider::ProviderErrorKind, test_server::StandIn};
+
+    fn events(chunks: &[Json]) -> String {
+        let mut body: String = chunks
+            .iter()
+            .map(|chunk| format!("data: {}\n\n", chunk))
+            .collect();
+        body.push_str("data: [DONE]\n\n");
+        body
+    }
+
+    #[tokio::test]
+    async fn collects_streamed_deltas() {
+        let server = StandIn::start(vec![(
+            200,
+            events(&[
+                json!({"choices": [{"index": 0, "delta": {"role": "assistant", "content": "Let me "}}]}),
+                json!({"choices": [{"index": 0, "delta": {"content": "check."}}]}),
+                json!({"choices": [{"index": 0, "delta": {"tool_calls": [
+                    {"index": 0, "id": "call_1", "type": "function", "function": {"name": "weather", "arguments": "{\"city\""}},
+                ]}}]}),
+                json!({"choices": [{"index": 0, "delta": {"tool_calls": [
+                    {"index": 0, "function": {"arguments": ": \"Paris\"}"}},
+                ]}, "finish_reason": "tool_calls"}]}),
+                json!({"choices": [], "usage": {"prompt_tokens": 12, "completion_tokens": 7}}),
+            ]),
+        )])
+        .await;
+        let model = Client::new("test-key", Some(server.url.clone()))
+            .with_dialect(Dialect::DeepSeek)
+            .completion_model("test-model")
+            .with_top_p(Some(0.9))
+            .with_reasoning_effort(Some(ReasoningEffort::High));
+
+        let output = model
+            .completion(CompletionRequest {
+                instructions: "Be brief.".to_string(),
+                raw_history: vec![
+                    json!({"role": "user", "content": "Hi"}),
+                    json!({"role": "assistant", "content": "Hello"}),
+                ],
+                prompt: "Weather in Paris?".to_string(),
+                max_output_tokens: Some(64),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(output.content, "Let me check.");
+        assert_eq!(output.failed_reason, None);
+        assert_eq!(output.usage.input_tokens, 12);
+        assert_eq!(output.usage.output_tokens, 7);
+        assert_eq!(output.tool_calls[0].name, "weather");
+        assert_eq!(output.tool_calls[0].args, json!({"city": "Paris"}));
+        assert_eq!(output.tool_calls[0].call_id.as_deref(), Some("call_1"));
+        // the raw history of the round follows the history of the run
+        assert_eq!(
+            output.raw_history,
+            vec![
+                json!({"role": "user", "content": "Weather in Paris?"}),
+                json!({"role": "assistant", "content": "Let me check.", "tool_calls": [
+                    {"id": "call_1", "type": "function", "function": {"name": "weather", "arguments": "{\"city\": \"Paris\"}"}},
+                ]}),
+            ]
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/chat/completions");
+        assert_eq!(requests[0].header("authorization"), Some("Bearer test-key"));
+        let body = requests[0].json();
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["max_tokens"], 64);
+        assert_eq!(body["top_p"], 0.9);
+        assert_eq!(body.get("reasoning_effort"), None);
+        assert_eq!(
+            body["messages"][0],
+            json!({"role": "system", "content": "Be brief."})
+        );
+        assert_eq!(body["messages"].as_array().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_streamed_error() {
+        let server = StandIn::start(vec![(
+            200,
+            events(&[
+                json!({"choices": [{"index": 0, "delta": {"content": "Hel"}}]}),
+                json!({"error": {"message": "The server is overloaded"}}),
+            ]),
+        )])
+        .await;
+        let model = Client::new("test-key", Some(server.url.clone()))
+            .completion_model("test-model")
+            .with_reasoning_effort(Some(ReasoningEffort::Medium));
+
+        let err = model
+            .completion(CompletionRequest {
+                prompt: "Hi".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("overloaded"), "{err}");
+        assert_eq!(ProviderErrorKind::of(&err), ProviderErrorKind::Server);
+        assert_eq!(server.requests()[0].json()["reasoning_effort"], "medium");
+    }
+}
@@ -0,0 +1,687 @@
+use anda_core::{AgentOutput, BoxError, BoxPinFut, CompletionRequest};
+use anda_engine::model::{CompletionFeaturesDyn, Proxy, request_client_builder};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::{
+    anthropic, gemini,
+    openai::{self, Dialect},
+    run::RunContext,
+};
+use crate::model::app::{ModelProvider, ProviderKind};
+
+/// Coarse classification of model provider errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderErrorKind {
+    Auth,
+    Quota,
+    RateLimit,
+    UnknownModel,
+    Network,
+    Server,
+    /// The provider settings can not be used to build a client.
+    Config,
+    Unknown,
+}
+
+impl ProviderErrorKind {
+    /// Classifies an error returned by a completion model by its HTTP status,
+    /// and by its message when there is no status or the status is ambiguous.
+    pub fn of(err: &BoxError) -> Self {
+        Self::classify(http_status(err.as_ref()), &err.to_string())
+    }
+
+    pub fn classify(status: Option<u16>, err: &str) -> Self {
+        let msg = err.to_ascii_lowercase();
+        if msg.contains("insufficient_quota")
+            || msg.contains("exceeded your current quota")
+            || msg.contains("insufficient balance")
+            || msg.contains("billing")
+        {
+            return ProviderErrorKind::Quota;
+        }
+        if msg.contains("model_not_found")
+            || msg.contains("model not found")
+            || (msg.contains("model") && msg.contains("does not exist"))
+        {
+            return ProviderErrorKind::UnknownModel;
+        }
+
+        match status {
+            Some(401) | Some(403) => return ProviderErrorKind::Auth,
+            Some(402) => return ProviderErrorKind::Quota,
+            Some(404) => return ProviderErrorKind::UnknownModel,
+            Some(429) => return ProviderErrorKind::RateLimit,
+            Some(code) if code >= 500 => return ProviderErrorKind::Server,
+            _ => {}
+        }
+
+        if msg.contains("unauthorized")
+            || msg.contains("invalid api key")
+            || msg.contains("incorrect api key")
+            || msg.contains("invalid_api_key")
+            || msg.contains("permission denied")
+        {
+            ProviderErrorKind::Auth
+        } else if msg.contains("rate limit")
+            || msg.contains("rate_limit")
+            || msg.contains("too many requests")
+            || msg.contains("resource_exhausted")
+        {
+            ProviderErrorKind::RateLimit
+        } else if msg.contains("internal server error")
+            || msg.contains("service unavailable")
+            || msg.contains("overloaded")
+            || msg.contains("bad gateway")
+        {
+            ProviderErrorKind::Server
+        } else if msg.contains("error sending request")
+            || msg.contains("connection")
+            || msg.contains("timed out")
+            || msg.contains("dns error")
+            || msg.contains("proxy")
+        {
+            ProviderErrorKind::Network
+        } else {
+            ProviderErrorKind::Unknown
+        }
+    }
+
+    pub fn should_fallback(&self) -> bool {
+        matches!(
+            self,
+            ProviderErrorKind::Auth
+                | ProviderErrorKind::Quota
+                | ProviderErrorKind::RateLimit
+                | ProviderErrorKind::Server
+        )
+    }
+}
+
+/// An error response of a model provider.
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP status {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// The completion models of anda_engine report a failed response as
+/// `completions request failed: {status}, body: {body}`.
+const REQUEST_FAILED: &str = "completions request failed: ";
+
+/// Returns the HTTP status of a failed completion, from an [`HttpError`], a
+/// reqwest error or the error of an anda_engine completion model.
+fn http_status(err: &(dyn std::error::Error + 'static)) -> Option<u16> {
+    if let Some(err) = err.downcast_ref::<HttpError>() {
+        return Some(err.status);
+    }
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        return err.status().map(|status| status.as_u16());
+    }
+
+    let msg = err.to_string();
+    let (_, rest) = msg.split_once(REQUEST_FAILED)?;
+    rest.get(..3)
+        .and_then(|code| code.parse::<u16>().ok())
+        .filter(|code| (100..600).contains(code))
+}
+
+/// Builds the HTTP client shared by the model providers.
+pub fn http_client(https_proxy: Option<String>) -> Result<reqwest::Client, BoxError> {
+    let mut http_client = request_client_builder();
+    if let Some(proxy) = https_proxy {
+        http_client = http_client.proxy(Proxy::all(proxy)?);
+    }
+    Ok(http_client.build()?)
+}
+
+/// Builds the HTTP client for model servers on the local machine. They are
+/// reached directly over plain HTTP, without the https proxy.
+pub fn local_http_client() -> Result<reqwest::Client, BoxError> {
+    let http_client = reqwest::Client::builder()
+        .no_proxy()
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(300))
+        .build()?;
+    Ok(http_client)
+}
+
+pub const OLLAMA_API_BASE: &str = "http://127.0.0.1:11434";
+
+/// The root URL of an Ollama server, without the `/v1` path of its OpenAI
+/// compatible API.
+fn ollama_root(api_base: Option<&str>) -> &str {
+    let api_base = api_base.unwrap_or(OLLAMA_API_BASE).trim_end_matches('/');
+    api_base.strip_suffix("/v1").unwrap_or(api_base)
+}
+
+/// A model installed on a local Ollama server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LocalModel {
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub modified_at: String,
+}
+
+#[derive(Deserialize)]
+struct LocalModels {
+    #[serde(default)]
+    models: Vec<LocalModel>,
+}
+
+/// Lists the models installed on a local Ollama server.
+pub async fn list_local_models(
+    http_client: &reqwest::Client,
+    api_base: Option<&str>,
+) -> Result<Vec<LocalModel>, BoxError> {
+    let api_base = ollama_root(api_base);
+    let res = http_client
+        .get(format!("{}/api/tags", api_base))
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(format!(
+            "Failed to list local models from {}: {}",
+            api_base,
+            res.status()
+        )
+        .into());
+    }
+
+    let res: LocalModels = res.json().await?;
+    Ok(res.models)
+}
+
+pub fn build_completer(
+    name: &str,
+    provider: &ModelProvider,
+    http_client: reqwest::Client,
+) -> Result<Arc<dyn CompletionFeaturesDyn>, BoxError> {
+    if provider.model.is_empty() {
+        return Err(format!("Model is missing for model provider: {}", name).into());
+    }
+    let completer: Arc<dyn CompletionFeaturesDyn> = match provider.kind {
+        ProviderKind::Gemini => Arc::new(
+            gemini::Client::new(&provider.api_key, provider.api_base.clone())
+                .with_client(http_client)
+                .completion_model(&provider.model)
+                .with_top_p(provider.top_p)
+                .with_reasoning_effort(provider.reasoning_effort),
+        ),
+        ProviderKind::Deepseek => {
+            let api_base = provider.api_base.clone().filter(|v| !v.is_empty());
+            Arc::new(
+                openai::Client::new(
+                    &provider.api_key,
+                    Some(api_base.unwrap_or_else(|| openai::DEEPSEEK_API_BASE.to_string())),
+                )
+                .with_client(http_client)
+                .with_dialect(Dialect::DeepSeek)
+                .completion_model(&provider.model)
+                .with_top_p(provider.top_p),
+            )
+        }
+        ProviderKind::Xai => {
+            let api_base = provider.api_base.clone().filter(|v| !v.is_empty());
+            Arc::new(
+                openai::Client::new(
+                    &provider.api_key,
+                    Some(api_base.unwrap_or_else(|| openai::XAI_API_BASE.to_string())),
+                )
+                .with_client(http_client)
+                .completion_model(&provider.model)
+                .with_top_p(provider.top_p)
+                .with_reasoning_effort(provider.reasoning_effort),
+            )
+        }
+        ProviderKind::OpenaiCompatible => Arc::new(
+            openai::Client::new(&provider.api_key, provider.api_base.clone())
+                .with_client(http_client)
+                .completion_model(&provider.model)
+                .with_top_p(provider.top_p)
+                .with_reasoning_effort(provider.reasoning_effort),
+        ),
+        ProviderKind::Anthropic => Arc::new(
+            anthropic::Client::new(&provider.api_key, provider.api_base.clone())
+                .with_client(http_client)
+                .completion_model(&provider.model)
+                .with_top_p(provider.top_p)
+                .with_reasoning_effort(provider.reasoning_effort),
+        ),
+        // Ollama serves an OpenAI compatible API under `/v1`
+        ProviderKind::Ollama => {
+            let api_base = format!("{}/v1", ollama_root(provider.api_base.as_deref()));
+            Arc::new(
+                openai::Client::new(&provider.api_key, Some(api_base))
+                    .with_client(local_http_client()?)
+                    .completion_model(&provider.model)
+                    .with_top_p(provider.top_p)
+                    .with_reasoning_effort(provider.reasoning_effort),
+            )
+        }
+    };
+
+    if provider.temperature.is_some() || provider.max_output_tokens.is_some() {
+        return Ok(Arc::new(TunedCompleter {
+            inner: completer,
+            temperature: provider.temperature,
+            max_output_tokens: provider.max_output_tokens,
+        }));
+    }
+    Ok(completer)
+}
+
+/// Applies the temperature and the max output tokens of a provider to every
+/// request. A smaller max output tokens of the request is kept.
+pub struct TunedCompleter {
+    inner: Arc<dyn CompletionFeaturesDyn>,
+    temperature: Option<f64>,
+    max_output_tokens: Option<usize>,
+}
+
+impl CompletionFeaturesDyn for TunedCompleter {
+    fn completion(&self, mut req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        if let Some(temperature) = self.temperature {
+            req.temperature = Some(temperature);
+        }
+        if let Some(max) = self.max_output_tokens {
+            req.max_output_tokens = Some(req.max_output_tokens.map_or(max, |v| v.min(max)));
+        }
+        self.inner.completion(req)
+    }
+}
+
+/// The outcome of a provider connection test.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProviderTestResult {
+    pub ok: bool,
+    pub latency_ms: u64,
+    pub error: Option<ProviderTestError>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ProviderTestError {
+    pub kind: ProviderErrorKind,
+    pub message: String,
+}
+
+/// Sends a minimal completion request to check a provider's credentials.
+pub async fn test_provider(
+    name: &str,
+    provider: &ModelProvider,
+    http_client: reqwest::Client,
+) -> ProviderTestResult {
+    let completer = match build_completer(name, provider, http_client) {
+        Ok(completer) => completer,
+        Err(err) => {
+            return ProviderTestResult {
+                ok: false,
+                latency_ms: 0,
+                error: Some(ProviderTestError {
+                    kind: ProviderErrorKind::Config,
+                    message: err.to_string(),
+                }),
+            };
+        }
+    };
+
+    let req = CompletionRequest {
+        prompt: "Reply with OK.".to_string(),
+        max_output_tokens: Some(16),
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let res = tokio::time::timeout(Duration::from_secs(30), completer.completion(req)).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+
+    let err = match res {
+        Ok(Ok(output)) => match output.failed_reason {
+            None => {
+                return ProviderTestResult {
+                    ok: true,
+                    latency_ms,
+                    error: None,
+                };
+            }
+            Some(reason) => ProviderTestError {
+                kind: ProviderErrorKind::classify(None, &reason),
+                message: reason,
+            },
+        },
+        Ok(Err(err)) => ProviderTestError {
+            kind: ProviderErrorKind::of(&err),
+            message: err.to_string(),
+        },
+        Err(_) => ProviderTestError {
+            kind: ProviderErrorKind::Network,
+            message: format!("Model provider {} timed out", name),
+        },
+    };
+
+    log::warn!(
+        "Model provider {} test failed with {:?} error: {}",
+        name,
+        err.kind,
+        err.message
+    );
+    ProviderTestResult {
+        ok: false,
+        latency_ms,
+        error: Some(err),
+    }
+}
+
+/// Tries the configured providers in order, moving on to the next one when a
+/// provider fails with an auth, quota, rate limit or server error.
+pub struct FallbackCompleter {
+    providers: Arc<Vec<ProviderCompleter>>,
+}
+
+/// The completion model of a configured provider.
+pub struct ProviderCompleter {
+    pub name: String,
+    pub model: String,
+    pub completer: Arc<dyn CompletionFeaturesDyn>,
+}
+
+impl FallbackCompleter {
+    pub fn new(providers: Vec<ProviderCompleter>) -> Self {
+        Self {
+            providers: Arc::new(providers),
+        }
+    }
+}
+
+impl CompletionFeaturesDyn for FallbackCompleter {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let providers = self.providers.clone();
+        let ctx = RunContext::current();
+
+        Box::pin(async move {
+            let mut last_err: Option<BoxError> = None;
+            for (i, provider) in providers.iter().enumerate() {
+                let name = &provider.name;
+                if let Some(ctx) = &ctx
+                    && ctx.is_exhausted(name)
+                {
+                    log::warn!("Model provider {} skipped: token budget exceeded", name);
+                    continue;
+                }
+
+                match provider.completer.completion(req.clone()).await {
+                    Ok(output) => {
+                        if i > 0 {
+                            log::warn!(
+                                "Completion served by fallback provider {} with model: {}",
+                                name,
+                                provider.model
+                            );
+                        }
+                        if let Some(ctx) = &ctx {
+                            ctx.set_provider(name);
+                            ctx.add_usage(name, &provider.model, &output.usage);
+                        }
+                        return Ok(output);
+                    }
+                    Err(err) => {
+                        let kind = ProviderErrorKind::of(&err);
+                        if !kind.should_fallback() || i + 1 == providers.len() {
+                            return Err(err);
+                        }
+                        log::warn!(
+                            "Model provider {} failed with {:?} error, trying the next one: {}",
+                            name,
+                            kind,
+                            err
+                        );
+                        last_err = Some(err);
+                    }
+                }
+            }
+
+            Err(last_err.unwrap_or_else(|| "No model provider available".into()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_server::StandIn;
+    use serde_json::json;
+
+    fn provider(kind: &str, api_base: &str) -> ModelProvider {
+        serde_json::from_value(json!({
+            "kind": kind,
+            "model": "test-model",
+            "api_key": "test-key",
+            "api_base": api_base,
+        }))
+        .unwrap()
+    }
+
+    fn chat_completion(text: &str) -> String {
+        json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "test-model",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": text},
+                "finish_reason": "stop",
+            }],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6},
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn classifies_by_status() {
+        let cases = [
+            (401, ProviderErrorKind::Auth),
+            (403, ProviderErrorKind::Auth),
+            (402, ProviderErrorKind::Quota),
+            (404, ProviderErrorKind::UnknownModel),
+            (429, ProviderErrorKind::RateLimit),
+            (500, ProviderErrorKind::Server),
+            (503, ProviderErrorKind::Server),
+            (400, ProviderErrorKind::Unknown),
+        ];
+        for (status, kind) in cases {
+            assert_eq!(
+                ProviderErrorKind::classify(Some(status), "request failed"),
+                kind,
+                "status {status}"
+            );
+        }
+    }
+
+    #[test]
+    fn classifies_by_message() {
+        let cases = [
+            (
+                "You exceeded your current quota, please check your plan",
+                ProviderErrorKind::Quota,
+            ),
+            (
+                "The model `gpt-9` does not exist",
+                ProviderErrorKind::UnknownModel,
+            ),
+            ("Invalid API key provided", ProviderErrorKind::Auth),
+            (
+                "Incorrect API key provided: sk-***",
+                ProviderErrorKind::Auth,
+            ),
+            (
+                "Rate limit reached for requests",
+                ProviderErrorKind::RateLimit,
+            ),
+            ("Service Unavailable", ProviderErrorKind::Server),
+            (
+                "error sending request for url (https://api.openai.com)",
+                ProviderErrorKind::Network,
+            ),
+            ("something else", ProviderErrorKind::Unknown),
+        ];
+        for (msg, kind) in cases {
+            assert_eq!(ProviderErrorKind::classify(None, msg), kind, "{msg}");
+        }
+
+        // a quota error wins over its status
+        assert_eq!(
+            ProviderErrorKind::classify(Some(429), "insufficient_quota"),
+            ProviderErrorKind::Quota
+        );
+    }
+
+    #[test]
+    fn reads_http_status() {
+        let err: BoxError = HttpError {
+            status: 429,
+            message: "slow down".to_string(),
+        }
+        .into();
+        assert_eq!(http_status(err.as_ref()), Some(429));
+        assert_eq!(ProviderErrorKind::of(&err), ProviderErrorKind::RateLimit);
+
+        let err: BoxError =
+            "completions request failed: 401 Unauthorized, body: {\"error\":{}}".into();
+        assert_eq!(http_status(err.as_ref()), Some(401));
+        assert_eq!(ProviderErrorKind::of(&err), ProviderErrorKind::Auth);
+
+        // digits in the message are not a status
+        let err: BoxError = "context of 404 tokens is too long for model 500".into();
+        assert_eq!(http_status(err.as_ref()), None);
+        assert_eq!(ProviderErrorKind::of(&err), ProviderErrorKind::Unknown);
+    }
+
+    #[test]
+    fn strips_ollama_api_path() {
+        assert_eq!(ollama_root(None), OLLAMA_API_BASE);
+        assert_eq!(
+            ollama_root(Some("http://localhost:11434/")),
+            "http://localhost:11434"
+        );
+        assert_eq!(
+            ollama_root(Some("http://localhost:11434/v1")),
+            "http://localhost:11434"
+        );
+        assert_eq!(
+            ollama_root(Some("http://localhost:11434/v1/")),
+            "http://localhost:11434"
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_local_models() {
+        let server = StandIn::start(vec![(
+            200,
+            json!({"models": [
+                {"name": "llama3:8b", "size": 4661224676u64, "modified_at": "2025-01-01T00:00:00Z"},
+                {"name": "qwen3"},
+            ]})
+            .to_string(),
+        )])
+        .await;
+
+        let api_base = format!("{}/v1", server.url);
+        let models = list_local_models(&local_http_client().unwrap(), Some(&api_base))
+            .await
+            .unwrap();
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].name, "llama3:8b");
+        assert_eq!(models[0].size, 4661224676);
+        assert_eq!(models[1].name, "qwen3");
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(requests[0].path, "/api/tags");
+    }
+
+    #[tokio::test]
+    async fn fails_to_list_local_models() {
+        let server = StandIn::start(vec![(500, "{}".to_string())]).await;
+        let res = list_local_models(&local_http_client().unwrap(), Some(&server.url)).await;
+        assert!(res.unwrap_err().to_string().contains("500"));
+    }
+
+    #[tokio::test]
+    async fn tests_ollama_provider() {
+        let server = StandIn::start(vec![(200, chat_completion("OK"))]).await;
+        let provider = provider("ollama", &format!("{}/v1/", server.url));
+
+        let res = test_provider("ollama", &provider, reqwest::Client::new()).await;
+        assert!(res.ok, "{:?}", res.error);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1/chat/completions");
+        assert_eq!(requests[0].json()["model"], "test-model");
+    }
+
+    #[tokio::test]
+    async fn tests_provider_with_bad_key() {
+        let server = StandIn::start(vec![(
+            401,
+            json!({"error": {"message": "Incorrect API key provided"}}).to_string(),
+        )])
+        .await;
+        let provider = provider("ollama", &server.url);
+
+        let res = test_provider("ollama", &provider, reqwest::Client::new()).await;
+        assert!(!res.ok);
+        let err = res.error.unwrap();
+        assert_eq!(err.kind, ProviderErrorKind::Auth);
+    }
+
+    #[tokio::test]
+    async fn tests_provider_without_model() {
+        let mut provider = provider("ollama", "http://127.0.0.1:1");
+        provider.model.clear();
+
+        let res = test_provider("ollama", &provider, reqwest::Client::new()).await;
+        assert!(!res.ok);
+        assert_eq!(res.error.unwrap().kind, ProviderErrorKind::Config);
+    }
+
+    #[tokio::test]
+    async fn tests_anthropic_provider_rate_limit() {
+        let server = StandIn::start(vec![(
+            429,
+            json!({"type": "error", "error": {"type": "rate_limit_error"}}).to_string(),
+        )])
+        .await;
+        let provider = provider("anthropic", &server.url);
+
+        let res = test_provider("anthropic", &provider, reqwest::Client::new()).await;
+        let err = res.error.unwrap();
+        assert_eq!(err.kind, ProviderErrorKind::RateLimit);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/messages");
+        assert_eq!(requests[0].header("x-api-key"), Some("test-key"));
+    }
+
+    #[tokio::test]
+    async fn unreachable_provider_is_a_network_error() {
+        // nothing listens on the discard port of the loopback interface
+        let provider = provider("ollama", "http://127.0.0.1:9");
+        let res = test_provider("ollama", &provider, reqwest::Client::new()).await;
+        assert_eq!(res.error.unwrap().kind, ProviderErrorKind::Network);
+    }
+}
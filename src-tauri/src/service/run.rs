@@ -0,0 +1,448 @@
+use anda_core::{
+    AgentOutput, BoxError, BoxPinFut, CompletionRequest, ContentPart, Json, ToolCall, Usage,
+};
+use anda_engine::model::CompletionFeaturesDyn;
+use parking_lot::Mutex;
+use serde::{Serialize, Serializer, ser::SerializeStruct};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Weak},
+};
+use tauri::ipc::Channel;
+use tokio_util::sync::CancellationToken;
+
+use super::usage::BudgetStatus;
+
+/// Typed errors of agent runs and tool calls, serialized to the frontend as
+/// `{ name, message, ... }` objects.
+#[derive(Debug)]
+pub enum RunError {
+    Cancelled { run_id: String },
+    BudgetExceeded(BudgetStatus),
+}
+
+impl RunError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RunError::Cancelled { .. } => "Cancelled",
+            RunError::BudgetExceeded(_) => "BudgetExceeded",
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Cancelled { run_id } => write!(f, "run {run_id} was cancelled"),
+            RunError::BudgetExceeded(status) => write!(
+                f,
+                "token budget of model provider {} exceeded: {} of {} tokens used",
+                status.provider,
+                status.used_tokens,
+                status.hard_limit.unwrap_or_default()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+impl Serialize for RunError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("RunError", 3)?;
+        s.serialize_field("name", self.name())?;
+        s.serialize_field("message", &self.to_string())?;
+        match self {
+            RunError::Cancelled { run_id } => s.serialize_field("run_id", run_id)?,
+            RunError::BudgetExceeded(status) => s.serialize_field("budget", status)?,
+        }
+        s.end()
+    }
+}
+
+/// Events pushed to the frontend while an agent run is in progress.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RunEvent {
+    /// A piece of text of a completion round, as the model streams it. The
+    /// text of a round that failed over to another provider is not retracted.
+    TextDelta { text: String },
+    /// The model requested a tool call.
+    ToolCallStarted {
+        id: String,
+        name: String,
+        args: Json,
+    },
+    /// A requested tool call has finished.
+    ToolCallFinished {
+        id: String,
+        name: String,
+        output: Option<Json>,
+    },
+    /// The run completed with its final output.
+    Finished { output: RunOutput },
+    /// The run failed.
+    Failed { error: String },
+}
+
+/// The agent output together with the run details.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunOutput {
+    #[serde(flatten)]
+    pub output: AgentOutput,
+    pub run_id: String,
+    /// The model provider that served the last completion of the run.
+    pub provider: Option<String>,
+}
+
+tokio::task_local! {
+    static RUN_CONTEXT: Arc<RunContext>;
+}
+
+/// Per-run state shared with the completion models through a task local,
+/// so that the engine can be used unchanged.
+pub struct RunContext {
+    run_id: String,
+    cancel_token: CancellationToken,
+    events: Option<Channel<RunEvent>>,
+    pending_tools: Mutex<BTreeMap<String, String>>, // call id -> tool name
+    provider: Mutex<Option<String>>,
+    usage: Mutex<BTreeMap<(String, String), Usage>>, // (provider, model) -> usage
+    budgets: Mutex<BTreeMap<String, BudgetStatus>>,  // provider -> status at start
+    providers: Mutex<Vec<String>>,                   // providers of the run, in order
+}
+
+impl RunContext {
+    pub fn new(
+        run_id: String,
+        cancel_token: CancellationToken,
+        events: Option<Channel<RunEvent>>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            run_id,
+            cancel_token,
+            events,
+            pending_tools: Mutex::new(BTreeMap::new()),
+            provider: Mutex::new(None),
+            usage: Mutex::new(BTreeMap::new()),
+            budgets: Mutex::new(BTreeMap::new()),
+            providers: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn current() -> Option<Arc<RunContext>> {
+        RUN_CONTEXT.try_with(|ctx| ctx.clone()).ok()
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel_token.clone()
+    }
+
+    pub fn provider(&self) -> Option<String> {
+        self.provider.lock().clone()
+    }
+
+    pub fn set_provider(&self, name: &str) {
+        *self.provider.lock() = Some(name.to_string());
+    }
+
+    /// Accumulates the usage of one completion round served by `provider`.
+    pub fn add_usage(&self, provider: &str, model: &str, usage: &Usage) {
+        let mut all = self.usage.lock();
+        let total = all
+            .entry((provider.to_string(), model.to_string()))
+            .or_default();
+        total.input_tokens += usage.input_tokens;
+        total.output_tokens += usage.output_tokens;
+        total.requests += usage.requests;
+    }
+
+    pub fn take_usage(&self) -> BTreeMap<(String, String), Usage> {
+        std::mem::take(&mut *self.usage.lock())
+    }
+
+    pub fn budgets(&self) -> BTreeMap<String, BudgetStatus> {
+        self.budgets.lock().clone()
+    }
+
+    pub fn set_budgets(&self, providers: Vec<String>, budgets: BTreeMap<String, BudgetStatus>) {
+        *self.providers.lock() = providers;
+        *self.budgets.lock() = budgets;
+    }
+
+    /// Tokens used by the run so far on `provider`.
+    fn run_tokens(&self, provider: &str) -> u64 {
+        self.usage
+            .lock()
+            .iter()
+            .filter(|((name, _), _)| name == provider)
+            .map(|(_, u)| u.input_tokens + u.output_tokens)
+            .sum()
+    }
+
+    /// The budget status of the provider, counting the tokens used by the
+    /// run so far.
+    fn budget_status(&self, provider: &str) -> Option<BudgetStatus> {
+        let status = self.budgets.lock().get(provider).cloned()?;
+        Some(BudgetStatus {
+            used_tokens: status.used_tokens + self.run_tokens(provider),
+            ..status
+        })
+    }
+
+    /// Whether the provider reached its hard budget limit, counting the
+    /// tokens used by the run so far.
+    pub fn is_exhausted(&self, provider: &str) -> bool {
+        self.budget_status(provider)
+            .is_some_and(|status| status.is_exhausted())
+    }
+
+    /// The status of the first provider when every provider of the run has
+    /// reached its hard budget limit.
+    pub fn exhausted_budget(&self) -> Option<BudgetStatus> {
+        let providers = self.providers.lock().clone();
+        let mut statuses = Vec::with_capacity(providers.len());
+        for name in &providers {
+            let status = self.budget_status(name)?;
+            if !status.is_exhausted() {
+                return None;
+            }
+            statuses.push(status);
+        }
+        statuses.into_iter().next()
+    }
+
+    /// Runs `fut` with this context as the task local. The future is dropped
+    /// when the run is cancelled, which aborts pending model requests and
+    /// tool calls.
+    pub async fn run<F, T>(self: Arc<Self>, fut: F) -> Result<T, BoxError>
+    where
+        F: Future<Output = Result<T, BoxError>>,
+    {
+        let cancel_token = self.cancel_token.clone();
+        let run_id = self.run_id.clone();
+        match cancel_token
+            .run_until_cancelled(RUN_CONTEXT.scope(self, fut))
+            .await
+        {
+            Some(res) => res,
+            None => {
+                log::info!("Run {run_id} cancelled");
+                Err(RunError::Cancelled { run_id }.into())
+            }
+        }
+    }
+
+    pub fn emit(&self, event: RunEvent) {
+        if let Some(events) = &self.events
+            && let Err(err) = events.send(event)
+        {
+            log::warn!("Failed to send run event: {err}");
+        }
+    }
+
+    /// The agent output together with the run details so far.
+    pub fn output(&self, output: AgentOutput) -> RunOutput {
+        RunOutput {
+            output,
+            run_id: self.run_id.clone(),
+            provider: self.provider(),
+        }
+    }
+
+    /// Emits the remaining tool call results and the final output.
+    pub fn finish(&self, output: AgentOutput) -> RunOutput {
+        let pending = std::mem::take(&mut *self.pending_tools.lock());
+        for call in &output.tool_calls {
+            let id = call_id(call);
+            if pending.contains_key(&id) {
+                self.emit(RunEvent::ToolCallFinished {
+                    id,
+                    name: call.name.clone(),
+                    output: call.result.as_ref().map(|v| json!(v)),
+                });
+            }
+        }
+
+        let output = self.output(output);
+        log::info!(
+            "Run {} finished with provider {:?}",
+            output.run_id,
+            output.provider
+        );
+        self.emit(RunEvent::Finished {
+            output: output.clone(),
+        });
+        output
+    }
+
+    fn on_request(&self, req: &CompletionRequest) {
+        let mut pending = self.pending_tools.lock();
+        if pending.is_empty() {
+            return;
+        }
+
+        let parts = req
+            .chat_history
+            .iter()
+            .flat_map(|msg| msg.content.iter())
+            .chain(req.content.iter());
+        for part in parts {
+            if let ContentPart::ToolOutput {
+                name,
+                output,
+                call_id: Some(id),
+                ..
+            } = part
+                && pending.remove(id).is_some()
+            {
+                self.emit(RunEvent::ToolCallFinished {
+                    id: id.clone(),
+                    name: name.clone(),
+                    output: Some(output.clone()),
+                });
+            }
+        }
+    }
+
+    fn on_output(&self, output: &AgentOutput) {
+        let mut pending = self.pending_tools.lock();
+        for call in &output.tool_calls {
+            let id = call_id(call);
+            pending.insert(id.clone(), call.name.clone());
+            self.emit(RunEvent::ToolCallStarted {
+                id,
+                name: call.name.clone(),
+                args: json!(call.args),
+            });
+        }
+    }
+}
+
+/// Emits a piece of streamed text to the run of the current task, if any.
+pub fn emit_text_delta(text: &str) {
+    if !text.is_empty()
+        && let Some(ctx) = RunContext::current()
+    {
+        ctx.emit(RunEvent::TextDelta {
+            text: text.to_string(),
+        });
+    }
+}
+
+/// Hands the run context to the completion rounds of agent runs. The
+/// assistant runs the rounds of an agent run on a task of its own, outside
+/// of the task local of the run, so the first round is matched to its run
+/// by the prompt and every later round by the last raw message of the round
+/// before it.
+#[derive(Default)]
+pub struct RunRounds {
+    waiting: Mutex<Vec<(RoundKey, Weak<RunContext>)>>,
+}
+
+#[derive(PartialEq)]
+enum RoundKey {
+    Prompt(String),
+    After(Json), // the last raw message of the previous round
+}
+
+impl RunRounds {
+    /// Expects the first round of an agent run with the prompt.
+    pub fn expect(&self, ctx: &Arc<RunContext>, prompt: &str) {
+        self.insert(RoundKey::Prompt(prompt.to_string()), ctx);
+    }
+
+    fn insert(&self, key: RoundKey, ctx: &Arc<RunContext>) {
+        let mut waiting = self.waiting.lock();
+        // runs that ended without all their rounds
+        waiting.retain(|(_, ctx)| ctx.strong_count() > 0);
+        waiting.push((key, Arc::downgrade(ctx)));
+    }
+
+    /// Takes the run context waiting for the completion round, if any.
+    fn claim(&self, req: &CompletionRequest) -> Option<Arc<RunContext>> {
+        let key = match req.raw_history.last() {
+            Some(last) => RoundKey::After(last.clone()),
+            None => RoundKey::Prompt(req.prompt.clone()),
+        };
+        let mut waiting = self.waiting.lock();
+        let i = waiting.iter().position(|(k, _)| k == &key)?;
+        waiting.remove(i).1.upgrade()
+    }
+
+    /// Expects the round after `output` when the agent goes on with the
+    /// results of its tool calls.
+    fn follow(&self, ctx: &Arc<RunContext>, output: &AgentOutput) {
+        if !output.tool_calls.is_empty()
+            && let Some(last) = output.raw_history.last()
+        {
+            self.insert(RoundKey::After(last.clone()), ctx);
+        }
+    }
+}
+
+/// The id of a tool call as reported to the frontend. The completion models
+/// always set the call id, the tool name is only a fallback.
+fn call_id(call: &ToolCall) -> String {
+    call.call_id.clone().unwrap_or_else(|| call.name.clone())
+}
+
+/// Wraps a provider's completion model and reports every completion round
+/// to the [`RunContext`] of its run, if any. The round runs in the context of
+/// its run, so that the providers see it and a cancelled run stops it.
+pub struct ObservedCompleter {
+    inner: Arc<dyn CompletionFeaturesDyn>,
+    rounds: Arc<RunRounds>,
+}
+
+impl ObservedCompleter {
+    pub fn new(inner: Arc<dyn CompletionFeaturesDyn>, rounds: Arc<RunRounds>) -> Self {
+        Self { inner, rounds }
+    }
+}
+
+impl CompletionFeaturesDyn for ObservedCompleter {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let ctx = match RunContext::current().or_else(|| self.rounds.claim(&req)) {
+            Some(ctx) => ctx,
+            None => return self.inner.completion(req),
+        };
+
+        // The budgets are checked before every round, so a run stops once
+        // the rounds it already made used up the hard limits.
+        if let Some(status) = ctx.exhausted_budget() {
+            log::warn!(
+                "Run {} stopped: token budget of {} exceeded",
+                ctx.run_id(),
+                status.provider
+            );
+            return Box::pin(futures::future::ready(Err(RunError::BudgetExceeded(
+                status,
+            )
+            .into())));
+        }
+        ctx.on_request(&req);
+
+        let inner = self.inner.clone();
+        let rounds = self.rounds.clone();
+        Box::pin(async move {
+            let output = ctx
+                .clone()
+                .run(async move { inner.completion(req).await })
+                .await?;
+            ctx.on_output(&output);
+            rounds.follow(&ctx, &output);
+            Ok(output)
+        })
+    }
+}
@@ -0,0 +1,431 @@
+use anda_core::{BoxError, Json};
+use anda_db::{
+    collection::{Collection, CollectionConfig},
+    database::AndaDB,
+    error::DBError,
+    query::{Filter, Query, RangeQuery, Search},
+    schema::{AndaDBSchema, FieldEntry, FieldType, Fv, Schema, SchemaError},
+    unix_ms,
+};
+use anda_engine::memory::Conversation;
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::BTreeSet, path::Path, sync::Arc};
+
+use super::{
+    history::{message_text, open_conversations},
+    storage::drop_collection,
+};
+
+const SNIPPET_CHARS: usize = 160;
+
+/// One indexed message of a conversation.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, AndaDBSchema)]
+pub struct MessageDoc {
+    pub _id: u64,
+    pub conversation: u64,
+    pub position: u64, // index of the message in the conversation
+    pub role: String,
+    pub text: String,
+    pub timestamp: Option<u64>, // unix timestamp in milliseconds
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchHit {
+    pub conversation: u64,
+    pub position: u64,
+    pub role: String,
+    pub timestamp: Option<u64>,
+    pub snippet: String,
+    /// Matched ranges in the snippet, as `[start, end)` character offsets.
+    pub highlights: Vec<(usize, usize)>,
+}
+
+/// BM25 full-text index over the messages of all conversations. Messages
+/// are keyed by their conversation and position, so a message is indexed
+/// at most once.
+///
+/// A rebuild writes a new generation of the index to a new collection and
+/// swaps it in once it is complete, so the index stays usable meanwhile.
+pub struct ConversationIndex {
+    messages: ArcSwap<Collection>,
+}
+
+impl ConversationIndex {
+    /// The first generation of the index, rebuilds write to
+    /// `<COLLECTION>_<generation>`.
+    const COLLECTION: &'static str = "conversation_message_index";
+    /// The index of earlier versions, which could hold duplicate messages.
+    const LEGACY_COLLECTION: &'static str = "conversation_messages";
+    /// Conversation IDs start at 1, the key (0, 0) marks a finished backfill.
+    const BACKFILL_MARKER: u64 = 0;
+
+    /// Opens the latest complete generation of the index and drops the
+    /// others, which are left by an interrupted rebuild or by a crash before
+    /// the old generation was dropped.
+    pub async fn connect(db: &AndaDB, dir: &Path) -> Result<Self, BoxError> {
+        let mut generations: Vec<(u64, String)> = db
+            .metadata()
+            .collections
+            .into_iter()
+            .filter_map(|name| Self::generation(&name).map(|generation| (generation, name)))
+            .collect();
+        generations.sort();
+
+        let mut current = None;
+        for (_, name) in generations.iter().rev() {
+            let index = Self {
+                messages: ArcSwap::new(Self::open(db, name).await?),
+            };
+            if index.is_complete().await? {
+                current = Some(index);
+                break;
+            }
+        }
+        // the first generation is used until its backfill finishes
+        let index = match current {
+            Some(index) => index,
+            None => Self {
+                messages: ArcSwap::new(Self::open(db, Self::COLLECTION).await?),
+            },
+        };
+
+        let name = index.messages.load().name().to_string();
+        for (_, other) in generations {
+            if other != name {
+                drop_collection(db, dir, &other).await?;
+                log::info!("Dropped conversation index {}", other);
+            }
+        }
+        if db.metadata().collections.contains(Self::LEGACY_COLLECTION) {
+            drop_collection(db, dir, Self::LEGACY_COLLECTION).await?;
+            log::info!("Dropped the legacy conversation index");
+        }
+
+        Ok(index)
+    }
+
+    async fn open(db: &AndaDB, name: &str) -> Result<Arc<Collection>, BoxError> {
+        let schema = MessageDoc::schema()?;
+        let messages = db
+            .open_or_create_collection(
+                schema,
+                CollectionConfig {
+                    name: name.to_string(),
+                    description: "Full-text index of conversation messages".to_string(),
+                },
+                async |collection| {
+                    collection.create_btree_index_nx(&["conversation"]).await?;
+                    collection
+                        .create_btree_index_nx(&["conversation", "position"])
+                        .await?;
+                    collection.create_bm25_index_nx(&["text"]).await?;
+                    Ok::<(), DBError>(())
+                },
+            )
+            .await?;
+        Ok(messages)
+    }
+
+    /// The generation of an index collection, `None` for other collections.
+    fn generation(name: &str) -> Option<u64> {
+        match name.strip_prefix(Self::COLLECTION)? {
+            "" => Some(0),
+            suffix => suffix.strip_prefix('_')?.parse().ok(),
+        }
+    }
+
+    /// The collection of the current generation.
+    pub fn name(&self) -> String {
+        self.messages.load().name().to_string()
+    }
+
+    async fn is_complete(&self) -> Result<bool, BoxError> {
+        Ok(!self.docs_of(Self::BACKFILL_MARKER).await?.is_empty())
+    }
+
+    /// Indexes the messages of a finished conversation that are not indexed
+    /// yet. Conversations that the agent is still working on are skipped,
+    /// they are indexed when they finish.
+    pub async fn update(&self, conversation: &Json) -> Result<usize, BoxError> {
+        let id = match conversation["_id"].as_u64() {
+            Some(id) if id != Self::BACKFILL_MARKER => id,
+            _ => return Ok(0),
+        };
+        if !is_finished(conversation) {
+            return Ok(0);
+        }
+        let messages = match conversation["messages"].as_array() {
+            Some(messages) => messages,
+            None => return Ok(0),
+        };
+
+        let indexed: BTreeSet<u64> = self
+            .docs_of(id)
+            .await?
+            .into_iter()
+            .map(|doc| doc.position)
+            .collect();
+        let mut count = 0;
+        for (position, msg) in messages.iter().enumerate() {
+            let position = position as u64;
+            if indexed.contains(&position) {
+                continue;
+            }
+            let doc = MessageDoc {
+                _id: 0,
+                conversation: id,
+                position,
+                role: msg["role"].as_str().unwrap_or_default().to_string(),
+                text: message_text(msg),
+                timestamp: msg["timestamp"].as_u64(),
+            };
+            match self.messages.load().add_from(&doc).await {
+                Ok(_) => count += 1,
+                // indexed meanwhile by a concurrent update
+                Err(DBError::AlreadyExists { .. }) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if count > 0 {
+            log::info!("Indexed {} messages of conversation {}", count, id);
+        }
+        Ok(count)
+    }
+
+    /// Indexes the finished conversations of all users once, for the
+    /// conversations that finished before the index existed. An interrupted
+    /// backfill runs again on the next start.
+    pub async fn backfill(&self, db: &AndaDB) -> Result<Option<usize>, BoxError> {
+        if self.is_complete().await? {
+            return Ok(None);
+        }
+
+        let conversations = open_conversations(db).await?;
+        let mut count = 0;
+        for id in 1..=conversations.stats().max_document_id {
+            match conversations.get_as::<Conversation>(id).await {
+                Ok(conversation) => count += self.update(&json!(conversation)).await?,
+                Err(DBError::NotFound { .. }) => {}
+                Err(err) => log::warn!("Skipped conversation {} in backfill: {}", id, err),
+            }
+        }
+
+        self.messages
+            .load()
+            .add_from(&MessageDoc {
+                conversation: Self::BACKFILL_MARKER,
+                position: 0,
+                role: "$backfill".to_string(),
+                timestamp: Some(unix_ms()),
+                ..Default::default()
+            })
+            .await?;
+        log::info!("Backfilled the conversation index with {} messages", count);
+        Ok(Some(count))
+    }
+
+    pub async fn remove_conversation(&self, conversation: u64) -> Result<(), BoxError> {
+        for doc in self.docs_of(conversation).await? {
+            self.messages.load().remove(doc._id).await?;
+        }
+        Ok(())
+    }
+
+    /// Indexes the finished conversations of all users into a new
+    /// generation of the index, swaps it in and drops the current one. This
+    /// drops the messages of deleted conversations and re-packs the index
+    /// buckets. Returns the number of indexed messages.
+    pub async fn rebuild(&self, db: &AndaDB, dir: &Path) -> Result<usize, BoxError> {
+        let current = self.messages.load_full();
+        let generation = Self::generation(current.name()).unwrap_or_default() + 1;
+        let name = format!("{}_{}", Self::COLLECTION, generation);
+        // left by a failed rebuild
+        drop_collection(db, dir, &name).await?;
+
+        let next = Self {
+            messages: ArcSwap::new(Self::open(db, &name).await?),
+        };
+        let count = next.backfill(db).await?.unwrap_or_default();
+        self.messages.store(next.messages.load_full());
+        drop_collection(db, dir, current.name()).await?;
+        log::info!(
+            "Rebuilt the conversation index as {} with {} messages",
+            name,
+            count
+        );
+        Ok(count)
+    }
+
+    /// Searches the messages of all conversations, best matches first.
+    pub async fn search(&self, query: &str, limit: usize) -> Result<Vec<SearchHit>, BoxError> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let docs: Vec<MessageDoc> = self
+            .messages
+            .load()
+            .search_as(Query {
+                search: Some(Search {
+                    text: Some(query.to_string()),
+                    ..Default::default()
+                }),
+                limit: Some(limit),
+                ..Default::default()
+            })
+            .await?;
+
+        let terms: Vec<String> = query
+            .split_whitespace()
+            .map(|term| term.to_lowercase())
+            .collect();
+        Ok(docs
+            .into_iter()
+            .map(|doc| {
+                let (snippet, highlights) = snippet(&doc.text, &terms);
+                SearchHit {
+                    conversation: doc.conversation,
+                    position: doc.position,
+                    role: doc.role,
+                    timestamp: doc.timestamp,
+                    snippet,
+                    highlights,
+                }
+            })
+            .collect())
+    }
+
+    /// Loads the indexed messages of a conversation. `search_as` returns at
+    /// most 1000 documents, `query_ids` is not limited.
+    async fn docs_of(&self, conversation: u64) -> Result<Vec<MessageDoc>, BoxError> {
+        let messages = self.messages.load();
+        let ids = messages
+            .query_ids(
+                Filter::Field((
+                    "conversation".to_string(),
+                    RangeQuery::Eq(Fv::U64(conversation)),
+                )),
+                None,
+            )
+            .await?;
+        let mut docs = Vec::with_capacity(ids.len());
+        for id in ids {
+            docs.push(messages.get_as(id).await?);
+        }
+        Ok(docs)
+    }
+}
+
+/// Whether the agent is done with the conversation.
+fn is_finished(conversation: &Json) -> bool {
+    !matches!(
+        conversation["status"].as_str(),
+        Some("submitted") | Some("working")
+    )
+}
+
+/// Cuts a window of the text around the first matched term and returns it
+/// with the character ranges of all matched terms in the window.
+fn snippet(text: &str, terms: &[String]) -> (String, Vec<(usize, usize)>) {
+    let chars: Vec<char> = text.chars().collect();
+    let lower: Vec<char> = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
+
+    let mut matches: Vec<(usize, usize)> = Vec::new();
+    for term in terms {
+        let term: Vec<char> = term.chars().collect();
+        if term.is_empty() || term.len() > lower.len() {
+            continue;
+        }
+        for start in 0..=(lower.len() - term.len()) {
+            if lower[start..start + term.len()] == term[..] {
+                matches.push((start, start + term.len()));
+            }
+        }
+    }
+    matches.sort();
+
+    let first = matches.first().map(|m| m.0).unwrap_or(0);
+    let start = first.saturating_sub(SNIPPET_CHARS / 4);
+    let end = (start + SNIPPET_CHARS).min(chars.len());
+    let mut snippet: String = chars[start..end].iter().collect();
+    let mut offset = 0;
+    if start > 0 {
+        snippet.insert(0, '…');
+        offset = 1;
+    }
+    if end < chars.len() {
+        snippet.push('…');
+    }
+
+    let mut highlights: Vec<(usize, usize)> = Vec::new();
+    for (s, e) in matches {
+        if s < start || e > end {
+            continue;
+        }
+        match highlights.last_mut() {
+            Some(last) if s - start + offset <= last.1 => {
+                last.1 = last.1.max(e - start + offset);
+            }
+            _ => highlights.push((s - start + offset, e - start + offset)),
+        }
+    }
+    (snippet, highlights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(terms: &[&str]) -> Vec<String> {
+        terms.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn highlights_terms_case_insensitively() {
+        let (text, highlights) = snippet("Rust and rust, trusted", &terms(&["rust"]));
+        assert_eq!(text, "Rust and rust, trusted");
+        assert_eq!(highlights, [(0, 4), (9, 13), (16, 20)]);
+    }
+
+    #[test]
+    fn merges_overlapping_highlights() {
+        let (_, highlights) = snippet("an anda app", &terms(&["anda", "and", "da a"]));
+        assert_eq!(highlights, [(3, 9)]);
+    }
+
+    #[test]
+    fn cuts_a_window_around_the_first_match() {
+        let text = format!("{}needle{}", "a".repeat(200), "b".repeat(200));
+        let (snippet, highlights) = snippet(&text, &terms(&["needle"]));
+        let chars: Vec<char> = snippet.chars().collect();
+        assert_eq!(chars.len(), SNIPPET_CHARS + 2);
+        assert_eq!(chars[0], '…');
+        assert_eq!(chars[chars.len() - 1], '…');
+        // the match starts a quarter into the window, after the ellipsis
+        assert_eq!(highlights, [(SNIPPET_CHARS / 4 + 1, SNIPPET_CHARS / 4 + 7)]);
+        let (s, e) = highlights[0];
+        assert_eq!(chars[s..e].iter().collect::<String>(), "needle");
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        let (snippet, highlights) = snippet("记忆：Anda 的知识", &terms(&["anda", "知识"]));
+        assert_eq!(snippet, "记忆：Anda 的知识");
+        assert_eq!(highlights, [(3, 7), (9, 11)]);
+    }
+
+    #[test]
+    fn starts_at_the_beginning_without_matches() {
+        let text = "x".repeat(SNIPPET_CHARS + 10);
+        let (snippet, highlights) = snippet(&text, &terms(&["y", ""]));
+        assert_eq!(snippet.chars().count(), SNIPPET_CHARS + 1);
+        assert!(snippet.ends_with('…'));
+        assert!(highlights.is_empty());
+    }
+}
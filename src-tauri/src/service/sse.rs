@@ -0,0 +1,126 @@
+use anda_core::BoxError;
+
+/// Whether a response is a stream of server-sent events rather than one
+/// JSON document.
+pub fn is_event_stream(res: &reqwest::Response) -> bool {
+    res.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"))
+}
+
+/// Reads the server-sent events of a streamed completion response.
+pub struct EventStream {
+    res: reqwest::Response,
+    parser: EventParser,
+    done: bool,
+}
+
+impl EventStream {
+    pub fn new(res: reqwest::Response) -> Self {
+        Self {
+            res,
+            parser: EventParser::default(),
+            done: false,
+        }
+    }
+
+    /// Returns the next event, or `None` when the stream has ended.
+    pub async fn next(&mut self) -> Result<Option<Event>, BoxError> {
+        loop {
+            if let Some(event) = self.parser.next_event() {
+                return Ok(Some(event));
+            }
+            if self.done {
+                return Ok(None);
+            }
+            match self.res.chunk().await? {
+                Some(chunk) => self.parser.push(&chunk),
+                None => {
+                    // the last event may miss its blank line
+                    self.parser.push(b"\n\n");
+                    self.done = true;
+                }
+            }
+        }
+    }
+}
+
+/// One server-sent event. The event name is empty when the event has none.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    pub event: String,
+    pub data: String,
+}
+
+/// Splits the bytes of a server-sent events stream into events. The bytes
+/// may arrive in chunks of any size.
+#[derive(Default)]
+pub struct EventParser {
+    buf: Vec<u8>,
+}
+
+impl EventParser {
+    pub fn push(&mut self, chunk: &[u8]) {
+        // line breaks may be `\r\n`, the JSON data never contains a raw `\r`
+        self.buf.extend(chunk.iter().filter(|b| **b != b'\r'));
+    }
+
+    /// Returns the next complete event. Blocks with comments only are
+    /// skipped.
+    pub fn next_event(&mut self) -> Option<Event> {
+        loop {
+            let end = self.buf.windows(2).position(|w| w == b"\n\n")?;
+            let block: Vec<u8> = self.buf.drain(..end + 2).collect();
+            let block = String::from_utf8_lossy(&block[..end]);
+
+            let mut event = Event::default();
+            let mut data: Vec<&str> = Vec::new();
+            for line in block.lines() {
+                let (field, value) = line.split_once(':').unwrap_or((line, ""));
+                let value = value.strip_prefix(' ').unwrap_or(value);
+                match field {
+                    "event" => event.event = value.to_string(),
+                    "data" => data.push(value),
+                    _ => {}
+                }
+            }
+            if !data.is_empty() {
+                event.data = data.join("\n");
+                return Some(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_events_split_across_chunks() {
+        let mut parser = EventParser::default();
+        parser.push(b": keep-alive\n\nevent: message_start\r\ndata: {\"a\":");
+        assert_eq!(parser.next_event(), None);
+
+        parser.push(b"1}\r\n\r\ndata: first\ndata: second\n\ndata:[DONE]\n");
+        assert_eq!(
+            parser.next_event(),
+            Some(Event {
+                event: "message_start".to_string(),
+                data: "{\"a\":1}".to_string(),
+            })
+        );
+        assert_eq!(
+            parser.next_event(),
+            Some(Event {
+                event: String::new(),
+                data: "first\nsecond".to_string(),
+            })
+        );
+        assert_eq!(parser.next_event(), None);
+
+        parser.push(b"\n");
+        assert_eq!(parser.next_event().unwrap().data, "[DONE]");
+    }
+}
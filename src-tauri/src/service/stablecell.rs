@@ -93,6 +93,14 @@ where
         &self.path
     }
 
+    /// Replaces the value with the one stored in the file, e.g. after the
+    /// file was restored from a backup.
+    pub fn reload(&self) -> Result<()> {
+        let cell = Self::load(self.path.clone())?;
+        *self.value.write() = cell.value.into_inner();
+        Ok(())
+    }
+
     pub fn with<F, R>(&self, f: F) -> R
     where
         F: FnOnce(&T) -> R,
@@ -126,7 +134,7 @@ where
     T: Serialize + DeserializeOwned,
 {
     path: PathBuf,
-    cipher: Aes256Gcm,
+    cipher: RwLock<Aes256Gcm>,
     value: RwLock<T>,
 }
 
@@ -198,7 +206,7 @@ where
 
                 Ok(CipherCell {
                     path,
-                    cipher,
+                    cipher: RwLock::new(cipher),
                     value: RwLock::new(value),
                 })
             }
@@ -207,7 +215,7 @@ where
                 let value = T::default();
                 let cell = CipherCell {
                     path,
-                    cipher,
+                    cipher: RwLock::new(cipher),
                     value: RwLock::new(value),
                 };
 
@@ -222,6 +230,15 @@ where
         &self.path
     }
 
+    /// Replaces the value with the one stored in the file, which may be
+    /// encrypted with a new secret, e.g. after it was restored from a backup.
+    pub fn reload(&self, secret: [u8; 32]) -> Result<()> {
+        let cell = Self::load(self.path.clone(), secret)?;
+        *self.cipher.write() = cell.cipher.into_inner();
+        *self.value.write() = cell.value.into_inner();
+        Ok(())
+    }
+
     pub fn with<F, R>(&self, f: F) -> R
     where
         F: FnOnce(&T) -> R,
@@ -248,6 +265,7 @@ where
         let nonce = rand_bytes::<12>();
         let encrypted_data = self
             .cipher
+            .read()
             .encrypt(Nonce::from_slice(&nonce), data.as_ref())
             .map_err(|err| {
                 io::Error::new(
@@ -0,0 +1,213 @@
+use anda_core::BoxError;
+use anda_db::{database::AndaDB, error::DBError};
+use futures::TryStreamExt;
+use object_store::{ObjectMeta, ObjectStore, path::Path as ObjectPath};
+use serde::Serialize;
+use std::{collections::BTreeMap, fs, path::Path};
+
+use super::{
+    integrity::collection_of,
+    store::{dropped_collections, set_dropped_collections, storage_config},
+};
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StorageStats {
+    /// Files and bytes of the object store directory, as stored on disk.
+    pub disk_files: usize,
+    pub disk_bytes: u64,
+    /// Objects and bytes as listed by the object store.
+    pub objects: usize,
+    pub object_bytes: u64,
+    pub collections: Vec<CollectionStorage>,
+    /// Objects of the database itself and of removed collections.
+    pub other_objects: usize,
+    pub other_bytes: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CollectionStorage {
+    pub name: String,
+    pub documents: u64,
+    pub objects: usize,
+    pub bytes: u64,
+    pub index_objects: usize,
+    pub index_bytes: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CompactionReport {
+    pub disk_bytes_before: u64,
+    pub disk_bytes_after: u64,
+    /// Objects of dropped collections deleted from the object store.
+    pub removed_objects: Vec<String>,
+    pub removed_bytes: u64,
+    pub removed_dirs: usize,
+    /// Index buckets over the bucket overload size after the conversation
+    /// index was re-packed. The indexes of the other collections are owned
+    /// by the engine and can not be rebuilt here.
+    pub overloaded_buckets: Vec<String>,
+    /// Messages written to the rebuilt conversation index.
+    pub reindexed_messages: Option<usize>,
+}
+
+/// Collects the document counts of the collections and the objects and
+/// bytes of the object store under `dir`.
+pub async fn storage_stats(db: &AndaDB, dir: &Path) -> Result<StorageStats, BoxError> {
+    let mut stats = StorageStats::default();
+    (stats.disk_files, stats.disk_bytes) = disk_usage(dir)?;
+
+    let mut collections: BTreeMap<String, CollectionStorage> = BTreeMap::new();
+    for name in db.metadata().collections {
+        let collection = db
+            .open_collection(name.clone(), async |_| Ok::<(), DBError>(()))
+            .await?;
+        collections.insert(
+            name.clone(),
+            CollectionStorage {
+                name,
+                documents: collection.stats().num_documents,
+                ..Default::default()
+            },
+        );
+    }
+
+    for meta in list_objects(db).await? {
+        stats.objects += 1;
+        stats.object_bytes += meta.size;
+        let location = meta.location.to_string();
+        match collection_of(db.name(), &location).and_then(|name| collections.get_mut(name)) {
+            Some(collection) => {
+                collection.objects += 1;
+                collection.bytes += meta.size;
+                if is_index_object(&location) {
+                    collection.index_objects += 1;
+                    collection.index_bytes += meta.size;
+                }
+            }
+            None => {
+                stats.other_objects += 1;
+                stats.other_bytes += meta.size;
+            }
+        }
+    }
+
+    stats.collections = collections.into_values().collect();
+    Ok(stats)
+}
+
+/// Drops a collection and its objects. The collection is recorded as
+/// dropped first, so that `compact` deletes the objects that an interrupted
+/// drop leaves behind.
+pub async fn drop_collection(db: &AndaDB, dir: &Path, name: &str) -> Result<(), BoxError> {
+    let mut dropped = dropped_collections(dir)?;
+    if dropped.insert(name.to_string()) {
+        set_dropped_collections(dir, &dropped)?;
+    }
+    if db.metadata().collections.contains(name) {
+        // opened so that its data is dropped with it
+        db.open_collection(name.to_string(), async |_| Ok::<(), DBError>(()))
+            .await?;
+        db.delete_collection(name).await?;
+    }
+    Ok(())
+}
+
+/// Deletes the objects left by the collections that were dropped with
+/// `drop_collection`, removes the empty directories of the object store and
+/// reports the index buckets that outgrew the bucket overload size of the
+/// storage config. Objects of other collections missing from the database
+/// metadata are left to the repair.
+///
+/// The caller must keep the database read-only meanwhile, a put can not
+/// create its object in a directory that is being removed.
+pub async fn compact(db: &AndaDB, dir: &Path) -> Result<CompactionReport, BoxError> {
+    let config = storage_config();
+    let names = db.metadata().collections;
+    let mut report = CompactionReport {
+        disk_bytes_before: disk_usage(dir)?.1,
+        ..Default::default()
+    };
+
+    let object_store = db.object_store().clone();
+    let mut dropped = dropped_collections(dir)?;
+    for name in dropped.clone() {
+        if names.contains(&name) {
+            continue;
+        }
+        let prefix = ObjectPath::from(db.name()).child(name.as_str());
+        let objects: Vec<ObjectMeta> = object_store.list(Some(&prefix)).try_collect().await?;
+        for meta in objects {
+            object_store.delete(&meta.location).await?;
+            report.removed_bytes += meta.size;
+            report.removed_objects.push(meta.location.to_string());
+        }
+        dropped.remove(&name);
+        set_dropped_collections(dir, &dropped)?;
+    }
+
+    for meta in list_objects(db).await? {
+        let location = meta.location.to_string();
+        if is_index_object(&location) && meta.size > config.bucket_overload_size as u64 {
+            report.overloaded_buckets.push(location);
+        }
+    }
+
+    report.removed_dirs = remove_empty_dirs(dir)?;
+    report.disk_bytes_after = disk_usage(dir)?.1;
+    log::info!(
+        "Storage compacted: {} objects ({} bytes) and {} directories removed",
+        report.removed_objects.len(),
+        report.removed_bytes,
+        report.removed_dirs
+    );
+    Ok(report)
+}
+
+async fn list_objects(db: &AndaDB) -> Result<Vec<ObjectMeta>, BoxError> {
+    let objects: Vec<ObjectMeta> = db.object_store().list(None).try_collect().await?;
+    Ok(objects)
+}
+
+/// Index objects are kept under `<db>/<collection>/<kind>_indexes/`.
+fn is_index_object(location: &str) -> bool {
+    location
+        .split('/')
+        .nth(2)
+        .is_some_and(|segment| segment.ends_with("indexes"))
+}
+
+fn disk_usage(dir: &Path) -> Result<(usize, u64), BoxError> {
+    let mut files = 0;
+    let mut bytes = 0;
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files += 1;
+                bytes += meta.len();
+            }
+        }
+    }
+    Ok((files, bytes))
+}
+
+/// Removes the empty directories under `dir`, deepest first. `dir` itself is
+/// kept.
+fn remove_empty_dirs(dir: &Path) -> Result<usize, BoxError> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            removed += remove_empty_dirs(&path)?;
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
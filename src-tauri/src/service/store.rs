@@ -0,0 +1,344 @@
+use anda_core::BoxError;
+use anda_db::storage::StorageConfig;
+use anda_engine::store::LocalFileSystem;
+use anda_object_store::{EncryptedStore, EncryptedStoreBuilder, MetaStore, MetaStoreBuilder};
+use futures::TryStreamExt;
+use object_store::ObjectStore;
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const CACHE_CAPACITY: u64 = 10000;
+
+/// The storage parameters of the database, also used by the compaction.
+pub fn storage_config() -> StorageConfig {
+    StorageConfig {
+        cache_max_capacity: 10000,
+        compress_level: 3,
+        object_chunk_size: 256 * 1024,
+        bucket_overload_size: 1024 * 1024,
+        max_small_object_size: 1024 * 1024 * 10,
+    }
+}
+
+/// Opens the object store under `dir`, encrypting every object at rest with
+/// AES-256-GCM. A plaintext store left by an older version is migrated first,
+/// and an interrupted migration is resumed.
+pub async fn open_object_store(
+    dir: &Path,
+    secret: [u8; 32],
+) -> Result<Arc<dyn ObjectStore>, BoxError> {
+    let marker = encrypted_marker(dir);
+    if migration_journal(dir).exists() {
+        migrate_plaintext(dir, secret).await?;
+    } else if !marker.exists() {
+        if is_empty_dir(dir)? {
+            write_marker(&marker)?;
+        } else {
+            migrate_plaintext(dir, secret).await?;
+        }
+    }
+
+    let object_store = encrypted_store(dir, secret)?;
+    Ok(Arc::new(object_store))
+}
+
+fn encrypted_store(
+    dir: &Path,
+    secret: [u8; 32],
+) -> Result<MetaStore<EncryptedStore<LocalFileSystem>>, BoxError> {
+    let object_store = LocalFileSystem::new_with_prefix(dir)?;
+    let object_store =
+        EncryptedStoreBuilder::with_secret(object_store, CACHE_CAPACITY, secret).build();
+    Ok(MetaStoreBuilder::new(object_store, CACHE_CAPACITY).build())
+}
+
+/// Re-writes every object of a plaintext store through the encrypted store.
+///
+/// The migration is journaled so that it survives a crash at any step:
+/// 1. the journal is written,
+/// 2. the plaintext store is moved aside,
+/// 3. its objects are copied to a new encrypted store,
+/// 4. the encrypted marker is written,
+/// 5. the plaintext store and the journal are removed.
+///
+/// With the journal present, the migration starts over from the plaintext
+/// store until the marker is written, and only cleans up after that. If the
+/// copy fails, the plaintext store is put back and the journal removed.
+async fn migrate_plaintext(dir: &Path, secret: [u8; 32]) -> Result<(), BoxError> {
+    let journal = migration_journal(dir);
+    let marker = encrypted_marker(dir);
+    let plain_dir = sibling(dir, "plaintext");
+
+    if journal.exists() {
+        log::warn!("Resuming the object store migration at {:?}", dir);
+    } else {
+        if plain_dir.exists() {
+            return Err(format!(
+                "Found an unfinished object store migration at {:?}, please check it manually",
+                plain_dir
+            )
+            .into());
+        }
+        log::warn!("Migrating plaintext object store at {:?}", dir);
+        fs::write(&journal, plain_dir.to_string_lossy().as_bytes())
+            .map_err(|e| format!("Failed to write {:?}: {}", journal, e))?;
+    }
+
+    if !marker.exists() {
+        if !plain_dir.exists() {
+            fs::rename(dir, &plain_dir)
+                .map_err(|e| format!("Failed to move {:?} to {:?}: {}", dir, plain_dir, e))?;
+        }
+        // objects copied before an interruption are copied again
+        remove_path(dir)?;
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {:?}: {}", dir, e))?;
+
+        match copy_objects(&plain_dir, dir, secret).await {
+            Ok(count) => {
+                write_marker(&marker)?;
+                log::warn!("Migrated {} objects to the encrypted object store", count);
+            }
+            Err(err) => {
+                log::error!("Failed to migrate plaintext object store: {}", err);
+                remove_path(dir)?;
+                fs::rename(&plain_dir, dir)
+                    .map_err(|e| format!("Failed to restore {:?}: {}", plain_dir, e))?;
+                remove_path(&journal)?;
+                return Err(err);
+            }
+        }
+    }
+
+    remove_path(&plain_dir)?;
+    remove_path(&journal)?;
+    Ok(())
+}
+
+async fn copy_objects(from: &Path, to: &Path, secret: [u8; 32]) -> Result<usize, BoxError> {
+    let source = LocalFileSystem::new_with_prefix(from)?;
+    let source = MetaStoreBuilder::new(source, CACHE_CAPACITY).build();
+    let target = encrypted_store(to, secret)?;
+
+    let mut count = 0;
+    let mut objects = source.list(None);
+    while let Some(meta) = objects.try_next().await? {
+        let data = source.get(&meta.location).await?.bytes().await?;
+        target.put(&meta.location, data.into()).await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// The file that marks the object store under `dir` as encrypted.
+pub fn encrypted_marker(dir: &Path) -> PathBuf {
+    sibling(dir, "encrypted")
+}
+
+/// The file that records an object store migration in progress.
+pub fn migration_journal(dir: &Path) -> PathBuf {
+    sibling(dir, "migrating")
+}
+
+/// The file that lists the collections dropped from the database whose
+/// objects may still be in the object store.
+pub fn dropped_collections_file(dir: &Path) -> PathBuf {
+    sibling(dir, "dropped")
+}
+
+pub fn dropped_collections(dir: &Path) -> Result<BTreeSet<String>, BoxError> {
+    let path = dropped_collections_file(dir);
+    match fs::read(&path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(err) => Err(format!("Failed to read {:?}: {}", path, err).into()),
+    }
+}
+
+/// Writes the list through a temporary file, an empty list removes it.
+pub fn set_dropped_collections(dir: &Path, names: &BTreeSet<String>) -> Result<(), BoxError> {
+    let path = dropped_collections_file(dir);
+    if names.is_empty() {
+        return remove_path(&path);
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(names)?)
+        .map_err(|e| format!("Failed to write {:?}: {}", tmp, e))?;
+    fs::rename(&tmp, &path).map_err(|e| format!("Failed to write {:?}: {}", path, e))?;
+    Ok(())
+}
+
+/// Writes the marker through a temporary file, so that a crash never leaves
+/// a partial marker behind.
+fn write_marker(marker: &Path) -> Result<(), BoxError> {
+    let tmp = marker.with_extension("tmp");
+    fs::write(&tmp, b"A256GCM").map_err(|e| format!("Failed to write {:?}: {}", tmp, e))?;
+    fs::rename(&tmp, marker).map_err(|e| format!("Failed to write {:?}: {}", marker, e))?;
+    Ok(())
+}
+
+/// Returns `<dir>.<suffix>`, next to `dir`.
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    dir.with_file_name(name)
+}
+
+pub fn remove_path(path: &Path) -> Result<(), BoxError> {
+    let res = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match res {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(format!("Failed to remove {:?}: {}", path, err).into()),
+    }
+}
+
+fn is_empty_dir(dir: &Path) -> Result<bool, BoxError> {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(err) => Err(format!("Failed to read {:?}: {}", dir, err).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::path::Path as ObjectPath;
+
+    const SECRET: [u8; 32] = [7u8; 32];
+
+    fn store_dir(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("anda-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("object_store");
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn put_plaintext(dir: &Path, count: usize) {
+        let store = MetaStoreBuilder::new(
+            LocalFileSystem::new_with_prefix(dir).unwrap(),
+            CACHE_CAPACITY,
+        )
+        .build();
+        for i in 0..count {
+            store
+                .put(
+                    &ObjectPath::from(format!("c/{i}")),
+                    format!("object {i}").into(),
+                )
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn read_all(store: &dyn ObjectStore) -> Vec<String> {
+        let mut metas: Vec<_> = store.list(None).try_collect().await.unwrap();
+        metas.sort_by(|a, b| a.location.cmp(&b.location));
+        let mut objects = Vec::new();
+        for meta in metas {
+            let data = store
+                .get(&meta.location)
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap();
+            objects.push(String::from_utf8(data.to_vec()).unwrap());
+        }
+        objects
+    }
+
+    fn assert_no_plaintext(dir: &Path) {
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    let data = fs::read(&path).unwrap();
+                    assert!(!data.windows(6).any(|w| w == b"object"), "{:?}", path);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_plaintext_store() {
+        let dir = store_dir("migrate");
+        put_plaintext(&dir, 3).await;
+
+        let store = open_object_store(&dir, SECRET).await.unwrap();
+        assert_eq!(
+            read_all(store.as_ref()).await,
+            ["object 0", "object 1", "object 2"]
+        );
+        assert!(encrypted_marker(&dir).exists());
+        assert!(!migration_journal(&dir).exists());
+        assert!(!sibling(&dir, "plaintext").exists());
+        assert_no_plaintext(&dir);
+
+        // an encrypted store is opened as is
+        let store = open_object_store(&dir, SECRET).await.unwrap();
+        assert_eq!(read_all(store.as_ref()).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_copy() {
+        let dir = store_dir("resume");
+        put_plaintext(&dir, 2).await;
+
+        // interrupted while copying: journal written, plaintext moved aside
+        // and a partial encrypted store left behind
+        let plain_dir = sibling(&dir, "plaintext");
+        fs::write(migration_journal(&dir), b"").unwrap();
+        fs::rename(&dir, &plain_dir).unwrap();
+        fs::create_dir_all(dir.join("partial")).unwrap();
+        fs::write(dir.join("partial").join("chunk"), b"garbage").unwrap();
+
+        let store = open_object_store(&dir, SECRET).await.unwrap();
+        assert_eq!(read_all(store.as_ref()).await, ["object 0", "object 1"]);
+        assert!(!dir.join("partial").exists());
+        assert!(!plain_dir.exists());
+        assert!(!migration_journal(&dir).exists());
+    }
+
+    #[tokio::test]
+    async fn resumes_before_moving_plaintext() {
+        let dir = store_dir("journal");
+        put_plaintext(&dir, 1).await;
+        fs::write(migration_journal(&dir), b"").unwrap();
+
+        let store = open_object_store(&dir, SECRET).await.unwrap();
+        assert_eq!(read_all(store.as_ref()).await, ["object 0"]);
+        assert!(encrypted_marker(&dir).exists());
+    }
+
+    #[tokio::test]
+    async fn cleans_up_after_marker() {
+        let dir = store_dir("cleanup");
+        put_plaintext(&dir, 1).await;
+        open_object_store(&dir, SECRET).await.unwrap();
+
+        // interrupted after the marker, before the plaintext was removed
+        let plain_dir = sibling(&dir, "plaintext");
+        fs::create_dir_all(&plain_dir).unwrap();
+        fs::write(plain_dir.join("leftover"), b"object").unwrap();
+        fs::write(migration_journal(&dir), b"").unwrap();
+
+        let store = open_object_store(&dir, SECRET).await.unwrap();
+        assert_eq!(read_all(store.as_ref()).await, ["object 0"]);
+        assert!(!plain_dir.exists());
+        assert!(!migration_journal(&dir).exists());
+    }
+}
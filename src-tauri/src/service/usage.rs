@@ -0,0 +1,395 @@
+use anda_core::BoxError;
+use anda_db::{
+    collection::{Collection, CollectionConfig},
+    database::AndaDB,
+    error::DBError,
+    query::{Filter, RangeQuery},
+    schema::{AndaDBSchema, FieldEntry, FieldType, Fv, Schema, SchemaError},
+};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use crate::model::app::{BudgetPeriod, ModelPrice, TokenBudget};
+
+/// One ledger entry: the tokens consumed by a provider's model during a run.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, AndaDBSchema)]
+pub struct UsageRecord {
+    pub _id: u64,
+    pub timestamp: u64, // unix timestamp in milliseconds
+    pub day: String,    // UTC date, e.g. "2025-10-01"
+    pub provider: String,
+    pub model: String,
+    pub conversation: Option<u64>,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub requests: u64,
+}
+
+/// Time range of a usage query in unix milliseconds, `end_ms` is exclusive.
+#[derive(Clone, Debug, Deserialize)]
+pub struct UsageRange {
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageGroupBy {
+    Day,
+    Provider,
+    Model,
+    Conversation,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct UsageSummary {
+    pub key: String,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub requests: u64,
+    /// The cost computed from the price table, if any model has a price.
+    pub cost: Option<f64>,
+}
+
+/// The token usage of a provider in the current budget period.
+#[derive(Clone, Debug, Serialize)]
+pub struct BudgetStatus {
+    pub provider: String,
+    pub period: BudgetPeriod,
+    pub used_tokens: u64,
+    pub soft_limit: Option<u64>,
+    pub hard_limit: Option<u64>,
+    pub resets_at: u64, // unix timestamp in milliseconds
+}
+
+impl BudgetStatus {
+    pub fn is_exhausted(&self) -> bool {
+        self.hard_limit
+            .is_some_and(|limit| self.used_tokens >= limit)
+    }
+
+    /// Whether `tokens` more tokens cross the soft or the hard limit.
+    pub fn crosses_limit(&self, tokens: u64) -> bool {
+        let used = self.used_tokens + tokens;
+        [self.soft_limit, self.hard_limit]
+            .into_iter()
+            .flatten()
+            .any(|limit| self.used_tokens < limit && used >= limit)
+    }
+}
+
+/// Returns the UTC period that contains `now_ms`.
+pub fn period_range(period: BudgetPeriod, now_ms: u64) -> UsageRange {
+    let today = DateTime::<Utc>::from_timestamp_millis(now_ms as i64)
+        .unwrap_or_default()
+        .date_naive();
+    let (start, end) = match period {
+        BudgetPeriod::Daily => (today, today.succ_opt()),
+        BudgetPeriod::Monthly => {
+            let start = today.with_day(1).unwrap_or(today);
+            let end = if today.month() == 12 {
+                NaiveDate::from_ymd_opt(today.year() + 1, 1, 1)
+            } else {
+                NaiveDate::from_ymd_opt(today.year(), today.month() + 1, 1)
+            };
+            (start, end)
+        }
+    };
+
+    let to_ms = |date: NaiveDate| {
+        date.and_time(Default::default())
+            .and_utc()
+            .timestamp_millis() as u64
+    };
+    UsageRange {
+        start_ms: to_ms(start),
+        end_ms: end.map(to_ms).unwrap_or(u64::MAX),
+    }
+}
+
+pub struct UsageLedger {
+    records: Arc<Collection>,
+}
+
+impl UsageLedger {
+    const COLLECTION: &'static str = "usage_ledger";
+
+    pub async fn connect(db: &AndaDB) -> Result<Self, BoxError> {
+        let schema = UsageRecord::schema()?;
+        let records = db
+            .open_or_create_collection(
+                schema,
+                CollectionConfig {
+                    name: Self::COLLECTION.to_string(),
+                    description: "Token usage ledger".to_string(),
+                },
+                async |collection| {
+                    collection.create_btree_index_nx(&["timestamp"]).await?;
+                    collection.create_btree_index_nx(&["provider"]).await?;
+                    collection.create_btree_index_nx(&["conversation"]).await?;
+                    Ok::<(), DBError>(())
+                },
+            )
+            .await?;
+
+        Ok(Self { records })
+    }
+
+    pub async fn record(&self, mut record: UsageRecord) -> Result<u64, BoxError> {
+        record.day = DateTime::<Utc>::from_timestamp_millis(record.timestamp as i64)
+            .map(|t| t.format("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+        let id = self.records.add_from(&record).await?;
+        Ok(id)
+    }
+
+    pub async fn list(&self, range: &UsageRange) -> Result<Vec<UsageRecord>, BoxError> {
+        let records = self
+            .query(Filter::Field((
+                "timestamp".to_string(),
+                RangeQuery::Between(Fv::U64(range.start_ms), Fv::U64(range.end_ms)),
+            )))
+            .await?;
+
+        // `Between` is inclusive on both ends
+        Ok(records
+            .into_iter()
+            .filter(|r| r.timestamp < range.end_ms)
+            .collect())
+    }
+
+    /// Returns the models that served a conversation.
+    pub async fn models_of(&self, conversation: u64) -> Result<BTreeSet<String>, BoxError> {
+        let records = self
+            .query(Filter::Field((
+                "conversation".to_string(),
+                RangeQuery::Eq(Fv::U64(conversation)),
+            )))
+            .await?;
+        Ok(records.into_iter().map(|r| r.model).collect())
+    }
+
+    /// Loads all records that match the filter. `search_as` returns at most
+    /// 1000 documents, `query_ids` is not limited.
+    async fn query(&self, filter: Filter) -> Result<Vec<UsageRecord>, BoxError> {
+        let ids = self.records.query_ids(filter, None).await?;
+        let mut records = Vec::with_capacity(ids.len());
+        for id in ids {
+            records.push(self.records.get_as(id).await?);
+        }
+        Ok(records)
+    }
+
+    pub async fn budget_status(
+        &self,
+        provider: &str,
+        budget: &TokenBudget,
+        now_ms: u64,
+    ) -> Result<BudgetStatus, BoxError> {
+        let range = period_range(budget.period, now_ms);
+        let used_tokens = self
+            .list(&range)
+            .await?
+            .into_iter()
+            .filter(|r| r.provider == provider)
+            .map(|r| r.input_tokens + r.output_tokens)
+            .sum();
+
+        Ok(BudgetStatus {
+            provider: provider.to_string(),
+            period: budget.period,
+            used_tokens,
+            soft_limit: budget.soft_limit,
+            hard_limit: budget.hard_limit,
+            resets_at: range.end_ms,
+        })
+    }
+
+    pub async fn summarize(
+        &self,
+        range: &UsageRange,
+        group_by: UsageGroupBy,
+        prices: &BTreeMap<String, ModelPrice>,
+    ) -> Result<Vec<UsageSummary>, BoxError> {
+        let records = self.list(range).await?;
+        let mut groups: BTreeMap<String, UsageSummary> = BTreeMap::new();
+        for record in records {
+            let key = match group_by {
+                UsageGroupBy::Day => record.day.clone(),
+                UsageGroupBy::Provider => record.provider.clone(),
+                UsageGroupBy::Model => record.model.clone(),
+                UsageGroupBy::Conversation => record
+                    .conversation
+                    .map(|id| id.to_string())
+                    .unwrap_or_default(),
+            };
+
+            let summary = groups.entry(key.clone()).or_insert_with(|| UsageSummary {
+                key,
+                ..Default::default()
+            });
+            summary.input_tokens += record.input_tokens;
+            summary.output_tokens += record.output_tokens;
+            summary.requests += record.requests;
+            if let Some(price) = prices.get(&record.model) {
+                *summary.cost.get_or_insert(0.0) +=
+                    price.cost(record.input_tokens, record.output_tokens);
+            }
+        }
+
+        Ok(groups.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anda_db::{database::DBConfig, storage::StorageConfig};
+    use object_store::memory::InMemory;
+
+    fn ms(rfc3339: &str) -> u64 {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .timestamp_millis() as u64
+    }
+
+    #[test]
+    fn daily_period_is_the_utc_day() {
+        let range = period_range(BudgetPeriod::Daily, ms("2025-10-01T23:59:59.999Z"));
+        assert_eq!(range.start_ms, ms("2025-10-01T00:00:00Z"));
+        assert_eq!(range.end_ms, ms("2025-10-02T00:00:00Z"));
+
+        let range = period_range(BudgetPeriod::Daily, ms("2025-10-02T00:00:00Z"));
+        assert_eq!(range.start_ms, ms("2025-10-02T00:00:00Z"));
+    }
+
+    #[test]
+    fn monthly_period_is_the_utc_month() {
+        let range = period_range(BudgetPeriod::Monthly, ms("2024-02-29T12:00:00Z"));
+        assert_eq!(range.start_ms, ms("2024-02-01T00:00:00Z"));
+        assert_eq!(range.end_ms, ms("2024-03-01T00:00:00Z"));
+
+        // December ends with the next year
+        let range = period_range(BudgetPeriod::Monthly, ms("2025-12-31T23:00:00Z"));
+        assert_eq!(range.start_ms, ms("2025-12-01T00:00:00Z"));
+        assert_eq!(range.end_ms, ms("2026-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn crosses_limits_once() {
+        let status = BudgetStatus {
+            provider: "p".to_string(),
+            period: BudgetPeriod::Daily,
+            used_tokens: 90,
+            soft_limit: Some(100),
+            hard_limit: Some(200),
+            resets_at: 0,
+        };
+        assert!(!status.crosses_limit(5));
+        assert!(status.crosses_limit(10));
+        assert!(!status.is_exhausted());
+
+        let status = BudgetStatus {
+            used_tokens: 150,
+            ..status
+        };
+        assert!(!status.crosses_limit(10));
+        assert!(status.crosses_limit(50));
+        assert!(
+            BudgetStatus {
+                used_tokens: 200,
+                ..status
+            }
+            .is_exhausted()
+        );
+    }
+
+    async fn ledger(name: &str) -> UsageLedger {
+        let db = AndaDB::connect(
+            Arc::new(InMemory::new()),
+            DBConfig {
+                name: name.to_string(),
+                description: "Usage test".to_string(),
+                storage: StorageConfig::default(),
+                lock: None,
+            },
+        )
+        .await
+        .unwrap();
+        UsageLedger::connect(&db).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn counts_more_records_than_a_search_returns() {
+        let ledger = ledger("usage_budget").await;
+
+        let now_ms = ms("2025-10-15T12:00:00Z");
+        for i in 0..1500 {
+            ledger
+                .record(UsageRecord {
+                    timestamp: now_ms + i,
+                    provider: if i % 3 == 0 { "gemini" } else { "openai" }.to_string(),
+                    model: "m".to_string(),
+                    input_tokens: 2,
+                    output_tokens: 1,
+                    requests: 1,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+
+        let budget = TokenBudget {
+            period: BudgetPeriod::Monthly,
+            soft_limit: None,
+            hard_limit: Some(3000),
+        };
+        let status = ledger
+            .budget_status("openai", &budget, now_ms)
+            .await
+            .unwrap();
+        assert_eq!(status.used_tokens, 1000 * 3);
+        assert!(status.is_exhausted());
+
+        let summary = ledger
+            .summarize(
+                &period_range(BudgetPeriod::Daily, now_ms),
+                UsageGroupBy::Provider,
+                &BTreeMap::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            summary
+                .iter()
+                .map(|s| (s.key.as_str(), s.requests))
+                .collect::<Vec<_>>(),
+            [("gemini", 500), ("openai", 1000)]
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_the_models_of_a_conversation() {
+        let ledger = ledger("usage_models").await;
+        for (conversation, model) in [(Some(1), "a"), (Some(2), "b"), (Some(1), "c"), (None, "d")] {
+            ledger
+                .record(UsageRecord {
+                    timestamp: 1,
+                    provider: "p".to_string(),
+                    model: model.to_string(),
+                    conversation,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+
+        let models = ledger.models_of(1).await.unwrap();
+        assert_eq!(models.into_iter().collect::<Vec<_>>(), ["a", "c"]);
+        assert!(ledger.models_of(3).await.unwrap().is_empty());
+    }
+}
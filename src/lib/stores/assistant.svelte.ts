@@ -44,6 +44,10 @@ class AssistantStore {
       assistantStore._isReady = event.payload
     })
 
+    window.addEventListener('online', () => {
+      invoke('network_restored')
+    })
+
     const checkReady = async () => {
       const info = await assistant_info()
       assistantStore._isReady = info.agents.length > 0